@@ -2,70 +2,207 @@ use crate::errors::*;
 use crate::expressions::Evaluation;
 use crate::functions::{Function, FunctionStage, RunType};
 use crate::globalstate::GlobalState;
-use crate::parser::{Symbol, Token};
-use crate::scanner::{Delimeter, Keyword, PreToken, Type};
-use std::error::Error;
+use crate::parser::{Symbol, Token, TokenKind};
+use crate::scanner::{
+    DataType, Delimeter, Field, Keyword, PreToken, RecordType, Type, TypeTable, Variant,
+};
+use crate::span::Span;
 
+fn kind(token: &Option<Token>) -> Option<&TokenKind> {
+    token.as_ref().map(|t| &t.kind)
+}
+
+pub fn unexpected(token: Option<Token>, expected: &str) -> CompileError {
+    match token {
+        Some(Token {
+            kind: TokenKind::Lang(PreToken::EOL),
+            span,
+        }) => SyntaxError::UnexpectedEol {
+            expected: expected.to_string(),
+            span,
+        }
+        .into(),
+        Some(token) => SyntaxError::UnexpectedToken {
+            expected: expected.to_string(),
+            found: token.kind.to_string(),
+            span: token.span,
+        }
+        .into(),
+        None => SyntaxError::UnexpectedEof {
+            expected: expected.to_string(),
+        }
+        .into(),
+    }
+}
+
+pub fn expect_token(tokens: &mut Vec<Token>, wanted: PreToken) -> Result<Token, CompileError> {
+    let token = tokens.pop();
+    if kind(&token) == Some(&TokenKind::Lang(wanted)) {
+        Ok(token.unwrap())
+    } else {
+        Err(unexpected(token, &wanted.to_string()))
+    }
+}
+
+fn expect_symbol(tokens: &mut Vec<Token>, expected: &str) -> Result<(Symbol, Span), CompileError> {
+    match tokens.pop() {
+        Some(Token {
+            kind: TokenKind::Symb(symbol),
+            span,
+        }) => Ok((symbol, span)),
+        token => Err(unexpected(token, expected)),
+    }
+}
+
+/// Reads a type: a type keyword, a function type like `int str -> bool`, or either of them in
+/// parentheses. Arrows group to the right, so `int -> int -> int` returns a function.
+pub fn consume_type(
+    tokens: &mut Vec<Token>,
+    types: &mut TypeTable,
+) -> Result<(Type, Span), CompileError> {
+    let (first, start) = consume_type_atom(tokens, types)?;
+    let mut args = vec![first];
+    while matches!(
+        tokens.last().map(|t| &t.kind),
+        Some(TokenKind::Lang(
+            PreToken::TYPE(_) | PreToken::DEL(Delimeter::LPar)
+        ))
+    ) {
+        args.push(consume_type_atom(tokens, types)?.0);
+    }
+    match tokens.last() {
+        Some(token) if token.kind == TokenKind::Lang(PreToken::KW(Keyword::Arrow)) => {
+            tokens.pop();
+            let (ret, end) = consume_type(tokens, types)?;
+            Ok((types.function(args, ret), start.to(end)))
+        }
+        _ if args.len() == 1 => Ok((first, start)),
+        _ => Err(unexpected(tokens.pop(), "`->`")),
+    }
+}
+
+fn consume_type_atom(
+    tokens: &mut Vec<Token>,
+    types: &mut TypeTable,
+) -> Result<(Type, Span), CompileError> {
+    match tokens.pop() {
+        Some(Token {
+            kind: TokenKind::Lang(PreToken::TYPE(t)),
+            span,
+        }) => Ok((t, span)),
+        Some(Token {
+            kind: TokenKind::Lang(PreToken::DEL(Delimeter::LPar)),
+            span,
+        }) => {
+            let (t, _) = consume_type(tokens, types)?;
+            let close = expect_token(tokens, PreToken::DEL(Delimeter::RPar))?;
+            Ok((t, span.to(close.span)))
+        }
+        token => Err(unexpected(token, "a type")),
+    }
+}
+
+/// Reads an expression that must have `desired_type`, as promised by the declaration at `declared`.
 fn consume_evaluation(
     tokens: &mut Vec<Token>,
     global_state: &mut GlobalState,
     desired_type: Type,
-) -> Result<Evaluation, Box<dyn Error>> {
-    let eval = Evaluation::from_tokens(tokens, global_state);
+    declared: (Span, String),
+) -> Result<Evaluation, CompileError> {
+    let eval = Evaluation::from_tokens_with_where(tokens, global_state)?;
     if eval.get_type() != desired_type {
-        return Err(Box::new(TypeError {
-            message: "Type mismatch".to_string(),
-            expected: desired_type,
-            found: eval.get_type(),
-        }));
+        return Err(TypeError {
+            code: ErrorCode::TypeMismatch,
+            message: "type mismatch".to_string(),
+            expected: desired_type.display(global_state.types()).to_string(),
+            found: eval.get_type().display(global_state.types()).to_string(),
+            span: eval.span(),
+            related: vec![declared],
+        }
+        .into());
     }
     Ok(eval)
 }
 
+fn consume_run_type(tokens: &mut Vec<Token>) -> Result<RunType, CompileError> {
+    match tokens.last().map(|t| &t.kind) {
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Bar))) => {
+            tokens.pop();
+            expect_token(tokens, PreToken::KW(Keyword::Kick))?;
+            expect_token(tokens, PreToken::EOL)?;
+            Ok(RunType::Thread)
+        }
+        _ => Ok(RunType::Regular),
+    }
+}
+
+/// Kicked assignments run before the regular ones of their stage and regular ones run in
+/// order, so a kicked assignment cannot use anything from its own stage, and a regular one
+/// cannot use what its stage kicks.
+fn check_stage_order(
+    body: &Evaluation,
+    run_type: &RunType,
+    earlier: &[(Symbol, Box<Evaluation>, RunType, Span)],
+) -> Result<(), CompileError> {
+    let mut used = Vec::new();
+    body.variables_used(&mut used);
+    for (symbol, span) in used {
+        let Some((_, _, other, defined)) = earlier.iter().rev().find(|a| a.0 == symbol) else {
+            continue;
+        };
+        let message = match (run_type, other) {
+            (RunType::Thread, _) => format!(
+                "`{}` is assigned in the same stage, so it is not ready when this kicked assignment starts",
+                symbol
+            ),
+            (RunType::Regular, RunType::Thread) => format!(
+                "`{}` is kicked in the same stage, so it is only ready in the stages after it",
+                symbol
+            ),
+            (RunType::Regular, RunType::Regular) => continue,
+        };
+        return Err(ProgramError {
+            code: ErrorCode::StageOrder,
+            message,
+            span: Some(span),
+            related: vec![(*defined, format!("`{}` is assigned here", symbol))],
+        }
+        .into());
+    }
+    Ok(())
+}
+
 fn consume_function_stage(
     tokens: &mut Vec<Token>,
     global_state: &mut GlobalState,
-) -> Result<FunctionStage, Box<dyn Error>> {
+) -> Result<FunctionStage, CompileError> {
     let mut curr_token = tokens.pop();
-    let mut assignments: Vec<(Symbol, Box<Evaluation>, RunType)> = Vec::new();
-    while curr_token != Some(Token::Lang(PreToken::KW(Keyword::Kerchow))) {
-        match curr_token {
-            Some(Token::Lang(PreToken::TYPE(t))) => {
-                let name = match tokens.pop() {
-                    Some(Token::Symb(name)) => name,
-                    x => panic!("{:?} is not a valid token, {:#?}", x, tokens),
-                };
-                let def_symbol = tokens.pop(); // def symbol
-                if def_symbol != Some(Token::Lang(PreToken::KW(Keyword::Define))) {
-                    return Err("Invalid token, wanted :=".into());
-                }
-                let body = consume_evaluation(tokens, global_state, t)?;
-                if body.get_type() != t {
-                    return Err(Box::new(TypeError {
-                        message: "Type mismatch".to_string(),
-                        expected: t,
-                        found: body.get_type(),
-                    }));
-                }
+    let mut assignments: Vec<(Symbol, Box<Evaluation>, RunType, Span)> = Vec::new();
+    while kind(&curr_token) != Some(&TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+        match kind(&curr_token) {
+            Some(TokenKind::Lang(PreToken::TYPE(_) | PreToken::DEL(Delimeter::LPar))) => {
+                tokens.push(curr_token.unwrap());
+                let (t, start) = consume_type(tokens, global_state.types_mut())?;
+                let (name, name_span) = expect_symbol(tokens, "a variable name")?;
+                expect_token(tokens, PreToken::KW(Keyword::Define))?;
+                let declared = (
+                    start.to(name_span),
+                    format!(
+                        "`{}` was declared as {} here",
+                        name,
+                        t.display(global_state.types())
+                    ),
+                );
+                let body = consume_evaluation(tokens, global_state, t, declared)?;
                 global_state.override_variables(name.clone(), t);
-                let run_type = match tokens.last().unwrap() {
-                    Token::Lang(PreToken::KW(Keyword::Bar)) => {
-                        tokens.pop();
-                        match tokens.pop() {
-                            Some(Token::Lang(PreToken::KW(Keyword::Kick))) => {
-                                tokens.pop();
-                                RunType::Thread
-                            }
-                            _ => panic!(),
-                        }
-                    }
-                    _ => RunType::Regular,
-                };
-                assignments.push((name, Box::new(body), run_type));
+                let span = start.to(body.span());
+                let run_type = consume_run_type(tokens)?;
+                check_stage_order(&body, &run_type, &assignments)?;
+                assignments.push((name, Box::new(body), run_type, span));
             }
-            Some(Token::Lang(PreToken::EOL)) => {}
-            Some(Token::Lang(PreToken::KW(Keyword::Bar))) => {}
-            _ => panic!("{:?} is not a valid token, {:#?}", curr_token, tokens),
+            Some(TokenKind::Lang(PreToken::EOL)) => {}
+            Some(TokenKind::Lang(PreToken::KW(Keyword::Bar))) => {}
+            _ => return Err(unexpected(curr_token, "a stage assignment or `=>`")),
         }
         curr_token = tokens.pop();
     }
@@ -75,129 +212,656 @@ fn consume_function_stage(
 
 fn consume_function(
     name: Symbol,
+    span: Span,
     tokens: &mut Vec<Token>,
     global_state: &mut GlobalState,
     desired_type: Type,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), CompileError> {
     let mut args: Vec<(Symbol, Type)> = Vec::new();
     let mut next_token = tokens.pop();
-    while next_token != Some(Token::Lang(PreToken::KW(Keyword::Kerchow)))
-        && next_token != Some(Token::Lang(PreToken::KW(Keyword::Bar)))
+    while kind(&next_token) != Some(&TokenKind::Lang(PreToken::KW(Keyword::Kerchow)))
+        && kind(&next_token) != Some(&TokenKind::Lang(PreToken::KW(Keyword::Bar)))
     {
-        if let Some(Token::Symb(var_name)) = next_token {
-            let colon_symbol = tokens.pop(); // def symbol
-            if colon_symbol != Some(Token::Lang(PreToken::DEL(Delimeter::Colon))) {
-                return Err("Invalid token, wanted :".into());
-            }
-            if let Some(Token::Lang(PreToken::TYPE(t))) = tokens.pop() {
+        match next_token {
+            Some(Token {
+                kind: TokenKind::Symb(var_name),
+                ..
+            }) => {
+                expect_token(tokens, PreToken::DEL(Delimeter::Colon))?;
+                let (t, _) = consume_type(tokens, global_state.types_mut())?;
                 args.push((var_name.clone(), t));
                 global_state.override_variables(var_name, t);
-            } else {
-                return Err("Invalid token, wanted a type".into());
             }
+            Some(Token {
+                kind: TokenKind::Lang(PreToken::EOL),
+                ..
+            }) => {}
+            token => return Err(unexpected(token, "an argument, `=>` or `|`")),
         }
         next_token = tokens.pop();
     }
-    let func = Function::Simple {
-        name: name.clone(),
-        args: args.clone(),
-        body: None,
-        return_type: desired_type,
-    };
-    global_state.add_function(name.clone(), func)?;
-    match next_token {
-        Some(Token::Lang(PreToken::KW(Keyword::Kerchow))) => {
-            let body = consume_evaluation(tokens, global_state, desired_type)?;
-            if body.get_type() != desired_type {
-                return Err(Box::new(TypeError {
-                    message: "Type mismatch".to_string(),
-                    expected: desired_type,
-                    found: body.get_type(),
-                }));
-            }
-            global_state.set_function_body(name, body);
-            global_state.clear_overrides();
-        }
-        Some(Token::Lang(PreToken::KW(Keyword::Bar))) => {
-            let mut stages: Vec<FunctionStage> = Vec::new();
-            loop {
-                let func_stage = consume_function_stage(tokens, global_state)?;
-                stages.push(func_stage);
-                let _kerchow = tokens.pop();
-                if tokens.last().unwrap() != &Token::Lang(PreToken::EOL) {
+    let declared = (
+        span,
+        format!(
+            "the function was declared to return {} here",
+            desired_type.display(global_state.types())
+        ),
+    );
+    if kind(&next_token) == Some(&TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+        let body = consume_evaluation(tokens, global_state, desired_type, declared)?;
+        let func = Function::Simple {
+            name: name.clone(),
+            args,
+            body: Some(Box::new(body)),
+            return_type: desired_type,
+            span,
+        };
+        global_state.add_function(name, span, func);
+    } else {
+        let mut stages: Vec<FunctionStage> = Vec::new();
+        loop {
+            let func_stage = consume_function_stage(tokens, global_state)?;
+            stages.push(func_stage);
+            let _kerchow = tokens.pop();
+            match tokens.last() {
+                Some(token) if token.kind == TokenKind::Lang(PreToken::EOL) => {}
+                _ => {
                     // we are terminal
-                    let last_eval = consume_evaluation(tokens, global_state, desired_type)?;
-                    let t = last_eval.get_type();
-                    if t != desired_type {
-                        return Err(Box::new(TypeError {
-                            message: "Type mismatch".to_string(),
-                            expected: desired_type,
-                            found: last_eval.get_type(),
-                        }));
-                    };
+                    let last_eval =
+                        consume_evaluation(tokens, global_state, desired_type, declared.clone())?;
                     let func = Function::Breakout {
                         name: name.clone(),
                         args: args.clone(),
                         stages: stages.to_vec(),
                         final_eval: Box::new(last_eval),
-                        return_type: t,
+                        return_type: desired_type,
+                        span,
                     };
-                    global_state.set_function_body_breakout(name.clone(), func);
+                    global_state.add_function(name.clone(), span, func);
                     break;
                 }
             }
         }
-        _ => todo!(),
-    };
+    }
     global_state.clear_overrides();
 
     Ok(())
 }
 
+/// A top-level definition always starts in the first column, with `pub`, `type` or a type.
+pub fn starts_definition(token: Option<&Token>) -> bool {
+    matches!(
+        token,
+        Some(Token {
+            kind: TokenKind::Lang(
+                PreToken::TYPE(_) | PreToken::KW(Keyword::Pub | Keyword::Type)
+            ),
+            span,
+        }) if span.col == 1
+    )
+}
+
+fn at_definition_start(tokens: &[Token]) -> bool {
+    starts_definition(tokens.last())
+}
+
+/// Skips the rest of a broken definition so parsing can resume at the next one.
+fn synchronize(tokens: &mut Vec<Token>) {
+    while !tokens.is_empty() && !at_definition_start(tokens) {
+        tokens.pop();
+    }
+}
+
+/// A `type` declaration runs up to the next definition or the doc comment of one.
+fn ends_type(token: &Token) -> bool {
+    starts_definition(Some(token)) || matches!(token.kind, TokenKind::Doc(_))
+}
+
+/// The signature of a top-level definition.
+enum Header {
+    Variable(Type),
+    Function(Vec<(Symbol, Type)>, Type),
+}
+
+/// Reads the name and signature of the definition at the start of `tokens`, given in source
+/// order, if it is well-formed.
+fn read_header(tokens: &[Token], types: &mut TypeTable) -> Option<(Symbol, Span, Header)> {
+    let mut header: Vec<Token> = Vec::new();
+    let mut is_function = false;
+    let mut has_body = false;
+    for token in tokens {
+        match &token.kind {
+            TokenKind::Lang(PreToken::KW(Keyword::Kerchow | Keyword::Bar)) => {
+                has_body = true;
+                break;
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Func)) => is_function = true,
+            _ if !header.is_empty() && starts_definition(Some(token)) => break,
+            _ => {}
+        }
+        header.push(token.clone());
+        // the header of a variable ends at its `:=`
+        if !is_function && token.kind == TokenKind::Lang(PreToken::KW(Keyword::Define)) {
+            break;
+        }
+    }
+    header.reverse();
+    let (t, start) = consume_type(&mut header, types).ok()?;
+    let next = header.pop()?;
+    match next.kind {
+        TokenKind::Symb(name) => {
+            expect_token(&mut header, PreToken::KW(Keyword::Define)).ok()?;
+            Some((name, start.to(next.span), Header::Variable(t)))
+        }
+        TokenKind::Lang(PreToken::KW(Keyword::Func)) if has_body => {
+            let (name, name_span) = expect_symbol(&mut header, "a function name").ok()?;
+            expect_token(&mut header, PreToken::KW(Keyword::Define)).ok()?;
+            let mut args: Vec<(Symbol, Type)> = Vec::new();
+            while let Some(token) = header.pop() {
+                match token.kind {
+                    TokenKind::Symb(arg) => {
+                        expect_token(&mut header, PreToken::DEL(Delimeter::Colon)).ok()?;
+                        args.push((arg, consume_type(&mut header, types).ok()?.0));
+                    }
+                    TokenKind::Lang(PreToken::EOL) => {}
+                    _ => return None,
+                }
+            }
+            Some((name, start.to(name_span), Header::Function(args, t)))
+        }
+        _ => None,
+    }
+}
+
+/// Declares the definition at the start of `tokens`, given in source order. Headers too
+/// malformed to read are left for `consume_definition` to report.
+fn declare_definition(
+    tokens: &[Token],
+    global_state: &mut GlobalState,
+) -> Result<(), CompileError> {
+    let (exported, tokens) = match tokens.first().map(|t| &t.kind) {
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Pub))) => (true, &tokens[1..]),
+        _ => (false, tokens),
+    };
+    let (name, span, header) = match read_header(tokens, global_state.types_mut()) {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+    let name = global_state.qualify(&name, span);
+    match header {
+        Header::Variable(t) => global_state.declare_variable(name.clone(), span, t)?,
+        Header::Function(args, t) => {
+            let func = Function::Simple {
+                name: name.clone(),
+                args,
+                body: None,
+                return_type: t,
+                span,
+            };
+            global_state.declare_function(name.clone(), span, func)?;
+        }
+    }
+    if exported {
+        global_state.export(name);
+    }
+    Ok(())
+}
+
+/// Defines the type declared at the start of `tokens`, given in source order. `defined` holds
+/// the names of the types defined so far, with where they were.
+fn declare_type(
+    tokens: &[Token],
+    global_state: &mut GlobalState,
+    defined: &mut Vec<(String, Span)>,
+    doc: Option<String>,
+) -> Result<(), CompileError> {
+    let end = tokens[1..]
+        .iter()
+        .position(ends_type)
+        .map_or(tokens.len(), |p| p + 1);
+    let mut tokens: Vec<Token> = tokens[..end].iter().rev().cloned().collect();
+    expect_token(&mut tokens, PreToken::KW(Keyword::Type))?;
+    let (t, span) = match tokens.pop() {
+        Some(Token {
+            kind: TokenKind::Lang(PreToken::TYPE(t @ (Type::Record(_) | Type::Data(_)))),
+            span,
+        }) => (t, span),
+        token => return Err(unexpected(token, "a type name")),
+    };
+    let name = t.display(global_state.types()).to_string();
+    if let Some((_, first)) = defined.iter().find(|(defined, _)| *defined == name) {
+        return Err(ProgramError {
+            code: ErrorCode::DuplicateType,
+            message: format!("type `{}` is already defined", name),
+            span: Some(span),
+            related: vec![(*first, format!("`{}` is first defined here", name))],
+        }
+        .into());
+    }
+    defined.push((name, span));
+    global_state.add_type(t, span, doc);
+    expect_token(&mut tokens, PreToken::KW(Keyword::Define))?;
+    match t {
+        Type::Record(record) => {
+            let fields = consume_fields(&mut tokens, global_state.types_mut(), record)?;
+            record.define(global_state.types_mut(), fields);
+        }
+        Type::Data(data) => {
+            let variants = consume_variants(&mut tokens, global_state.types_mut(), data)?;
+            data.define(global_state.types_mut(), variants.clone());
+            for (index, variant) in variants.into_iter().enumerate() {
+                declare_constructor(global_state, data, index, variant)?;
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Reads the `name : type` fields of `record`.
+fn consume_fields(
+    tokens: &mut Vec<Token>,
+    types: &mut TypeTable,
+    record: RecordType,
+) -> Result<Vec<Field>, CompileError> {
+    let mut fields: Vec<Field> = Vec::new();
+    while let Some(token) = tokens.pop() {
+        match token.kind {
+            TokenKind::Symb(name) => {
+                expect_token(tokens, PreToken::DEL(Delimeter::Colon))?;
+                let (t, type_span) = consume_type(tokens, types)?;
+                if let Some(first) = fields.iter().find(|f| f.name == name.name()) {
+                    return Err(ProgramError {
+                        code: ErrorCode::DuplicateDefinition,
+                        message: format!(
+                            "{} already has a field called {}",
+                            record.name(types),
+                            name
+                        ),
+                        span: Some(token.span),
+                        related: vec![(first.span, "first defined here".to_string())],
+                    }
+                    .into());
+                }
+                fields.push(Field {
+                    name: name.name(),
+                    t,
+                    span: token.span.to(type_span),
+                });
+            }
+            TokenKind::Lang(PreToken::EOL) => {}
+            _ => return Err(unexpected(Some(token), "a field `name : type`")),
+        }
+    }
+    Ok(fields)
+}
+
+/// Reads the variants of `data`, `name type ...`, separated by `|`.
+fn consume_variants(
+    tokens: &mut Vec<Token>,
+    types: &mut TypeTable,
+    data: DataType,
+) -> Result<Vec<Variant>, CompileError> {
+    let mut variants: Vec<Variant> = Vec::new();
+    let mut separated = true;
+    let mut last_eol = None;
+    while let Some(token) = tokens.pop() {
+        match token.kind {
+            TokenKind::Symb(name) if separated => {
+                let mut fields = Vec::new();
+                let mut span = token.span;
+                while matches!(
+                    tokens.last().map(|t| &t.kind),
+                    Some(TokenKind::Lang(
+                        PreToken::TYPE(_) | PreToken::DEL(Delimeter::LPar)
+                    ))
+                ) {
+                    let (t, type_span) = consume_type_atom(tokens, types)?;
+                    fields.push(t);
+                    span = span.to(type_span);
+                }
+                if let Some(first) = variants.iter().find(|v| v.name == name.name()) {
+                    return Err(ProgramError {
+                        code: ErrorCode::DuplicateDefinition,
+                        message: format!(
+                            "{} already has a variant called {}",
+                            data.name(types),
+                            name
+                        ),
+                        span: Some(token.span),
+                        related: vec![(first.span, "first defined here".to_string())],
+                    }
+                    .into());
+                }
+                variants.push(Variant {
+                    name: name.name(),
+                    fields,
+                    span,
+                });
+                separated = false;
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Bar)) => separated = true,
+            TokenKind::Lang(PreToken::EOL) => last_eol = Some(token),
+            _ if separated => return Err(unexpected(Some(token), "a variant `name type ...`")),
+            _ => return Err(unexpected(Some(token), "`|`")),
+        }
+    }
+    if separated {
+        return Err(unexpected(last_eol, "a variant `name type ...`"));
+    }
+    Ok(variants)
+}
+
+/// Declares the constructor of the variant of `data` at `index`, a function taking its fields.
+fn declare_constructor(
+    global_state: &mut GlobalState,
+    data: DataType,
+    index: usize,
+    variant: Variant,
+) -> Result<(), CompileError> {
+    let name = global_state.qualify(&Symbol::new(variant.name.clone()), variant.span);
+    let args: Vec<(Symbol, Type)> = variant
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (Symbol::new(format!("_{}", i + 1)), *t))
+        .collect();
+    let body = Evaluation::Variant {
+        data,
+        index,
+        args: args
+            .iter()
+            .map(|(arg, t)| Evaluation::Variable(arg.clone(), *t, variant.span))
+            .collect(),
+        span: variant.span,
+    };
+    let func = Function::Simple {
+        name: name.clone(),
+        args,
+        body: Some(Box::new(body)),
+        return_type: Type::Data(data),
+        span: variant.span,
+    };
+    global_state.declare_function(name.clone(), variant.span, func)?;
+    global_state.export(name);
+    Ok(())
+}
+
+/// Declares every top-level variable and function before any body is parsed, so definitions
+/// can refer to each other regardless of the order they appear in. Types are defined first, as
+/// declaring the rest needs nothing but their names.
+fn collect_declarations(tokens: &[Token], global_state: &mut GlobalState) -> Vec<CompileError> {
+    let mut errors = Vec::new();
+    let mut types = Vec::new();
+    for i in 0..tokens.len() {
+        let rest = &tokens[i..];
+        if starts_definition(rest.first()) && kind_is(rest.first(), Keyword::Type) {
+            let doc = doc_above(&tokens[..i]);
+            if let Err(e) = declare_type(rest, global_state, &mut types, doc) {
+                errors.push(e);
+            }
+        }
+    }
+    for i in 0..tokens.len() {
+        let rest = &tokens[i..];
+        if starts_definition(rest.first()) && !kind_is(rest.first(), Keyword::Type) {
+            if let Err(e) = declare_definition(rest, global_state) {
+                errors.push(e);
+            }
+        }
+    }
+    errors
+}
+
+/// The doc comment on the lines just before the end of `tokens`.
+fn doc_above(tokens: &[Token]) -> Option<String> {
+    let mut doc = Vec::new();
+    for token in tokens.iter().rev() {
+        match &token.kind {
+            TokenKind::Lang(PreToken::EOL) => {}
+            TokenKind::Doc(text) => doc.push(text.clone()),
+            _ => break,
+        }
+    }
+    doc.reverse();
+    (!doc.is_empty()).then(|| doc.join("\n"))
+}
+
+fn kind_is(token: Option<&Token>, keyword: Keyword) -> bool {
+    token.is_some_and(|t| t.kind == TokenKind::Lang(PreToken::KW(keyword)))
+}
+
+/// Builds the program into `global_state`, collecting at most `max_errors` errors before giving up
+/// (0 means no limit). The errors come with whether any more were left out.
 pub fn generate_ast(
     tokens: &mut Vec<Token>,
     global_state: &mut GlobalState,
-) -> Result<(), Box<dyn Error>> {
+    max_errors: usize,
+) -> Result<(), (Vec<CompileError>, bool)> {
+    let mut errors = collect_declarations(tokens, global_state);
+    errors.extend(global_state.check_imports());
     tokens.reverse();
-    while !tokens.is_empty() {
-        let mut line_start_token = tokens.pop();
-        if line_start_token == Some(Token::Lang(PreToken::COMMENT)) {
-            while line_start_token != Some(Token::Lang(PreToken::EOL)) {
-                line_start_token = tokens.pop();
+    // one error past the limit shows that some were left out
+    while !tokens.is_empty() && (max_errors == 0 || errors.len() <= max_errors) {
+        if let Err(e) = consume_definition(tokens, global_state) {
+            errors.push(e);
+            global_state.clear_overrides();
+            synchronize(tokens);
+        }
+    }
+    if errors.is_empty() {
+        if let Some(e) = global_state.find_variable_cycle() {
+            errors.push(e.into());
+        }
+    }
+    let left_out = max_errors != 0 && errors.len() > max_errors;
+    if left_out {
+        errors.truncate(max_errors);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err((errors, left_out))
+    }
+}
+
+fn consume_definition(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+) -> Result<(), CompileError> {
+    let mut line_start_token = tokens.pop();
+    let mut doc: Vec<String> = Vec::new();
+    loop {
+        match kind(&line_start_token) {
+            Some(TokenKind::Lang(PreToken::EOL)) => {}
+            Some(TokenKind::Doc(text)) => doc.push(text.clone()),
+            _ => break,
+        }
+        line_start_token = tokens.pop();
+    }
+    if let Some(token) = &line_start_token {
+        let definition = matches!(
+            token.kind,
+            TokenKind::Lang(PreToken::TYPE(_) | PreToken::KW(Keyword::Pub | Keyword::Type))
+        );
+        // an indented definition was joined onto the line above it
+        if definition && !starts_definition(Some(token)) {
+            return Err(ProgramError {
+                code: ErrorCode::UnexpectedToken,
+                message: "definitions must start in column 1".to_string(),
+                span: Some(token.span),
+                related: Vec::new(),
             }
+            .into());
         }
-        while line_start_token == Some(Token::Lang(PreToken::EOL)) {
-            line_start_token = tokens.pop();
+    }
+    if kind(&line_start_token) == Some(&TokenKind::Lang(PreToken::KW(Keyword::Type))) {
+        // types are defined by `collect_declarations`
+        while tokens.last().is_some_and(|t| !ends_type(t)) {
+            tokens.pop();
         }
-        match line_start_token {
-            Some(Token::Lang(PreToken::TYPE(t))) => {
-                let next_token = tokens.pop();
-                if let Some(Token::Symb(func_name)) = next_token {
-                    let def_symbol = tokens.pop(); // def symbol
-                    if def_symbol != Some(Token::Lang(PreToken::KW(Keyword::Define))) {
-                        return Err("Invalid token, wanted :=".into());
-                    }
-                    let expression = consume_evaluation(tokens, global_state, t)?;
-                    global_state.add_variable(func_name, expression)?;
-                } else if let Some(Token::Lang(PreToken::KW(Keyword::Func))) = next_token {
-                    let func_name = tokens.pop();
-                    if let Some(Token::Symb(func_name)) = func_name {
-                        let def_symbol = tokens.pop(); // def symbol
-                        if def_symbol != Some(Token::Lang(PreToken::KW(Keyword::Define))) {
-                            return Err("Invalid token, wanted :=".into());
-                        }
-                        consume_function(func_name.clone(), tokens, global_state, t)?;
-                        // function adds to global state
-                    }
-                } else {
-                    return Err("Invalid token, wanted a function name".into());
-                }
+        return Ok(());
+    }
+    if kind(&line_start_token) == Some(&TokenKind::Lang(PreToken::KW(Keyword::Pub))) {
+        line_start_token = tokens.pop();
+    }
+    let (t, start) = match line_start_token {
+        Some(
+            token @ Token {
+                kind: TokenKind::Lang(PreToken::TYPE(_)),
+                ..
+            },
+        ) => {
+            tokens.push(token);
+            consume_type(tokens, global_state.types_mut())?
+        }
+        None => return Ok(()),
+        token => return Err(unexpected(token, "a definition")),
+    };
+    let next_token = tokens.pop();
+    match kind(&next_token) {
+        Some(TokenKind::Symb(var_name)) => {
+            let span = start.to(next_token.as_ref().unwrap().span);
+            let var_name = global_state.qualify(var_name, span);
+            if !doc.is_empty() {
+                global_state.set_doc(var_name.clone(), span, doc.join("\n"));
             }
-            None => break,
-            _ => {
-                return Err(format!("Invalid token, got {:?}", line_start_token).into());
+            expect_token(tokens, PreToken::KW(Keyword::Define))?;
+            let declared = (
+                span,
+                format!(
+                    "`{}` was declared as {} here",
+                    var_name,
+                    t.display(global_state.types())
+                ),
+            );
+            let expression = consume_evaluation(tokens, global_state, t, declared)?;
+            global_state.add_variable(var_name, span, expression);
+        }
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Func))) => {
+            let (func_name, name_span) = expect_symbol(tokens, "a function name")?;
+            expect_token(tokens, PreToken::KW(Keyword::Define))?;
+            let span = start.to(name_span);
+            let func_name = global_state.qualify(&func_name, span);
+            if !doc.is_empty() {
+                global_state.set_doc(func_name.clone(), span, doc.join("\n"));
             }
+            // function adds to global state
+            consume_function(func_name, span, tokens, global_state, t)?;
         }
+        _ => return Err(unexpected(next_token, "a variable name or `func`")),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Literal;
+    use crate::primitives::{Bool, Int};
+    use crate::testing::{compile, errors, run};
+
+    #[test]
+    fn doc_comments_after_a_record_type_belong_to_the_next_definition() {
+        let program = "type point := x : int y : int\n## The answer.\nint main := 42\n";
+        let global_state = compile(program).unwrap();
+        assert_eq!(
+            global_state.get_doc(&Symbol::new("main".to_string())),
+            Some("The answer.")
+        );
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(42)));
+    }
+
+    #[test]
+    fn doc_comments_after_a_sum_type_belong_to_the_next_definition() {
+        let program =
+            "type shape := circle float | rect float float\n## The answer.\nint main := 42\n";
+        let global_state = compile(program).unwrap();
+        assert_eq!(
+            global_state.get_doc(&Symbol::new("main".to_string())),
+            Some("The answer.")
+        );
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(42)));
+    }
+
+    #[test]
+    fn deeply_nested_operators_compile() {
+        let mut body = "1".to_string();
+        for _ in 0..40 {
+            body = format!("+ 1 ({})", body);
+        }
+        let program = format!("int main := {}\n", body);
+        assert_eq!(run(&program).unwrap(), Literal::Integer(Int::new(41)));
+    }
+
+    #[test]
+    fn kicked_assignments_cannot_use_their_own_stage() {
+        let program = "int func scaled := n : int\n  | int k := * n 10\n  | int a := + k 1 | kick\n=> a\n\nint main := scaled 2\n";
+        let errors = errors(program);
+        assert_eq!(errors[0].code, ErrorCode::StageOrder);
+        assert!(errors[0].message.contains("`k`"), "{}", errors[0].message);
+    }
+
+    #[test]
+    fn regular_assignments_cannot_use_what_their_stage_kicks() {
+        let program = "int func scaled := n : int\n  | int a := * n 10 | kick\n  | int b := + a 1\n=> b\n\nint main := scaled 2\n";
+        assert_eq!(errors(program)[0].code, ErrorCode::StageOrder);
+    }
+
+    #[test]
+    fn later_stages_see_kicked_assignments() {
+        let program = "int func scaled := n : int\n  | int k := * n 10\n=>\n  | int a := + k 1 | kick\n  | int b := + n 1\n=>\n  | int c := + a b\n=> c\n\nint main := scaled 2\n";
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(24)));
+    }
+
+    #[test]
+    fn indented_definitions_are_rejected() {
+        for program in [
+            "int a := 1\n  int b := 2\nint main := a\n",
+            "int main := 1\n  type point := x : int y : int\n",
+        ] {
+            let errors = errors(program);
+            assert_eq!(errors[0].code, ErrorCode::UnexpectedToken, "{}", program);
+            assert_eq!(errors[0].message, "definitions must start in column 1");
+        }
+    }
+
+    #[test]
+    fn types_cannot_be_defined_twice() {
+        let errors = errors(
+            "type point := x : int y : int\ntype point := Origin | At int int\nint main := 1\n",
+        );
+        assert_eq!(errors[0].code, ErrorCode::DuplicateType);
+        assert_eq!(errors[0].message, "type `point` is already defined");
+        assert_eq!(errors[0].labels[0].span.line, 2);
+        assert_eq!(errors[0].labels[1].span.line, 1);
+        assert_eq!(errors[0].labels[1].message, "`point` is first defined here");
+    }
+
+    #[test]
+    fn definitions_can_be_used_before_they_are_written() {
+        let program = "\
+int main := + total (double 4)
+int total := + base 1
+int func double := n : int => * n 2
+int base := 10
+";
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(19)));
+    }
+
+    #[test]
+    fn functions_can_call_each_other() {
+        let program = "\
+bool main := && (is_even 10) (is_odd 7)
+bool func is_even := n : int => cond (== n 0) true (is_odd (- n 1))
+bool func is_odd := n : int => cond (== n 0) false (is_even (- n 1))
+";
+        assert_eq!(run(program).unwrap(), Literal::Bool(Bool::new(true)));
+    }
+
+    #[test]
+    fn forward_references_are_type_checked() {
+        let errors = errors("int main := later\nstr later := \"x\"\n");
+        assert_eq!(errors[0].code, ErrorCode::TypeMismatch);
+    }
+}
@@ -0,0 +1,134 @@
+pub const USAGE: &str = "Usage:
+    breakout [-I <dir>]... [--max-errors <n>] [--error-format=human|json] <file>
+    breakout doc [-I <dir>]... [-o <dir>] <file>
+    breakout fmt [--check] <file>...
+    breakout explain <code>";
+
+pub enum Command {
+    Run(Options),
+    /// Writes documentation of the program into the given directory.
+    Doc(Options, String),
+    /// Reformats the given files in place, or with `check` only reports those that would change.
+    Fmt {
+        paths: Vec<String>,
+        check: bool,
+    },
+    Explain(String),
+}
+impl Command {
+    pub fn from_args(args: &[String]) -> Result<Command, String> {
+        match args.first().map(String::as_str) {
+            Some("explain") => match args {
+                [_, code] => Ok(Command::Explain(code.clone())),
+                _ => Err("explain expects a single error code, e.g. B0012".to_string()),
+            },
+            Some("doc") => {
+                let mut out_dir = "doc".to_string();
+                let mut rest = Vec::new();
+                let mut args = args[1..].iter();
+                while let Some(arg) = args.next() {
+                    if arg == "-o" {
+                        match args.next() {
+                            Some(dir) => out_dir = dir.clone(),
+                            None => return Err("-o expects a directory".to_string()),
+                        }
+                    } else if let Some(dir) = flag_value(arg, "--out", &mut args)? {
+                        out_dir = dir.to_string();
+                    } else {
+                        rest.push(arg.clone());
+                    }
+                }
+                Options::from_args(&rest).map(|options| Command::Doc(options, out_dir))
+            }
+            Some("fmt") => {
+                let mut paths = Vec::new();
+                let mut check = false;
+                for arg in &args[1..] {
+                    if arg == "--check" {
+                        check = true;
+                    } else if arg.starts_with("--") {
+                        return Err(format!("unknown option `{}`", arg));
+                    } else {
+                        paths.push(arg.clone());
+                    }
+                }
+                if paths.is_empty() {
+                    return Err("fmt expects at least one file".to_string());
+                }
+                Ok(Command::Fmt { paths, check })
+            }
+            _ => Options::from_args(args).map(Command::Run),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+pub struct Options {
+    pub path: String,
+    pub include_paths: Vec<String>,
+    pub max_errors: usize,
+    pub error_format: ErrorFormat,
+}
+
+impl Options {
+    pub fn from_args(args: &[String]) -> Result<Options, String> {
+        let mut path = None;
+        let mut include_paths = Vec::new();
+        let mut max_errors = 20;
+        let mut error_format = ErrorFormat::Human;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-I" {
+                match args.next() {
+                    Some(dir) => include_paths.push(dir.clone()),
+                    None => return Err("-I expects a directory".to_string()),
+                }
+            } else if let Some(dir) = arg.strip_prefix("-I") {
+                include_paths.push(dir.to_string());
+            } else if let Some(value) = flag_value(arg, "--max-errors", &mut args)? {
+                max_errors = value
+                    .parse()
+                    .map_err(|_| format!("--max-errors expects a number, got `{}`", value))?;
+            } else if let Some(value) = flag_value(arg, "--error-format", &mut args)? {
+                error_format = match value {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    _ => return Err(format!("unknown error format `{}`", value)),
+                };
+            } else if arg.starts_with("--") {
+                return Err(format!("unknown option `{}`", arg));
+            } else if path.replace(arg.clone()).is_some() {
+                return Err(format!("unexpected argument `{}`", arg));
+            }
+        }
+        Ok(Options {
+            path: path.ok_or("no file given")?,
+            include_paths,
+            max_errors,
+            error_format,
+        })
+    }
+}
+
+/// Reads the value of `name` given either as `name=value` or as `name value`.
+fn flag_value<'a>(
+    arg: &'a str,
+    name: &str,
+    rest: &mut impl Iterator<Item = &'a String>,
+) -> Result<Option<&'a str>, String> {
+    if arg == name {
+        match rest.next() {
+            Some(value) => Ok(Some(value)),
+            None => Err(format!("{} expects a value", name)),
+        }
+    } else {
+        Ok(arg
+            .strip_prefix(name)
+            .and_then(|value| value.strip_prefix('=')))
+    }
+}
@@ -0,0 +1,297 @@
+use crate::lexer::{hidden_lines, lex};
+use crate::parser::{resolve_type_names, token_kind, ParsingError, Token, TokenKind};
+use crate::scanner::{is_directive, PreToken, PreTokenized, TypeTable};
+use crate::span::Span;
+
+/// Source text that does not change what a program means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    /// A `,` or `;`, which the language treats as whitespace.
+    Separator,
+    /// A `#` line comment or a `#[ ... ]#` block comment.
+    Comment,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// A token together with the source text it was read from.
+#[derive(Debug, Clone)]
+pub struct CstToken {
+    /// The trivia between the previous token and this one.
+    pub leading: Vec<Trivia>,
+    pub token: Token,
+    /// The token as written. For an `EOL` this is the line break itself, which is empty at the
+    /// end of a file that does not end with one.
+    pub text: String,
+}
+
+/// The concrete syntax tree of one source file: every token along with the trivia before it,
+/// which together hold every character of the file.
+#[derive(Debug, Clone)]
+pub struct SyntaxTree {
+    tokens: Vec<CstToken>,
+    /// Trivia after the last token.
+    trailing: Vec<Trivia>,
+}
+impl SyntaxTree {
+    pub fn tokens(&self) -> &[CstToken] {
+        &self.tokens
+    }
+    /// The text the tree was built from.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for token in &self.tokens {
+            for trivia in &token.leading {
+                out += &trivia.text;
+            }
+            out += &token.text;
+        }
+        for trivia in &self.trailing {
+            out += &trivia.text;
+        }
+        out
+    }
+    /// The tokens `generate_ast` reads, which leave out `include` and `import` lines as the
+    /// scanner has already acted on them.
+    pub fn into_program_tokens(self) -> Vec<Token> {
+        self.tokens
+            .into_iter()
+            .filter(|t| !matches!(t.token.kind, TokenKind::Directive(_)))
+            .map(|t| t.token)
+            .collect()
+    }
+}
+
+/// Builds the syntax tree of a file. Only errors the lexer finds are reported; tokens out of
+/// place are left for `generate_ast`.
+pub fn build(
+    contents: &str,
+    file: usize,
+    types: &mut TypeTable,
+) -> Result<SyntaxTree, ParsingError> {
+    let source = Source::new(contents, file);
+    let hidden = hidden_lines(contents);
+    let mut lines = Vec::new();
+    let mut pieces: Vec<(usize, Piece)> = Vec::new();
+    for (i, line) in source.lines.iter().enumerate() {
+        // directive lines are not lexed, as paths need not be valid tokens
+        if !hidden[i] && is_directive(line) {
+            let span = Span::new(file, i + 1, 1, line.chars().count() + 1);
+            let kind = TokenKind::Directive(line.to_string());
+            pieces.push((
+                source.starts[i],
+                Piece::Token(Token::new(kind, span), line.to_string()),
+            ));
+            lines.push((String::new(), file, i + 1));
+        } else {
+            lines.push((line.to_string(), file, i + 1));
+        }
+    }
+    for (token, span) in lex(&lines)? {
+        let start = match token {
+            PreTokenized::T(PreToken::EOL) => source.line_end(span.line),
+            _ => source.offset(span.line, span.col),
+        };
+        let end = match token {
+            PreTokenized::T(PreToken::EOL) => source.next_line_start(span.line),
+            _ => source.offset(span.end_line, span.end_col),
+        };
+        let text = contents[start..end].to_string();
+        let piece = match token {
+            PreTokenized::Comment(_) => Piece::Comment(span, text),
+            token => Piece::Token(Token::new(token_kind(token, span)?, span), text),
+        };
+        pieces.push((start, piece));
+    }
+    pieces.sort_by_key(|(start, _)| *start);
+    resolve_type_names(
+        pieces
+            .iter_mut()
+            .filter_map(|(_, piece)| match piece {
+                Piece::Token(token, _) => Some(&mut token.kind),
+                Piece::Comment(..) => None,
+            })
+            .collect(),
+        types,
+    );
+
+    let mut tokens = Vec::new();
+    let mut leading = Vec::new();
+    let mut cursor = 0;
+    for (start, piece) in pieces {
+        leading.extend(source.trivia(cursor, start));
+        match piece {
+            Piece::Comment(span, text) => {
+                cursor = start + text.len();
+                leading.push(Trivia {
+                    kind: TriviaKind::Comment,
+                    text,
+                    span,
+                });
+            }
+            Piece::Token(token, text) => {
+                cursor = start + text.len();
+                tokens.push(CstToken {
+                    leading: std::mem::take(&mut leading),
+                    token,
+                    text,
+                });
+            }
+        }
+    }
+    leading.extend(source.trivia(cursor, contents.len()));
+    let tree = SyntaxTree {
+        tokens,
+        trailing: leading,
+    };
+    debug_assert_eq!(tree.text(), contents);
+    Ok(tree)
+}
+
+enum Piece {
+    Token(Token, String),
+    Comment(Span, String),
+}
+
+/// Maps the lines and columns of spans to byte offsets in a file.
+struct Source<'a> {
+    contents: &'a str,
+    file: usize,
+    lines: Vec<&'a str>,
+    starts: Vec<usize>,
+}
+impl<'a> Source<'a> {
+    fn new(contents: &'a str, file: usize) -> Source<'a> {
+        let lines: Vec<&str> = contents.lines().collect();
+        let mut starts = Vec::new();
+        let mut start = 0;
+        for line in contents.split_inclusive('\n').take(lines.len()) {
+            starts.push(start);
+            start += line.len();
+        }
+        Source {
+            contents,
+            file,
+            lines,
+            starts,
+        }
+    }
+    fn offset(&self, line: usize, col: usize) -> usize {
+        let text = self.lines[line - 1];
+        self.starts[line - 1]
+            + text
+                .char_indices()
+                .nth(col - 1)
+                .map_or(text.len(), |(i, _)| i)
+    }
+    fn line_end(&self, line: usize) -> usize {
+        self.starts[line - 1] + self.lines[line - 1].len()
+    }
+    fn next_line_start(&self, line: usize) -> usize {
+        self.starts
+            .get(line)
+            .copied()
+            .unwrap_or(self.contents.len())
+    }
+    /// The 1-based line and column of a byte offset.
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset).max(1);
+        let col = self.contents[self.starts[line - 1]..offset].chars().count() + 1;
+        (line, col)
+    }
+    /// Splits the text between two tokens into runs of whitespace and single separators.
+    fn trivia(&self, start: usize, end: usize) -> Vec<Trivia> {
+        let mut out: Vec<Trivia> = Vec::new();
+        let mut run_start = start;
+        for (i, c) in self.contents[start..end].char_indices() {
+            let i = start + i;
+            if c.is_whitespace() {
+                continue;
+            }
+            if run_start < i {
+                out.push(self.trivia_at(TriviaKind::Whitespace, run_start, i));
+            }
+            out.push(self.trivia_at(TriviaKind::Separator, i, i + c.len_utf8()));
+            run_start = i + c.len_utf8();
+        }
+        if run_start < end {
+            out.push(self.trivia_at(TriviaKind::Whitespace, run_start, end));
+        }
+        out
+    }
+    fn trivia_at(&self, kind: TriviaKind, start: usize, end: usize) -> Trivia {
+        let (line, col) = self.position(start);
+        let (end_line, end_col) = self.position(end);
+        Trivia {
+            kind,
+            text: self.contents[start..end].to_string(),
+            span: Span {
+                file: self.file,
+                line,
+                col,
+                end_line,
+                end_col,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(contents: &str) {
+        let tree = build(contents, 0, &mut TypeTable::new()).unwrap();
+        assert_eq!(tree.text(), contents);
+    }
+
+    #[test]
+    fn crlf_line_breaks_are_kept() {
+        round_trip("int a := 1\r\n\r\nint main := + a 2\r\n");
+    }
+
+    #[test]
+    fn a_missing_final_newline_is_kept() {
+        round_trip("int main := 1");
+        round_trip("int main := 1\n\n   ");
+    }
+
+    #[test]
+    fn comments_and_separators_are_kept() {
+        round_trip(
+            "# line comment\n## doc\nint main := + 1, 2 #[ block\n  #[ nested ]# ]# ; # end\n",
+        );
+        let tree = build("int main := 1 # note\n", 0, &mut TypeTable::new()).unwrap();
+        let comments: Vec<&str> = tree
+            .tokens()
+            .iter()
+            .flat_map(|t| &t.leading)
+            .filter(|t| t.kind == TriviaKind::Comment)
+            .map(|t| t.text.as_str())
+            .collect();
+        assert_eq!(comments, ["# note"]);
+    }
+
+    #[test]
+    fn unicode_is_kept() {
+        round_trip("str main := concat \"héllo \\u{1F600}\" \"\"\"\n日本語\n\"\"\" # ✓\n");
+    }
+
+    #[test]
+    fn directives_are_kept_but_not_read() {
+        let contents = "include other.bo\nimport \"lib/x.bo\" as x\nint main := 1\n";
+        round_trip(contents);
+        let tokens = build(contents, 0, &mut TypeTable::new())
+            .unwrap()
+            .into_program_tokens();
+        assert!(tokens
+            .iter()
+            .all(|t| !matches!(t.kind, TokenKind::Directive(_))));
+    }
+}
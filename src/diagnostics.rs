@@ -0,0 +1,471 @@
+use crate::errors::{CompileError, ErrorCode, NameError, ProgramError, SyntaxError, TypeError};
+use crate::parser::ParsingError;
+use crate::span::{SourceMap, Span};
+use std::io::IsTerminal;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+/// A message about the program, pointing at the source that caused it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+    pub fn with_primary(mut self, span: Span, message: String) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            message,
+            primary: true,
+        });
+        self
+    }
+    pub fn with_secondary(mut self, span: Span, message: String) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            message,
+            primary: false,
+        });
+        self
+    }
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    /// Renders the diagnostic with the offending lines of source underlined.
+    pub fn render(&self, sources: &SourceMap, color: bool) -> String {
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (reset, bold, blue) = (paint(RESET), paint(BOLD), paint(BLUE));
+        let severity_color = match self.severity {
+            Severity::Error => paint(RED),
+        };
+
+        let mut out = format!(
+            "{}{}[{}]{}: {}{}{}\n",
+            severity_color, self.severity, self.code, reset, bold, self.message, reset
+        );
+
+        let primary = self.labels.iter().find(|l| l.primary).map(|l| l.span);
+        let primary_file = primary.map(|s| s.file);
+        let mut labels: Vec<&Label> = self.labels.iter().collect();
+        labels.sort_by_key(|l| {
+            let file = l.span.file;
+            (Some(file) != primary_file, file, l.span.line, !l.primary)
+        });
+        let gutter = labels
+            .iter()
+            .map(|l| l.span.end_line.max(l.span.line).to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(gutter);
+
+        let mut current: Option<(usize, usize)> = None;
+        for label in labels {
+            let span = label.span;
+            if sources.line(span.file, span.line).is_none() {
+                continue;
+            }
+            if current.map(|(file, _)| file) != Some(span.file) {
+                let location = match primary {
+                    Some(primary) if primary.file == span.file => primary,
+                    _ => span,
+                };
+                out += &format!(
+                    "{}{}-->{} {}\n{}{} |{}\n",
+                    pad,
+                    blue,
+                    reset,
+                    sources.location(location),
+                    blue,
+                    pad,
+                    reset
+                );
+                current = None;
+            }
+            let (marker, marker_color) = if label.primary {
+                ('^', severity_color)
+            } else {
+                ('-', blue)
+            };
+            // a span over several lines underlines each of them, with the message on the last
+            let last = span.end_line.max(span.line);
+            for line in span.line..=last {
+                let Some(source_line) = sources.line(span.file, line) else {
+                    break;
+                };
+                let source_line = source_line.replace('\t', " ");
+                if current != Some((span.file, line)) {
+                    out += &format!(
+                        "{}{:>width$} |{} {}\n",
+                        blue,
+                        line,
+                        reset,
+                        source_line,
+                        width = gutter
+                    );
+                    current = Some((span.file, line));
+                }
+                let line_len = source_line.chars().count() + 1;
+                let start = if line == span.line {
+                    span.col
+                } else {
+                    source_line.chars().take_while(|c| *c == ' ').count() + 1
+                };
+                let end = if line < last {
+                    line_len
+                } else {
+                    span.end_col.min(line_len)
+                };
+                let underline = format!(
+                    "{}{} |{} {}{}{} {}",
+                    blue,
+                    pad,
+                    reset,
+                    " ".repeat(start.saturating_sub(1)),
+                    marker_color,
+                    marker.to_string().repeat(end.saturating_sub(start).max(1)),
+                    if line == last {
+                        label.message.as_str()
+                    } else {
+                        ""
+                    },
+                );
+                out += &format!("{}{}\n", underline.trim_end(), reset);
+            }
+        }
+        for note in &self.notes {
+            out += &format!(
+                "{}{} ={} {}note{}: {}\n",
+                blue, pad, reset, bold, reset, note
+            );
+        }
+        out
+    }
+
+    /// Renders the diagnostic as a single line of JSON for tools to consume.
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        let primary = self.labels.iter().find(|l| l.primary);
+        let related: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|l| !l.primary)
+            .map(|l| {
+                format!(
+                    "{{{},\"message\":{}}}",
+                    json_span(l.span, sources),
+                    json_string(&l.message)
+                )
+            })
+            .collect();
+        let notes: Vec<String> = self.notes.iter().map(|n| json_string(n)).collect();
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},{},\"label\":{},\"related\":[{}],\"notes\":[{}]}}",
+            json_string(&self.severity.to_string()),
+            json_string(self.code.as_str()),
+            json_string(&self.message),
+            match primary {
+                Some(label) => json_span(label.span, sources),
+                None => "\"file\":null,\"line\":null,\"column\":null,\"end_line\":null,\"end_column\":null"
+                    .to_string(),
+            },
+            match primary {
+                Some(label) if !label.message.is_empty() => json_string(&label.message),
+                _ => "null".to_string(),
+            },
+            related.join(","),
+            notes.join(",")
+        )
+    }
+}
+
+fn json_span(span: Span, sources: &SourceMap) -> String {
+    format!(
+        "\"file\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}",
+        json_string(sources.path(span.file)),
+        span.line,
+        span.col,
+        span.end_line,
+        span.end_col
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Color is used only when writing to a terminal and `NO_COLOR` is not set.
+pub fn use_color() -> bool {
+    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+impl From<&ParsingError> for Diagnostic {
+    fn from(e: &ParsingError) -> Self {
+        Diagnostic::error(e.code, e.message.clone()).with_primary(e.span, "here".to_string())
+    }
+}
+
+impl From<&SyntaxError> for Diagnostic {
+    fn from(e: &SyntaxError) -> Self {
+        match e {
+            SyntaxError::UnexpectedToken {
+                expected,
+                found,
+                span,
+            } => Diagnostic::error(e.code(), format!("expected {}, found {}", expected, found))
+                .with_primary(*span, format!("expected {}", expected)),
+            SyntaxError::UnexpectedEol { expected, span } => Diagnostic::error(
+                e.code(),
+                format!("expected {}, found end of line", expected),
+            )
+            .with_primary(*span, format!("expected {}", expected)),
+            SyntaxError::UnexpectedEof { expected } => Diagnostic::error(
+                e.code(),
+                format!("expected {}, found end of file", expected),
+            ),
+            SyntaxError::UnbalancedParen { open, found, span } => {
+                Diagnostic::error(e.code(), format!("unclosed `(`, found {}", found))
+                    .with_primary(*span, "expected `)`".to_string())
+                    .with_secondary(*open, "unclosed `(` opened here".to_string())
+            }
+        }
+    }
+}
+
+impl From<&TypeError> for Diagnostic {
+    fn from(e: &TypeError) -> Self {
+        let mut diagnostic = Diagnostic::error(e.code, e.message.clone()).with_primary(
+            e.span,
+            format!("expected {}, found {}", e.expected, e.found),
+        );
+        for (span, message) in &e.related {
+            diagnostic = diagnostic.with_secondary(*span, message.clone());
+        }
+        if e.expected == "int" && e.found == "float" {
+            diagnostic =
+                diagnostic.with_note("use `floor` to turn a float into an int".to_string());
+        } else if e.expected == "float" && e.found == "int" {
+            diagnostic = diagnostic.with_note(
+                "write a float literal with a fraction or suffix, as in `3.0` or `3f`".to_string(),
+            );
+        }
+        diagnostic
+    }
+}
+
+impl From<&NameError> for Diagnostic {
+    fn from(e: &NameError) -> Self {
+        let label = match e.code {
+            ErrorCode::PrivateSymbol => "private",
+            _ => "not found in this scope",
+        };
+        let mut diagnostic =
+            Diagnostic::error(e.code, e.message.clone()).with_primary(e.span, label.to_string());
+        for (span, message) in &e.related {
+            diagnostic = diagnostic.with_secondary(*span, message.clone());
+        }
+        match e.suggestions.as_slice() {
+            [] => {}
+            [one] => diagnostic = diagnostic.with_note(format!("did you mean `{}`?", one)),
+            many => {
+                let names: Vec<String> = many.iter().map(|s| format!("`{}`", s)).collect();
+                diagnostic =
+                    diagnostic.with_note(format!("did you mean one of {}?", names.join(", ")));
+            }
+        }
+        if let Some(keyword) = e.keywords.first() {
+            diagnostic = diagnostic.with_note(format!(
+                "`{}` looks like a typo of the keyword `{}`",
+                e.name, keyword
+            ));
+        }
+        if e.code == ErrorCode::PrivateSymbol {
+            diagnostic = diagnostic
+                .with_note("mark the definition `pub` to use it outside its module".to_string());
+        }
+        diagnostic
+    }
+}
+
+impl From<&ProgramError> for Diagnostic {
+    fn from(e: &ProgramError) -> Self {
+        let mut diagnostic = Diagnostic::error(e.code, e.message.clone());
+        if let Some(span) = e.span {
+            diagnostic = diagnostic.with_primary(span, String::new());
+        }
+        for (span, message) in &e.related {
+            diagnostic = diagnostic.with_secondary(*span, message.clone());
+        }
+        diagnostic
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(e: &CompileError) -> Self {
+        match e {
+            CompileError::Parsing(e) => e.into(),
+            CompileError::Syntax(e) => e.into(),
+            CompileError::Type(e) => e.as_ref().into(),
+            CompileError::Name(e) => e.as_ref().into(),
+            CompileError::Program(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::compile_files;
+
+    /// The diagnostics of `contents`, which must not compile, with the sources they point into.
+    fn diagnostics(contents: &str) -> (Vec<Diagnostic>, SourceMap) {
+        let (result, sources) = compile_files(&[("main.bo", contents)]);
+        match result {
+            Ok(_) => panic!("compiles: {}", contents),
+            Err(errors) => (errors.iter().map(Diagnostic::from).collect(), sources),
+        }
+    }
+
+    #[test]
+    fn errors_underline_the_source_and_label_related_spans() {
+        let (diagnostics, sources) =
+            diagnostics("int func f := n : int => \"no\"\nint main := f 1\n");
+        let rendered = diagnostics[0].render(&sources, false);
+        let path = sources.path(0);
+        assert_eq!(
+            rendered,
+            format!(
+                "error[B0009]: type mismatch
+ --> {}:1:26
+  |
+1 | int func f := n : int => \"no\"
+  |                          ^^^^ expected int, found str
+  | ---------- the function was declared to return int here
+",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn colors_are_only_used_when_asked_for() {
+        let (diagnostics, sources) = diagnostics("int main := + 1 true\n");
+        assert!(!diagnostics[0].render(&sources, false).contains('\x1b'));
+        let colored = diagnostics[0].render(&sources, true);
+        assert!(colored.starts_with(RED));
+        assert!(colored.contains(BLUE));
+    }
+
+    #[test]
+    fn spans_over_several_lines_underline_each_line() {
+        let mut sources = SourceMap::new();
+        let file = sources.add(
+            "main.bo".to_string(),
+            "int main := cond\n  | true => 1\n  | else => 2\n".to_string(),
+        );
+        let span = Span {
+            file,
+            line: 1,
+            col: 13,
+            end_line: 3,
+            end_col: 14,
+        };
+        let diagnostic = Diagnostic::error(ErrorCode::TypeMismatch, "mismatched".to_string())
+            .with_primary(span, "this cond".to_string());
+        assert_eq!(
+            diagnostic.render(&sources, false),
+            "error[B0009]: mismatched
+ --> main.bo:1:13
+  |
+1 | int main := cond
+  |             ^^^^
+2 |   | true => 1
+  |   ^^^^^^^^^^^
+3 |   | else => 2
+  |   ^^^^^^^^^^^ this cond
+"
+        );
+    }
+
+    #[test]
+    fn json_diagnostics_are_one_line_each() {
+        let (diagnostics, sources) =
+            diagnostics("int func f := n : int => \"no\"\nint main := f 1\n");
+        let json = diagnostics[0].to_json(&sources);
+        assert!(!json.contains('\n'));
+        let path = json_string(sources.path(0));
+        assert_eq!(
+            json,
+            format!(
+                "{{\"severity\":\"error\",\"code\":\"B0009\",\"message\":\"type mismatch\",\
+                 \"file\":{0},\"line\":1,\"column\":26,\"end_line\":1,\"end_column\":30,\
+                 \"label\":\"expected int, found str\",\"related\":[{{\"file\":{0},\"line\":1,\
+                 \"column\":1,\"end_line\":1,\"end_column\":11,\
+                 \"message\":\"the function was declared to return int here\"}}],\"notes\":[]}}",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn runtime_errors_are_reported_as_json_too() {
+        let (result, sources) = compile_files(&[("main.bo", "int main := / 1 0\n")]);
+        let error = result.unwrap().eval_main().unwrap_err();
+        let json = Diagnostic::from(&error).to_json(&sources);
+        assert!(json.starts_with("{\"severity\":\"error\",\"code\":\"B0013\","));
+        assert!(json.contains("\"line\":1,\"column\":13,"));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a \"b\"\n\\"), "\"a \\\"b\\\"\\n\\\\\"");
+    }
+}
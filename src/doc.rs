@@ -0,0 +1,328 @@
+use crate::functions::Function;
+use crate::globalstate::GlobalState;
+use crate::parser::Symbol;
+use crate::scanner::{Type, TypeTable};
+use crate::span::{SourceMap, Span};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A documented top-level definition.
+struct Entry {
+    name: String,
+    signature: String,
+    /// Sentences describing the definition, with names in backticks.
+    summary: Vec<String>,
+    doc: Option<String>,
+    location: String,
+}
+
+/// The definitions of one module, which become one page of documentation.
+pub struct Page {
+    title: String,
+    file_stem: String,
+    entries: Vec<Entry>,
+}
+
+/// Collects the documentation of every module, starting with the main one.
+pub fn pages(global_state: &GlobalState, sources: &SourceMap) -> Vec<Page> {
+    let modules = global_state.modules();
+    let mut pages: Vec<Page> = modules
+        .iter()
+        .map(|(_, module)| {
+            let name = match module.name.as_str() {
+                "" => Path::new(sources.path(0))
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "main".to_string()),
+                name => name.to_string(),
+            };
+            Page {
+                title: name.clone(),
+                file_stem: name,
+                entries: Vec::new(),
+            }
+        })
+        .collect();
+    let types = global_state.types();
+    let mut constructors: Vec<Span> = Vec::new();
+    for &(t, span) in global_state.declared_types() {
+        let (signature, summary) = describe_type(types, t);
+        if let Type::Data(data) = t {
+            constructors.extend(data.variants(types).iter().map(|variant| variant.span));
+        }
+        let name = t.display(types).to_string();
+        pages[modules.module_of(span.file)].entries.push(Entry {
+            doc: global_state
+                .get_doc(&Symbol::new(name.clone()))
+                .map(str::to_string),
+            name,
+            signature,
+            summary,
+            location: sources.location(span),
+        });
+    }
+    for (symbol, span) in global_state.definitions() {
+        // the variants of a sum type are documented with the type
+        if constructors.contains(&span) {
+            continue;
+        }
+        let module = modules.module_of(span.file);
+        let qualified = symbol.name();
+        let name = modules
+            .unqualify(module, &qualified)
+            .unwrap_or(&qualified)
+            .to_string();
+        let visibility = if global_state.is_exported(&symbol) {
+            "pub "
+        } else {
+            ""
+        };
+        let (signature, summary) = match global_state.get_function(&symbol) {
+            Some(function) => describe_function(types, visibility, &name, &function),
+            None => {
+                let t = global_state.get_type(&symbol).unwrap().display(types);
+                (
+                    format!("{}{} {}", visibility, t, name),
+                    vec![format!("Variable of type `{}`.", t)],
+                )
+            }
+        };
+        pages[module].entries.push(Entry {
+            name,
+            signature,
+            summary,
+            doc: global_state.get_doc(&symbol).map(str::to_string),
+            location: sources.location(span),
+        });
+    }
+    pages
+}
+
+fn describe_type(types: &TypeTable, t: Type) -> (String, Vec<String>) {
+    let name = t.display(types);
+    match t {
+        Type::Record(record) => {
+            let fields = record.fields(types);
+            let declared: Vec<String> = fields
+                .iter()
+                .map(|field| format!("{} : {}", field.name, field.t.display(types)))
+                .collect();
+            let names: Vec<String> = fields
+                .iter()
+                .map(|field| format!("`{}`", field.name))
+                .collect();
+            let summary = match names.len() {
+                0 => "Record type with no fields.".to_string(),
+                _ => format!("Record type with the fields {}.", names.join(", ")),
+            };
+            (
+                format!("type {} := {}", name, declared.join(" ")),
+                vec![summary],
+            )
+        }
+        Type::Data(data) => {
+            let variants = data.variants(types);
+            let declared: Vec<String> = variants
+                .iter()
+                .map(|variant| {
+                    let mut declared = variant.name.clone();
+                    for field in &variant.fields {
+                        match field {
+                            Type::Func(_) => declared += &format!(" ({})", field.display(types)),
+                            _ => declared += &format!(" {}", field.display(types)),
+                        }
+                    }
+                    declared
+                })
+                .collect();
+            let names: Vec<String> = variants
+                .iter()
+                .map(|variant| format!("`{}`", variant.name))
+                .collect();
+            (
+                format!("type {} := {}", name, declared.join(" | ")),
+                vec![format!(
+                    "Sum type whose variant{} {} build{} a `{}`.",
+                    if names.len() == 1 { "" } else { "s" },
+                    names.join(", "),
+                    if names.len() == 1 { "s" } else { "" },
+                    name
+                )],
+            )
+        }
+        _ => unreachable!("only records and sum types are declared with `type`"),
+    }
+}
+
+fn describe_function(
+    types: &TypeTable,
+    visibility: &str,
+    name: &str,
+    function: &Function,
+) -> (String, Vec<String>) {
+    let args: Vec<String> = function
+        .get_args()
+        .iter()
+        .map(|(arg, t)| format!("{} : {}", arg, t.display(types)))
+        .collect();
+    let ret = function.get_type().display(types);
+    let signature = format!("{}{} func {} := {}", visibility, ret, name, args.join(" "));
+    let stages = function.get_stages();
+    let mut summary = Vec::new();
+    if stages.is_empty() {
+        summary.push(format!("Simple function returning `{}`.", ret));
+    } else {
+        summary.push(format!(
+            "Breakout function with {} stage{} returning `{}`.",
+            stages.len(),
+            if stages.len() == 1 { "" } else { "s" },
+            ret
+        ));
+    }
+    for (i, stage) in stages.iter().enumerate() {
+        let kicked: Vec<String> = stage
+            .kicked()
+            .iter()
+            .map(|name| format!("`{}`", name))
+            .collect();
+        if !kicked.is_empty() {
+            summary.push(format!(
+                "Stage {} kicks {} onto {} of {} own.",
+                i + 1,
+                kicked.join(", "),
+                if kicked.len() == 1 {
+                    "a thread"
+                } else {
+                    "threads"
+                },
+                if kicked.len() == 1 { "its" } else { "their" }
+            ));
+        }
+    }
+    (signature, summary)
+}
+
+pub fn markdown(page: &Page) -> String {
+    let mut out = format!("# {}\n", page.title);
+    if page.entries.is_empty() {
+        out += "\nThis module has no definitions.\n";
+    }
+    for entry in &page.entries {
+        out += &format!("\n## `{}`\n\n```\n{}\n```\n\n", entry.name, entry.signature);
+        out += &entry.summary.join(" ");
+        out += "\n";
+        if let Some(doc) = &entry.doc {
+            out += &format!("\n{}\n", doc);
+        }
+        out += &format!("\n*Defined at `{}`.*\n", entry.location);
+    }
+    out
+}
+
+pub fn html(page: &Page) -> String {
+    let mut body = String::new();
+    if page.entries.is_empty() {
+        body += "<p>This module has no definitions.</p>\n";
+    }
+    for entry in &page.entries {
+        body += &format!(
+            "<section id=\"{0}\">\n<h2><code>{0}</code></h2>\n<pre><code>{1}</code></pre>\n<p>{2}</p>\n",
+            escape(&entry.name),
+            escape(&entry.signature),
+            inline_html(&entry.summary.join(" "))
+        );
+        if let Some(doc) = &entry.doc {
+            for paragraph in doc.split("\n\n").filter(|p| !p.trim().is_empty()) {
+                body += &format!("<p>{}</p>\n", inline_html(paragraph));
+            }
+        }
+        body += &format!(
+            "<p class=\"location\">Defined at <code>{}</code>.</p>\n</section>\n",
+            escape(&entry.location)
+        );
+    }
+    html_page(&page.title, &body)
+}
+
+pub fn index_markdown(pages: &[Page]) -> String {
+    let mut out = "# Modules\n\n".to_string();
+    for page in pages {
+        out += &format!("- [{}]({}.md)\n", page.title, page.file_stem);
+    }
+    out
+}
+
+pub fn index_html(pages: &[Page]) -> String {
+    let mut body = "<ul>\n".to_string();
+    for page in pages {
+        body += &format!(
+            "<li><a href=\"{}.html\">{}</a></li>\n",
+            escape(&page.file_stem),
+            escape(&page.title)
+        );
+    }
+    body += "</ul>\n";
+    html_page("Modules", &body)
+}
+
+/// Writes a Markdown and an HTML page for every module, plus an index of them, into `dir`.
+pub fn write(pages: &[Page], dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let mut files = vec![
+        (dir.join("index.md"), index_markdown(pages)),
+        (dir.join("index.html"), index_html(pages)),
+    ];
+    for page in pages {
+        files.push((dir.join(format!("{}.md", page.file_stem)), markdown(page)));
+        files.push((dir.join(format!("{}.html", page.file_stem)), html(page)));
+    }
+    for (path, contents) in &files {
+        fs::write(path, contents)?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{0}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; }}
+pre {{ background: #f4f4f4; padding: 0.5em; }}
+.location {{ color: #666; font-size: 0.9em; }}
+</style>
+</head>
+<body>
+<h1>{0}</h1>
+{1}</body>
+</html>
+",
+        escape(title),
+        body
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes `text`, turning the parts written in backticks into code.
+fn inline_html(text: &str) -> String {
+    text.split('`')
+        .enumerate()
+        .map(|(i, part)| {
+            if i % 2 == 1 {
+                format!("<code>{}</code>", escape(part))
+            } else {
+                escape(part)
+            }
+        })
+        .collect()
+}
@@ -1,31 +1,344 @@
-use crate::scanner::Type;
+use crate::parser::ParsingError;
+use crate::span::Span;
 use std::error::Error;
 
+/// Stable identifiers for every kind of error, so they can be searched for and explained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnreadableFile,
+    InvalidLiteral,
+    UnexpectedToken,
+    UnexpectedEol,
+    UnexpectedEof,
+    UnclosedParen,
+    DuplicateDefinition,
+    MissingMain,
+    TypeMismatch,
+    ArgumentType,
+    CondNotBool,
+    CondBranchTypes,
+    DivisionByZero,
+    IntegerOverflow,
+    ThreadPanicked,
+    UnknownSymbol,
+    CyclicDefinition,
+    IncludeCycle,
+    PrivateSymbol,
+    UnterminatedString,
+    UnexpectedCharacter,
+    InvalidEscape,
+    UnterminatedComment,
+    MatchArmTypes,
+    NonExhaustiveMatch,
+    MissingElse,
+    UnknownField,
+    StageOrder,
+    DuplicateType,
+}
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::UnreadableFile,
+        ErrorCode::InvalidLiteral,
+        ErrorCode::UnexpectedToken,
+        ErrorCode::UnexpectedEol,
+        ErrorCode::UnexpectedEof,
+        ErrorCode::UnclosedParen,
+        ErrorCode::DuplicateDefinition,
+        ErrorCode::MissingMain,
+        ErrorCode::TypeMismatch,
+        ErrorCode::ArgumentType,
+        ErrorCode::CondNotBool,
+        ErrorCode::CondBranchTypes,
+        ErrorCode::DivisionByZero,
+        ErrorCode::IntegerOverflow,
+        ErrorCode::ThreadPanicked,
+        ErrorCode::UnknownSymbol,
+        ErrorCode::CyclicDefinition,
+        ErrorCode::IncludeCycle,
+        ErrorCode::PrivateSymbol,
+        ErrorCode::UnterminatedString,
+        ErrorCode::UnexpectedCharacter,
+        ErrorCode::InvalidEscape,
+        ErrorCode::UnterminatedComment,
+        ErrorCode::MatchArmTypes,
+        ErrorCode::NonExhaustiveMatch,
+        ErrorCode::MissingElse,
+        ErrorCode::UnknownField,
+        ErrorCode::StageOrder,
+        ErrorCode::DuplicateType,
+    ];
+    /// The code as printed in messages, e.g. `B0012`. Codes are never reused or renumbered.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::UnreadableFile => "B0001",
+            ErrorCode::InvalidLiteral => "B0002",
+            ErrorCode::UnexpectedToken => "B0003",
+            ErrorCode::UnexpectedEol => "B0004",
+            ErrorCode::UnexpectedEof => "B0005",
+            ErrorCode::UnclosedParen => "B0006",
+            ErrorCode::DuplicateDefinition => "B0007",
+            ErrorCode::MissingMain => "B0008",
+            ErrorCode::TypeMismatch => "B0009",
+            ErrorCode::ArgumentType => "B0010",
+            ErrorCode::CondNotBool => "B0011",
+            ErrorCode::CondBranchTypes => "B0012",
+            ErrorCode::DivisionByZero => "B0013",
+            ErrorCode::IntegerOverflow => "B0014",
+            ErrorCode::ThreadPanicked => "B0015",
+            ErrorCode::UnknownSymbol => "B0016",
+            ErrorCode::CyclicDefinition => "B0017",
+            ErrorCode::IncludeCycle => "B0018",
+            ErrorCode::PrivateSymbol => "B0019",
+            ErrorCode::UnterminatedString => "B0020",
+            ErrorCode::UnexpectedCharacter => "B0021",
+            ErrorCode::InvalidEscape => "B0022",
+            ErrorCode::UnterminatedComment => "B0023",
+            ErrorCode::MatchArmTypes => "B0024",
+            ErrorCode::NonExhaustiveMatch => "B0025",
+            ErrorCode::MissingElse => "B0026",
+            ErrorCode::UnknownField => "B0027",
+            ErrorCode::StageOrder => "B0028",
+            ErrorCode::DuplicateType => "B0029",
+        }
+    }
+    pub fn title(self) -> &'static str {
+        match self {
+            ErrorCode::UnreadableFile => "file could not be read",
+            ErrorCode::InvalidLiteral => "invalid literal",
+            ErrorCode::UnexpectedToken => "unexpected token",
+            ErrorCode::UnexpectedEol => "unexpected end of line",
+            ErrorCode::UnexpectedEof => "unexpected end of file",
+            ErrorCode::UnclosedParen => "unclosed parenthesis",
+            ErrorCode::DuplicateDefinition => "name defined more than once",
+            ErrorCode::MissingMain => "no main definition",
+            ErrorCode::TypeMismatch => "type mismatch",
+            ErrorCode::ArgumentType => "wrong argument type",
+            ErrorCode::CondNotBool => "cond condition is not a bool",
+            ErrorCode::CondBranchTypes => "cond branches have different types",
+            ErrorCode::DivisionByZero => "division by zero",
+            ErrorCode::IntegerOverflow => "integer overflow",
+            ErrorCode::ThreadPanicked => "kicked thread panicked",
+            ErrorCode::UnknownSymbol => "unknown symbol",
+            ErrorCode::CyclicDefinition => "variable defined in terms of itself",
+            ErrorCode::IncludeCycle => "file includes itself",
+            ErrorCode::PrivateSymbol => "definition is private to its module",
+            ErrorCode::UnterminatedString => "unterminated string",
+            ErrorCode::UnexpectedCharacter => "unexpected character",
+            ErrorCode::InvalidEscape => "invalid escape sequence",
+            ErrorCode::UnterminatedComment => "unterminated block comment",
+            ErrorCode::MatchArmTypes => "match arms have different types",
+            ErrorCode::NonExhaustiveMatch => "match does not cover every value",
+            ErrorCode::MissingElse => "cond has no else clause",
+            ErrorCode::UnknownField => "record has no such field",
+            ErrorCode::StageOrder => "stage variable used before it is ready",
+            ErrorCode::DuplicateType => "type defined more than once",
+        }
+    }
+    pub fn from_code(code: &str) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .iter()
+            .find(|c| c.as_str().eq_ignore_ascii_case(code))
+            .copied()
+    }
+}
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub struct ProgramError {
+    pub code: ErrorCode,
     pub message: String,
+    pub span: Option<Span>,
+    pub related: Vec<(Span, String)>,
+}
+impl ProgramError {
+    pub fn new(code: ErrorCode, message: String) -> ProgramError {
+        ProgramError {
+            code,
+            message,
+            span: None,
+            related: Vec::new(),
+        }
+    }
 }
 
 impl std::fmt::Display for ProgramError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+        write!(f, "[{}] {}", self.code, self.message)
     }
 }
 impl Error for ProgramError {}
 
 #[derive(Debug)]
 pub struct TypeError {
+    pub code: ErrorCode,
     pub message: String,
-    pub expected: Type,
-    pub found: Type,
+    /// The expected and found types, as written in programs.
+    pub expected: String,
+    pub found: String,
+    pub span: Span,
+    /// Other places in the source that explain the error.
+    pub related: Vec<(Span, String)>,
 }
 impl std::fmt::Display for TypeError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Type Error: {}, expected: {}, found: {}",
-            self.message, self.expected, self.found
+            "Type Error [{}] at {}: {}, expected: {}, found: {}",
+            self.code, self.span, self.message, self.expected, self.found
         )
     }
 }
 impl Error for TypeError {}
+
+/// A name that does not refer to anything visible where it is used.
+#[derive(Debug)]
+pub struct NameError {
+    pub code: ErrorCode,
+    pub name: String,
+    pub message: String,
+    pub span: Span,
+    /// Known names that are spelled similarly, closest first.
+    pub suggestions: Vec<String>,
+    /// Keywords or operators that are spelled similarly, closest first.
+    pub keywords: Vec<String>,
+    pub related: Vec<(Span, String)>,
+}
+impl std::fmt::Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Name Error [{}] at {}: {}",
+            self.code, self.span, self.message
+        )?;
+        if let Some(suggestion) = self.suggestions.first().or(self.keywords.first()) {
+            write!(f, ", did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+impl Error for NameError {}
+
+#[derive(Debug)]
+pub enum SyntaxError {
+    UnexpectedToken {
+        expected: String,
+        found: String,
+        span: Span,
+    },
+    UnexpectedEol {
+        expected: String,
+        span: Span,
+    },
+    UnexpectedEof {
+        expected: String,
+    },
+    UnbalancedParen {
+        open: Span,
+        found: String,
+        span: Span,
+    },
+}
+impl SyntaxError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SyntaxError::UnexpectedToken { .. } => ErrorCode::UnexpectedToken,
+            SyntaxError::UnexpectedEol { .. } => ErrorCode::UnexpectedEol,
+            SyntaxError::UnexpectedEof { .. } => ErrorCode::UnexpectedEof,
+            SyntaxError::UnbalancedParen { .. } => ErrorCode::UnclosedParen,
+        }
+    }
+}
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyntaxError::UnexpectedToken {
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "Syntax Error [{}] at {}: expected {}, found {}",
+                self.code(),
+                span,
+                expected,
+                found
+            ),
+            SyntaxError::UnexpectedEol { expected, span } => write!(
+                f,
+                "Syntax Error [{}] at {}: expected {}, found end of line",
+                self.code(),
+                span,
+                expected
+            ),
+            SyntaxError::UnexpectedEof { expected } => {
+                write!(
+                    f,
+                    "Syntax Error [{}]: expected {}, found end of file",
+                    self.code(),
+                    expected
+                )
+            }
+            SyntaxError::UnbalancedParen { open, found, span } => write!(
+                f,
+                "Syntax Error [{}] at {}: expected `)` to close the `(` at {}, found {}",
+                self.code(),
+                span,
+                open,
+                found
+            ),
+        }
+    }
+}
+impl Error for SyntaxError {}
+
+/// Everything that can go wrong between reading a file and having a runnable program.
+#[derive(Debug)]
+pub enum CompileError {
+    Parsing(ParsingError),
+    Syntax(SyntaxError),
+    Type(Box<TypeError>),
+    Name(Box<NameError>),
+    Program(ProgramError),
+}
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileError::Parsing(e) => write!(f, "{}", e),
+            CompileError::Syntax(e) => write!(f, "{}", e),
+            CompileError::Type(e) => write!(f, "{}", e),
+            CompileError::Name(e) => write!(f, "{}", e),
+            CompileError::Program(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for CompileError {}
+
+impl From<ParsingError> for CompileError {
+    fn from(e: ParsingError) -> Self {
+        CompileError::Parsing(e)
+    }
+}
+impl From<SyntaxError> for CompileError {
+    fn from(e: SyntaxError) -> Self {
+        CompileError::Syntax(e)
+    }
+}
+impl From<TypeError> for CompileError {
+    fn from(e: TypeError) -> Self {
+        CompileError::Type(Box::new(e))
+    }
+}
+impl From<NameError> for CompileError {
+    fn from(e: NameError) -> Self {
+        CompileError::Name(Box::new(e))
+    }
+}
+impl From<ProgramError> for CompileError {
+    fn from(e: ProgramError) -> Self {
+        CompileError::Program(e)
+    }
+}
@@ -0,0 +1,582 @@
+use crate::errors::ErrorCode;
+
+/// The long-form explanation printed by `breakout explain <code>`.
+pub fn explanation(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::UnreadableFile => {
+            "The file given on the command line, or named by an `include`, could not be opened.
+
+Included paths are looked up relative to the directory of the file containing
+the `include`, then in each directory given with `-I`, then in each directory
+listed in the `BREAKOUT_PATH` environment variable. Check that the path is
+spelled correctly and that the file is readable.
+
+Erroneous code:
+
+    include helpers.b
+
+Corrected code:
+
+    include helpers.bo
+"
+        }
+        ErrorCode::InvalidLiteral => {
+            "A word starts like a number (a digit, `.` or `-` followed by a digit) but is not a
+valid int or float.
+
+Ints are written in decimal, or in hex, binary or octal with a `0x`, `0b` or
+`0o` prefix. Floats have a fraction (`1.5`, `.5`) or an exponent (`1.5e-3`).
+Digits may be grouped with `_`, as in `1_000_000`, and a suffix makes the type
+explicit: `3i` is an int and `3f` a float. Ints must fit in 64 bits, and names
+may not start with a digit.
+
+Erroneous code:
+
+    int 2nd := 0xFG
+
+Corrected code:
+
+    int second := 0xFF
+"
+        }
+        ErrorCode::UnexpectedToken => {
+            "The parser found a token that cannot appear at this point.
+
+The message says what was expected instead. A common cause is a missing `:=`
+after a name, or a missing `:` between an argument and its type.
+
+Erroneous code:
+
+    int func add := a int b int => + a b
+
+Corrected code:
+
+    int func add := a : int b : int => + a b
+"
+        }
+        ErrorCode::UnexpectedEol => {
+            "The line ended before the expression or definition was complete.
+
+Every operator and function takes a fixed number of arguments, so a call
+that is missing an argument runs into the end of the line. An unfinished
+expression carries on onto the next line, but not when that line starts a
+new definition, a `|` stage or a `=>`.
+
+Erroneous code:
+
+    int main := + 1
+
+Corrected code:
+
+    int main := + 1 2
+"
+        }
+        ErrorCode::UnexpectedEof => {
+            "The file ended in the middle of a definition.
+
+Breakout functions need a final `=>` giving the value they return.
+
+Erroneous code:
+
+    int func f := a : int
+    | int b := + a 1
+    =>
+
+Corrected code:
+
+    int func f := a : int
+    | int b := + a 1
+    => b
+"
+        }
+        ErrorCode::UnclosedParen => {
+            "A `(` was opened but the expression inside it was followed by something
+other than `)`.
+
+Parentheses may only wrap a single expression.
+
+Erroneous code:
+
+    int main := (+ 1 2
+
+Corrected code:
+
+    int main := (+ 1 2)
+"
+        }
+        ErrorCode::DuplicateDefinition => {
+            "Two top-level definitions use the same name.
+
+Every variable and function in a file, including those pulled in with
+`include`, shares one namespace, so names must be unique. Definitions of
+modules loaded with `import` live in their own namespace.
+
+Erroneous code:
+
+    int x := 1
+    int x := 2
+
+Corrected code:
+
+    int x := 1
+    int y := 2
+"
+        }
+        ErrorCode::MissingMain => {
+            "The program has no `main` definition, so there is nothing to run.
+
+Erroneous code:
+
+    int answer := 42
+
+Corrected code:
+
+    int answer := 42
+    int main := answer
+"
+        }
+        ErrorCode::TypeMismatch => {
+            "The value of a definition does not have the type it was declared with.
+
+The type written at the start of a variable, function or stage assignment
+must match the type of the expression after `:=` or `=>`. Use `floor` to turn
+a float into an int. Number literals are ints unless they have a fraction, an
+exponent or the `f` suffix, so write `2.0` or `2f` where a float is needed.
+The value that `with` copies has to be a record.
+
+Erroneous code:
+
+    int half := / 3.0 2.0
+
+Corrected code:
+
+    int half := floor / 3.0 2.0
+"
+        }
+        ErrorCode::ArgumentType => {
+            "A function or operator was given an argument of the wrong type.
+
+Arguments are matched to the function's declared argument types in order.
+Arithmetic and comparisons such as `+` and `<` take ints and floats, `&&`,
+`||`, `!&&` and `!` take bools, `%` takes ints, `concat` takes strings and
+`floor` takes a float.
+
+Erroneous code:
+
+    int func double := n : int => * n 2
+    int main := double \"4\"
+
+Corrected code:
+
+    int func double := n : int => * n 2
+    int main := double 4
+"
+        }
+        ErrorCode::CondNotBool => {
+            "The first argument of `cond` must be a bool.
+
+Use a comparison such as `==` or `<` to turn a number into a bool.
+
+Erroneous code:
+
+    int main := cond 1 10 20
+
+Corrected code:
+
+    int main := cond (== 1 1) 10 20
+"
+        }
+        ErrorCode::CondBranchTypes => {
+            "The two branches of a `cond` produce values of different types.
+
+Whichever branch is taken, the `cond` must have a single type, so the
+\"then\" and \"otherwise\" expressions, or the expressions of every clause,
+have to agree.
+
+Erroneous code:
+
+    int main := cond (> 2 1) 1 \"one\"
+
+Corrected code:
+
+    int main := cond (> 2 1) 1 0
+"
+        }
+        ErrorCode::DivisionByZero => {
+            "An int was divided by zero, with either `/` or `%`, while the program ran.
+
+Guard the division with a `cond` when the divisor can be zero.
+
+Erroneous code:
+
+    int func per := total : int n : int => / total n
+    int main := per 10 0
+
+Corrected code:
+
+    int func per := total : int n : int => cond (== n 0) 0 (/ total n)
+    int main := per 10 0
+"
+        }
+        ErrorCode::IntegerOverflow => {
+            "An int calculation produced a value that does not fit in 64 bits.
+
+Use floats for very large values.
+
+Erroneous code:
+
+    int main := * 9223372036854775807 2
+
+Corrected code:
+
+    float main := * 9223372036854775807.0 2.0
+"
+        }
+        ErrorCode::ThreadPanicked => {
+            "A stage assignment marked with `| kick` crashed on its own thread.
+
+This is a bug in the interpreter rather than in your program; the value was
+computed on a separate thread which stopped unexpectedly. Removing `| kick`
+runs the assignment on the calling thread instead, which may give a more
+useful error.
+
+Example:
+
+    int func f := n : int
+    | int a := g n | kick
+    => a
+
+Without the thread:
+
+    int func f := n : int
+    | int a := g n
+    => a
+"
+        }
+        ErrorCode::UnknownSymbol => {
+            "A name is used that is not defined anywhere the expression can see.
+
+Only top-level variables and functions, the arguments of the enclosing
+function and the stage variables assigned before the current stage are in
+scope. Check the spelling; the error suggests similar names when it finds
+some.
+
+Erroneous code:
+
+    int func fib := a : int => cond (< a 2) 1 (+ fib (- a 1) fibb (- a 2))
+
+Corrected code:
+
+    int func fib := a : int => cond (< a 2) 1 (+ fib (- a 1) fib (- a 2))
+"
+        }
+        ErrorCode::CyclicDefinition => {
+            "The value of a top-level variable depends, directly or through other variables,
+on itself.
+
+Top-level definitions may appear in any order, but a variable's value must
+not need itself to be computed, including through the functions it calls.
+Functions may be recursive because each call gets new arguments; variables
+cannot.
+
+Erroneous code:
+
+    int width := + height 1
+    int height := - width 1
+
+Corrected code:
+
+    int width := 4
+    int height := - width 1
+"
+        }
+        ErrorCode::IncludeCycle => {
+            "A file includes itself, either directly or through other included files.
+
+Every file is only read once, so including the same file from several places
+is fine, but a file cannot be included while it is still being read. Move
+the definitions both files need into a third file that includes neither.
+
+Erroneous code:
+
+    # shapes.bo
+    include colors.bo
+
+    # colors.bo
+    include shapes.bo
+
+Corrected code:
+
+    # shapes.bo
+    include common.bo
+
+    # colors.bo
+    include common.bo
+"
+        }
+        ErrorCode::PrivateSymbol => {
+            "A definition of an imported module is used, but the module does not export it.
+
+Only definitions marked `pub` can be used outside the file that defines them;
+everything else is a private helper of the module.
+
+Erroneous code:
+
+    # shapes.bo
+    float func area := r : float => * 3.14 * r r
+
+    # main.bo
+    import shapes
+    float main := shapes.area 2.0
+
+Corrected code:
+
+    # shapes.bo
+    pub float func area := r : float => * 3.14 * r r
+
+    # main.bo
+    import shapes
+    float main := shapes.area 2.0
+"
+        }
+        ErrorCode::UnterminatedString => {
+            "A string literal is missing its closing `\"`.
+
+Strings end at the next `\"` on the same line; write `\\\"` for a quote inside
+a string. Strings that span several lines start and end with `\"\"\"`.
+
+Erroneous code:
+
+    str greeting := \"hello
+
+Corrected code:
+
+    str greeting := \"hello\"
+"
+        }
+        ErrorCode::UnexpectedCharacter => {
+            "The source contains a character, or a run of operator characters, that is not
+part of the language.
+
+Names are made of letters, digits, `_` and `.`; everything else has to be one
+of the operators, `(`, `)`, `\"` or `#` for a comment. A lone `=` is a common
+mistake: definitions use `:=` and comparisons use `==`.
+
+Erroneous code:
+
+    int x = 1
+
+Corrected code:
+
+    int x := 1
+"
+        }
+        ErrorCode::InvalidEscape => {
+            "A backslash in a string is followed by something that is not a known escape.
+
+The escapes are `\\n` (line break), `\\t` (tab), `\\r`, `\\0`, `\\\"`, `\\\\` and
+`\\u{...}` with the hex code of a unicode character. Raw strings, written
+`r\"...\"`, keep every backslash as it is.
+
+Erroneous code:
+
+    str path := \"C:\\data\"
+
+Corrected code:
+
+    str path := r\"C:\\data\"
+"
+        }
+        ErrorCode::UnterminatedComment => {
+            "A `#[` block comment is never closed with `]#`.
+
+Block comments may span several lines and may contain other block comments,
+so every `#[` inside a comment needs its own `]#` as well.
+
+Erroneous code:
+
+    #[ the #[ inner ]# comment
+    int main := 1
+
+Corrected code:
+
+    #[ the #[ inner ]# comment ]#
+    int main := 1
+"
+        }
+        ErrorCode::MatchArmTypes => {
+            "The arms of a `match` produce values of different types.
+
+Whichever arm is taken, the `match` must have a single type, so every arm's
+expression has to agree with the first one.
+
+Erroneous code:
+
+    str func describe := n : int => match n [0 -> \"zero\"] [_ -> n]
+
+Corrected code:
+
+    str func describe := n : int => match n [0 -> \"zero\"] [_ -> \"other\"]
+"
+        }
+        ErrorCode::NonExhaustiveMatch => {
+            "A `match` has no arm for some of the values it could be given.
+
+Every value has to match an arm without a guard: `true` and `false` for a
+bool, every constructor of a data type (and every value of their fields), and
+a `_` or a name for anything else. Arms with an `if` guard do not count, since
+the guard may be false. The error shows a value that no arm matches.
+
+Erroneous code:
+
+    str func sign := n : int => match n [0 -> \"zero\"] [m if > m 0 -> \"positive\"]
+
+Corrected code:
+
+    str func sign := n : int => match n [0 -> \"zero\"] [m if > m 0 -> \"positive\"] [_ -> \"negative\"]
+"
+        }
+        ErrorCode::MissingElse => {
+            "A `cond` with clauses has no `else` clause.
+
+If none of the tests is true the `cond` would have no value, so the clauses
+have to end with an `[else expression]` clause.
+
+Erroneous code:
+
+    str func sign := n : int => cond [(< n 0) \"negative\"] [(> n 0) \"positive\"]
+
+Corrected code:
+
+    str func sign := n : int => cond [(< n 0) \"negative\"] [(> n 0) \"positive\"] [else \"zero\"]
+"
+        }
+        ErrorCode::UnknownField => {
+            "A field is used that the record does not have, or a value that is not a
+record is used like one.
+
+`p.x` reads the field `x` of the record `p`, and `with p [x value]` makes a copy
+of `p` with a new `x`, so `p` has to be a record whose type declares `x`.
+
+Erroneous code:
+
+    type point := x : float y : float
+    float func norm := p : point => + (* p.x p.x) (* p.z p.z)
+
+Corrected code:
+
+    type point := x : float y : float
+    float func norm := p : point => + (* p.x p.x) (* p.y p.y)
+"
+        }
+        ErrorCode::StageOrder => {
+            "A stage assignment uses a variable of its own stage that is not computed yet.
+
+The kicked assignments of a stage all start on their own threads before the
+other assignments of the stage run, and they are only waited for at the next
+`=>`. So a kicked assignment cannot use anything assigned in its own stage, and
+the other assignments cannot use what their stage kicks. Move the assignment
+to a later stage, or the variable it uses to an earlier one.
+
+Erroneous code:
+
+    int func scaled := n : int
+      | int k := * n 10
+      | int a := + k 1 | kick
+    => a
+
+Corrected code:
+
+    int func scaled := n : int
+      | int k := * n 10
+    =>
+      | int a := + k 1 | kick
+    => a
+"
+        }
+        ErrorCode::DuplicateType => {
+            "Two type definitions use the same name.
+
+Record and sum types share one namespace across a file and everything it
+includes, so each type name can only be defined once. Rename one of the types,
+or merge their definitions.
+
+Erroneous code:
+
+    type point := x : int y : int
+    type point := Origin | At int int
+
+Corrected code:
+
+    type point := x : int y : int
+    type place := Origin | At int int
+"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Diagnostic;
+    use crate::testing::compile_files;
+
+    /// The files of the example under `heading`, split at their `# name.bo` lines, with
+    /// `main.bo` first as that is the one that gets compiled.
+    fn example(code: ErrorCode, heading: &str) -> Option<Vec<(String, String)>> {
+        let text = explanation(code).split(heading).nth(1)?;
+        let text = text.split("Corrected code:").next().unwrap();
+        let mut files: Vec<(String, String)> = Vec::new();
+        for line in text.lines().filter_map(|line| line.strip_prefix("    ")) {
+            match line.strip_prefix("# ").filter(|name| name.ends_with(".bo")) {
+                Some(name) => files.push((name.to_string(), String::new())),
+                None if files.is_empty() => {
+                    files.push(("main.bo".to_string(), line.to_string() + "\n"))
+                }
+                None => files.last_mut().unwrap().1 += &(line.to_string() + "\n"),
+            }
+        }
+        if let Some(main) = files.iter().position(|(name, _)| name == "main.bo") {
+            let main = files.remove(main);
+            files.insert(0, main);
+        }
+        Some(files)
+    }
+
+    #[test]
+    fn codes_are_numbered_in_order() {
+        for (i, code) in ErrorCode::ALL.iter().enumerate() {
+            assert_eq!(code.as_str(), format!("B{:04}", i + 1));
+            assert_eq!(ErrorCode::from_code(code.as_str()), Some(*code));
+        }
+        assert_eq!(
+            ErrorCode::from_code("b0012"),
+            Some(ErrorCode::CondBranchTypes)
+        );
+        assert_eq!(ErrorCode::from_code("B9999"), None);
+    }
+
+    #[test]
+    fn erroneous_examples_give_their_error() {
+        for &code in ErrorCode::ALL {
+            let Some(files) = example(code, "Erroneous code:") else {
+                assert_eq!(code, ErrorCode::ThreadPanicked);
+                continue;
+            };
+            let files: Vec<(&str, &str)> = files
+                .iter()
+                .map(|(name, contents)| (name.as_str(), contents.as_str()))
+                .collect();
+            let codes: Vec<ErrorCode> = match compile_files(&files).0 {
+                Ok(global_state) => global_state
+                    .eval_main()
+                    .err()
+                    .map(|e| e.code)
+                    .into_iter()
+                    .collect(),
+                Err(errors) => errors.iter().map(|e| Diagnostic::from(e).code).collect(),
+            };
+            assert!(codes.contains(&code), "{}: {:?} {:?}", code, codes, files);
+        }
+    }
+}
@@ -1,244 +1,1665 @@
-use crate::functions::Function;
-use crate::globalstate::GlobalState;
+use crate::ast_generator::{consume_type, expect_token, starts_definition, unexpected};
+use crate::errors::{CompileError, ErrorCode, ProgramError, SyntaxError, TypeError};
+use crate::functions::Closure;
+use crate::globalstate::{GlobalState, Globals};
 use crate::parser::Literal;
 use crate::parser::Symbol;
 use crate::parser::Token;
-use crate::primitives::{exec_prim_op, get_prim_op_type};
+use crate::parser::TokenKind;
+use crate::patterns::{self, Pattern};
+use crate::primitives::{exec_prim_op, get_prim_op_type, Data, Record};
+use crate::scanner::DataType;
 use crate::scanner::Delimeter;
+use crate::scanner::Keyword;
 use crate::scanner::Operator;
 use crate::scanner::PreToken;
+use crate::scanner::RecordType;
 use crate::scanner::Type;
+use crate::scanner::TypeTable;
+use crate::span::Span;
 use std::cell::RefCell;
 use std::collections::HashMap as Map;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// A lazily computed local, shared by every copy of the scope it was bound in.
+#[derive(Debug, Clone)]
+pub struct Thunk {
+    eval: Arc<Evaluation>,
+    captured: Vec<(Symbol, Evaluation)>,
+    value: Arc<Mutex<Option<Literal>>>,
+}
+
+/// `[pattern if guard -> body]` in a `match`, where the guard is optional.
+#[derive(Debug, Clone)]
+pub struct Arm {
+    pattern: Pattern,
+    guard: Option<Evaluation>,
+    body: Evaluation,
+}
 
 #[derive(Debug)]
 pub enum Evaluation {
-    Literal(Literal),
+    Literal(Literal, Span),
     PrimOp {
         op: Operator,
         arg1: Box<Evaluation>,
         arg2: Box<Option<Evaluation>>,
+        t: Type,
+        span: Span,
     },
-    Variable(Symbol, Type),
+    Variable(Symbol, Type, Span),
     Conditional {
         cond: Box<Evaluation>,
         then: Box<Evaluation>,
         otherwise: Box<Evaluation>,
+        span: Span,
     },
     FuncCall {
         name: Symbol,
         args: Vec<Evaluation>,
         return_type: Type,
+        span: Span,
+    },
+    /// A top-level function given fewer arguments than it takes, such as `(add 1)`, which is
+    /// a function of the rest.
+    Partial {
+        name: Symbol,
+        args: Vec<Evaluation>,
+        t: Type,
+        span: Span,
+    },
+    /// An anonymous function, `\x : int => body`.
+    Lambda {
+        params: Vec<(Symbol, Type)>,
+        body: Arc<Evaluation>,
+        t: Type,
+        span: Span,
+    },
+    /// The first arm whose pattern matches the value, and whose guard holds.
+    Match {
+        scrutinee: Box<Evaluation>,
+        arms: Vec<Arm>,
+        span: Span,
+    },
+    /// `let` bindings, or a `where` clause, in scope for `body`.
+    Let {
+        bindings: Vec<(Symbol, Arc<Evaluation>)>,
+        body: Box<Evaluation>,
+        span: Span,
+    },
+    /// A `let` or `where` binding while its scope runs, computed the first time it is used.
+    Thunk(Thunk),
+    /// A call of a function value, such as an argument of a function type. With fewer
+    /// arguments than the function takes, it is a function of the rest.
+    Apply {
+        func: Box<Evaluation>,
+        args: Vec<Evaluation>,
+        return_type: Type,
+        span: Span,
+    },
+    /// A new record, `point 1.0 2.0`, given its fields in the order they were declared.
+    Construct {
+        record: RecordType,
+        args: Vec<Evaluation>,
+        span: Span,
+    },
+    /// The field at `index` of a record, `p.x`.
+    Field {
+        record: Box<Evaluation>,
+        index: usize,
+        t: Type,
+        span: Span,
+    },
+    /// A copy of a record with some of its fields replaced, `with p [x 1.0]`.
+    Update {
+        record: Box<Evaluation>,
+        fields: Vec<(usize, Evaluation)>,
+        span: Span,
+    },
+    /// A value of a sum type built by the constructor of its variant at `index`.
+    Variant {
+        data: DataType,
+        index: usize,
+        args: Vec<Evaluation>,
+        span: Span,
     },
 }
 impl Clone for Evaluation {
     fn clone(&self) -> Self {
         match self {
-            Evaluation::Literal(literal) => Evaluation::Literal(literal.clone()),
-            Evaluation::PrimOp { op, arg1, arg2 } => Evaluation::PrimOp {
+            Evaluation::Literal(literal, span) => Evaluation::Literal(literal.clone(), *span),
+            Evaluation::PrimOp {
+                op,
+                arg1,
+                arg2,
+                t,
+                span,
+            } => Evaluation::PrimOp {
                 op: *op,
                 arg1: Box::new(*arg1.clone()),
                 arg2: Box::new(*arg2.clone()),
+                t: *t,
+                span: *span,
             },
-            Evaluation::Variable(symbol, t) => Evaluation::Variable(symbol.clone(), *t),
+            Evaluation::Variable(symbol, t, span) => {
+                Evaluation::Variable(symbol.clone(), *t, *span)
+            }
             Evaluation::Conditional {
                 cond,
                 then,
                 otherwise,
+                span,
             } => Evaluation::Conditional {
                 cond: Box::new(*cond.clone()),
                 then: Box::new(*then.clone()),
                 otherwise: Box::new(*otherwise.clone()),
+                span: *span,
             },
             Evaluation::FuncCall {
                 name,
                 args,
                 return_type,
+                span,
             } => Evaluation::FuncCall {
                 name: name.clone(),
                 args: args.clone(),
                 return_type: *return_type,
+                span: *span,
+            },
+            Evaluation::Partial {
+                name,
+                args,
+                t,
+                span,
+            } => Evaluation::Partial {
+                name: name.clone(),
+                args: args.clone(),
+                t: *t,
+                span: *span,
+            },
+            Evaluation::Lambda {
+                params,
+                body,
+                t,
+                span,
+            } => Evaluation::Lambda {
+                params: params.clone(),
+                body: body.clone(),
+                t: *t,
+                span: *span,
+            },
+            Evaluation::Let {
+                bindings,
+                body,
+                span,
+            } => Evaluation::Let {
+                bindings: bindings.clone(),
+                body: Box::new(*body.clone()),
+                span: *span,
+            },
+            Evaluation::Thunk(thunk) => Evaluation::Thunk(thunk.clone()),
+            Evaluation::Match {
+                scrutinee,
+                arms,
+                span,
+            } => Evaluation::Match {
+                scrutinee: Box::new(*scrutinee.clone()),
+                arms: arms.clone(),
+                span: *span,
+            },
+            Evaluation::Apply {
+                func,
+                args,
+                return_type,
+                span,
+            } => Evaluation::Apply {
+                func: Box::new(*func.clone()),
+                args: args.clone(),
+                return_type: *return_type,
+                span: *span,
+            },
+            Evaluation::Construct { record, args, span } => Evaluation::Construct {
+                record: *record,
+                args: args.clone(),
+                span: *span,
+            },
+            Evaluation::Field {
+                record,
+                index,
+                t,
+                span,
+            } => Evaluation::Field {
+                record: Box::new(*record.clone()),
+                index: *index,
+                t: *t,
+                span: *span,
+            },
+            Evaluation::Update {
+                record,
+                fields,
+                span,
+            } => Evaluation::Update {
+                record: Box::new(*record.clone()),
+                fields: fields.clone(),
+                span: *span,
+            },
+            Evaluation::Variant {
+                data,
+                index,
+                args,
+                span,
+            } => Evaluation::Variant {
+                data: *data,
+                index: *index,
+                args: args.clone(),
+                span: *span,
             },
         }
     }
 }
+/// Skips the line breaks before the rest of an unfinished expression, unless the next line
+/// starts something else: a definition, a stage or a `=>`.
+fn skip_continued_lines(tokens: &mut Vec<Token>) {
+    if tokens.last().map(|t| &t.kind) != Some(&TokenKind::Lang(PreToken::EOL)) {
+        return;
+    }
+    let next = tokens
+        .iter()
+        .rev()
+        .find(|t| t.kind != TokenKind::Lang(PreToken::EOL));
+    let continues = match next {
+        Some(token) => {
+            !starts_definition(Some(token))
+                && !matches!(
+                    token.kind,
+                    TokenKind::Lang(PreToken::KW(Keyword::Bar | Keyword::Kerchow))
+                        | TokenKind::Doc(_)
+                )
+        }
+        None => false,
+    };
+    if continues {
+        while tokens.last().map(|t| &t.kind) == Some(&TokenKind::Lang(PreToken::EOL)) {
+            tokens.pop();
+        }
+    }
+}
+
 impl Evaluation {
-    pub fn from_tokens(tokens: &mut Vec<Token>, global_state: &mut GlobalState) -> Self {
-        match tokens.pop() {
-            Some(Token::Lit(literal)) => Evaluation::Literal(literal),
-            Some(Token::Lang(PreToken::OP(Operator::Not))) => {
-                let arg = Evaluation::from_tokens(tokens, global_state);
-                Evaluation::PrimOp {
+    /// Reads an expression that may end in a `where` clause: the body of a definition, a stage
+    /// assignment or a parenthesized expression. The bindings are read first, so that they are
+    /// in scope for the expression before them.
+    pub fn from_tokens_with_where(
+        tokens: &mut Vec<Token>,
+        global_state: &mut GlobalState,
+    ) -> Result<Self, CompileError> {
+        let at = match find_where(tokens) {
+            Some(at) => at,
+            None => {
+                let eval = Evaluation::from_tokens(tokens, global_state)?;
+                return apply_rest(tokens, global_state, eval);
+            }
+        };
+        let mut body_tokens = tokens.split_off(at + 1);
+        let where_token = tokens.pop().unwrap();
+        let mut bindings = Vec::new();
+        let mut shadowed = Vec::new();
+        let body =
+            consume_bindings(tokens, global_state, &mut bindings, &mut shadowed).and_then(|()| {
+                let body = Evaluation::from_tokens(&mut body_tokens, global_state)?;
+                let body = apply_rest(&mut body_tokens, global_state, body)?;
+                match body_tokens
+                    .iter()
+                    .rev()
+                    .find(|t| t.kind != TokenKind::Lang(PreToken::EOL))
+                {
+                    Some(extra) => Err(unexpected(Some(extra.clone()), "`where`")),
+                    None => Ok(body),
+                }
+            });
+        restore_bindings(global_state, &bindings, shadowed);
+        let body = body?;
+        let end = bindings
+            .last()
+            .map_or(where_token.span, |(_, eval)| eval.span());
+        Ok(Evaluation::Let {
+            span: body.span().to(end),
+            bindings,
+            body: Box::new(body),
+        })
+    }
+    pub fn from_tokens(
+        tokens: &mut Vec<Token>,
+        global_state: &mut GlobalState,
+    ) -> Result<Self, CompileError> {
+        skip_continued_lines(tokens);
+        let token = match tokens.pop() {
+            Some(token) => token,
+            None => {
+                return Err(SyntaxError::UnexpectedEof {
+                    expected: "an expression".to_string(),
+                }
+                .into())
+            }
+        };
+        let start = token.span;
+        match token.kind {
+            TokenKind::Lit(literal) => Ok(Evaluation::Literal(literal, start)),
+            TokenKind::Lang(PreToken::OP(Operator::Not)) => {
+                let arg = consume_operand(tokens, global_state, Operator::Not)?;
+                let t = get_prim_op_type(global_state.types(), Operator::Not, &arg, None)?;
+                Ok(Evaluation::PrimOp {
                     op: Operator::Not,
+                    t,
+                    span: start.to(arg.span()),
                     arg1: Box::new(arg),
                     arg2: Box::new(None),
-                }
+                })
             }
-            Some(Token::Lang(PreToken::OP(Operator::Floor))) => {
-                let arg = Evaluation::from_tokens(tokens, global_state);
-                Evaluation::PrimOp {
+            TokenKind::Lang(PreToken::OP(Operator::Floor)) => {
+                let arg = consume_operand(tokens, global_state, Operator::Floor)?;
+                let t = get_prim_op_type(global_state.types(), Operator::Floor, &arg, None)?;
+                Ok(Evaluation::PrimOp {
                     op: Operator::Floor,
+                    t,
+                    span: start.to(arg.span()),
                     arg1: Box::new(arg),
                     arg2: Box::new(None),
-                }
+                })
             }
-            Some(Token::Lang(PreToken::OP(Operator::Cond))) => {
-                let cond = Evaluation::from_tokens(tokens, global_state);
-                let then = Evaluation::from_tokens(tokens, global_state);
-                let otherwise = Evaluation::from_tokens(tokens, global_state);
-                if cond.get_type() != Type::Bool || then.get_type() != otherwise.get_type() {
-                    panic!()
+            TokenKind::Lang(PreToken::OP(Operator::Cond)) => {
+                skip_continued_lines(tokens);
+                if tokens.last().map(|t| &t.kind)
+                    == Some(&TokenKind::Lang(PreToken::DEL(Delimeter::LBracket)))
+                {
+                    return consume_cond_clauses(tokens, global_state, start);
                 }
-                Evaluation::Conditional {
+                let cond = Evaluation::from_tokens(tokens, global_state)?;
+                let then = Evaluation::from_tokens(tokens, global_state)?;
+                let otherwise = Evaluation::from_tokens(tokens, global_state)?;
+                check_cond_test(global_state.types(), &cond)?;
+                check_cond_branch(global_state.types(), &then, &otherwise, "this branch")?;
+                Ok(Evaluation::Conditional {
+                    span: start.to(otherwise.span()),
                     cond: Box::new(cond),
                     then: Box::new(then),
                     otherwise: Box::new(otherwise),
-                }
+                })
             }
-            Some(Token::Lang(PreToken::OP(op))) => {
-                let arg1 = Evaluation::from_tokens(tokens, global_state);
-                let arg2 = Evaluation::from_tokens(tokens, global_state);
-                Evaluation::PrimOp {
+            TokenKind::Lang(PreToken::OP(op)) => {
+                let arg1 = consume_operand(tokens, global_state, op)?;
+                let arg2 = consume_operand(tokens, global_state, op)?;
+                let t = get_prim_op_type(global_state.types(), op, &arg1, Some(&arg2))?;
+                Ok(Evaluation::PrimOp {
                     op,
+                    t,
+                    span: start.to(arg2.span()),
                     arg1: Box::new(arg1),
                     arg2: Box::new(Some(arg2)),
+                })
+            }
+            TokenKind::Lang(PreToken::DEL(Delimeter::LPar)) => {
+                let eval = Evaluation::from_tokens_with_where(tokens, global_state)?;
+                skip_continued_lines(tokens);
+                match tokens.pop() {
+                    Some(next) if next.kind == TokenKind::Lang(PreToken::DEL(Delimeter::RPar)) => {
+                        let eval = eval.with_span(start.to(next.span));
+                        // `(expression).field`
+                        match tokens.last() {
+                            Some(Token {
+                                kind: TokenKind::Symb(path),
+                                span,
+                            }) if path.name().starts_with('.') && span.col == next.span.end_col => {
+                                let (path, path_span) = (path.name(), *span);
+                                tokens.pop();
+                                let field =
+                                    fields_of(global_state.types(), eval, &path, path_span)?;
+                                consume_application(tokens, global_state, &Symbol::new(path), field)
+                            }
+                            _ => Ok(eval),
+                        }
+                    }
+                    Some(next) => Err(SyntaxError::UnbalancedParen {
+                        open: start,
+                        found: next.kind.to_string(),
+                        span: next.span,
+                    }
+                    .into()),
+                    None => Err(SyntaxError::UnexpectedEof {
+                        expected: "`)`".to_string(),
+                    }
+                    .into()),
                 }
             }
-            Some(Token::Lang(PreToken::DEL(Delimeter::LPar))) => {
-                let eval = Evaluation::from_tokens(tokens, global_state);
-                let next = tokens.pop().unwrap();
-                if next != Token::Lang(PreToken::DEL(Delimeter::RPar)) {
-                    panic!("{:?}", next);
+            TokenKind::Symb(symbol) => {
+                if let Some(field) = field_access(global_state, &symbol, start)? {
+                    return consume_application(tokens, global_state, &symbol, field);
+                }
+                let symbol = global_state.resolve(&symbol, start)?;
+                let t: Type = match global_state.get_type(&symbol) {
+                    Some(t) => t,
+                    None => return Err(global_state.unknown_symbol(&symbol, start).into()),
                 };
-                eval
-            }
-            Some(Token::Symb(symbol)) => {
-                let t: Type = global_state.get_type(&symbol);
-                if global_state.is_function(&symbol) {
+                if global_state.is_function(&symbol) && !global_state.is_local(&symbol) {
                     let needed_types = global_state.get_args(&symbol);
-                    let mut args: Vec<Evaluation> = Vec::new();
-                    for needed_type in needed_types {
-                        let eval = Evaluation::from_tokens(tokens, global_state);
-                        if eval.get_type() != needed_type {
-                            panic!()
-                        }
-                        args.push(eval);
+                    let (args, span) = consume_arguments(
+                        tokens,
+                        global_state,
+                        &symbol,
+                        needed_types.clone(),
+                        start,
+                    )?;
+                    if args.len() < needed_types.len() {
+                        return Ok(Evaluation::Partial {
+                            t: global_state
+                                .types_mut()
+                                .function(needed_types[args.len()..].to_vec(), t),
+                            name: symbol,
+                            args,
+                            span,
+                        });
                     }
-                    Evaluation::FuncCall {
+                    Ok(Evaluation::FuncCall {
                         name: symbol,
                         args,
                         return_type: t,
-                    }
+                        span,
+                    })
                 } else {
-                    Evaluation::Variable(symbol, t)
+                    let variable = Evaluation::Variable(symbol.clone(), t, start);
+                    consume_application(tokens, global_state, &symbol, variable)
                 }
             }
-            Some(Token::Lang(PreToken::EOL)) => {
-                panic!("unexpected end of line, {:?}", tokens)
+            TokenKind::Lang(PreToken::TYPE(Type::Record(record))) => {
+                let fields = record.fields(global_state.types()).to_vec();
+                let name = Symbol::new(record.name(global_state.types()).to_string());
+                let (args, span) = consume_arguments(
+                    tokens,
+                    global_state,
+                    &name,
+                    fields.iter().map(|field| field.t).collect(),
+                    start,
+                )?;
+                if let Some(missing) = fields.get(args.len()) {
+                    let expected = format!("a value for the field `{}`", missing.name);
+                    return Err(unexpected(tokens.pop(), &expected));
+                }
+                Ok(Evaluation::Construct { record, args, span })
             }
-            None => todo!(),
-            _ => panic!("{:?}", tokens),
+            TokenKind::Lang(PreToken::KW(Keyword::With)) => {
+                let base = Evaluation::from_tokens(tokens, global_state)?;
+                let record = match base.get_type() {
+                    Type::Record(record) => record,
+                    t => {
+                        return Err(ProgramError {
+                            code: ErrorCode::TypeMismatch,
+                            message: format!(
+                                "`with` updates a record, but this is {}",
+                                t.display(global_state.types())
+                            ),
+                            span: Some(base.span()),
+                            related: Vec::new(),
+                        }
+                        .into())
+                    }
+                };
+                let mut fields = Vec::new();
+                let mut span = start.to(base.span());
+                loop {
+                    skip_continued_lines(tokens);
+                    if tokens.last().map(|t| &t.kind)
+                        != Some(&TokenKind::Lang(PreToken::DEL(Delimeter::LBracket)))
+                    {
+                        break;
+                    }
+                    tokens.pop();
+                    let (name, name_span) = match tokens.pop() {
+                        Some(Token {
+                            kind: TokenKind::Symb(name),
+                            span,
+                        }) => (name, span),
+                        token => return Err(unexpected(token, "a field name")),
+                    };
+                    let types = global_state.types();
+                    let (index, field) = record
+                        .field(types, &name.name())
+                        .map(|(index, field)| (index, field.clone()))
+                        .ok_or_else(|| no_field(types, record, &name.name(), name_span))?;
+                    let value = Evaluation::from_tokens(tokens, global_state)?;
+                    if value.get_type() != field.t {
+                        let types = global_state.types();
+                        return Err(TypeError {
+                            code: ErrorCode::TypeMismatch,
+                            message: "type mismatch".to_string(),
+                            expected: field.t.display(types).to_string(),
+                            found: value.get_type().display(types).to_string(),
+                            span: value.span(),
+                            related: vec![(
+                                field.span,
+                                format!(
+                                    "`{}` was declared as {} here",
+                                    field.name,
+                                    field.t.display(types)
+                                ),
+                            )],
+                        }
+                        .into());
+                    }
+                    let close = expect_token(tokens, PreToken::DEL(Delimeter::RBracket))?;
+                    span = span.to(close.span);
+                    fields.push((index, value));
+                }
+                if fields.is_empty() {
+                    return Err(unexpected(
+                        tokens.pop(),
+                        "a field to update, `[name value]`",
+                    ));
+                }
+                Ok(Evaluation::Update {
+                    record: Box::new(base),
+                    fields,
+                    span,
+                })
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Match)) => {
+                let scrutinee = Evaluation::from_tokens(tokens, global_state)?;
+                let t = scrutinee.get_type();
+                let mut arms: Vec<Arm> = Vec::new();
+                let mut span = start.to(scrutinee.span());
+                loop {
+                    skip_continued_lines(tokens);
+                    if tokens.last().map(|t| &t.kind)
+                        != Some(&TokenKind::Lang(PreToken::DEL(Delimeter::LBracket)))
+                    {
+                        break;
+                    }
+                    tokens.pop();
+                    let pattern = Pattern::from_tokens(tokens, global_state.types(), t)?;
+                    let bound = pattern.bindings(global_state.types(), t);
+                    let shadowed: Vec<Option<Type>> = bound
+                        .iter()
+                        .map(|(name, t)| global_state.override_variables(name.clone(), *t))
+                        .collect();
+                    let arm = consume_arm(tokens, global_state);
+                    for ((name, _), previous) in bound.iter().zip(shadowed).rev() {
+                        global_state.restore_override(name.clone(), previous);
+                    }
+                    let (guard, body) = arm?;
+                    if let Some(first) = arms.first() {
+                        if body.get_type() != first.body.get_type() {
+                            let types = global_state.types();
+                            let first_type = first.body.get_type().display(types);
+                            return Err(TypeError {
+                                code: ErrorCode::MatchArmTypes,
+                                message: "match arms have different types".to_string(),
+                                expected: first_type.to_string(),
+                                found: body.get_type().display(types).to_string(),
+                                span: body.span(),
+                                related: vec![(
+                                    first.body.span(),
+                                    format!("the first arm has type {}", first_type),
+                                )],
+                            }
+                            .into());
+                        }
+                    }
+                    let close = expect_token(tokens, PreToken::DEL(Delimeter::RBracket))?;
+                    span = span.to(close.span);
+                    arms.push(Arm {
+                        pattern,
+                        guard,
+                        body,
+                    });
+                }
+                if arms.is_empty() {
+                    return Err(unexpected(tokens.pop(), "an arm `[pattern -> expression]`"));
+                }
+                let unguarded: Vec<&Pattern> = arms
+                    .iter()
+                    .filter(|arm| arm.guard.is_none())
+                    .map(|arm| &arm.pattern)
+                    .collect();
+                if let Some(missing) = patterns::missing(&unguarded, global_state.types(), t) {
+                    return Err(ProgramError {
+                        code: ErrorCode::NonExhaustiveMatch,
+                        message: format!("`match` does not cover every value: {}", missing),
+                        span: Some(start.to(scrutinee.span())),
+                        related: Vec::new(),
+                    }
+                    .into());
+                }
+                Ok(Evaluation::Match {
+                    scrutinee: Box::new(scrutinee),
+                    arms,
+                    span,
+                })
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Let)) => {
+                let mut bindings = Vec::new();
+                let mut shadowed = Vec::new();
+                let body = consume_bindings(tokens, global_state, &mut bindings, &mut shadowed)
+                    .and_then(|()| {
+                        skip_continued_lines(tokens);
+                        expect_token(tokens, PreToken::KW(Keyword::In))?;
+                        Evaluation::from_tokens(tokens, global_state)
+                    });
+                restore_bindings(global_state, &bindings, shadowed);
+                let body = body?;
+                Ok(Evaluation::Let {
+                    span: start.to(body.span()),
+                    bindings,
+                    body: Box::new(body),
+                })
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Lambda)) => {
+                let mut params: Vec<(Symbol, Type)> = Vec::new();
+                loop {
+                    match tokens.pop() {
+                        Some(Token {
+                            kind: TokenKind::Symb(name),
+                            ..
+                        }) => {
+                            expect_token(tokens, PreToken::DEL(Delimeter::Colon))?;
+                            let (t, _) = consume_type(tokens, global_state.types_mut())?;
+                            params.push((name, t));
+                        }
+                        Some(Token {
+                            kind: TokenKind::Lang(PreToken::KW(Keyword::Kerchow)),
+                            ..
+                        }) if !params.is_empty() => break,
+                        token if params.is_empty() => return Err(unexpected(token, "a parameter")),
+                        token => return Err(unexpected(token, "a parameter or `=>`")),
+                    }
+                }
+                let shadowed: Vec<Option<Type>> = params
+                    .iter()
+                    .map(|(name, t)| global_state.override_variables(name.clone(), *t))
+                    .collect();
+                let body = Evaluation::from_tokens(tokens, global_state);
+                for ((name, _), previous) in params.iter().zip(shadowed).rev() {
+                    global_state.restore_override(name.clone(), previous);
+                }
+                let body = body?;
+                let t = global_state
+                    .types_mut()
+                    .function(params.iter().map(|(_, t)| *t).collect(), body.get_type());
+                Ok(Evaluation::Lambda {
+                    span: start.to(body.span()),
+                    params,
+                    body: Arc::new(body),
+                    t,
+                })
+            }
+            TokenKind::Lang(PreToken::EOL) => {
+                // leave the end of line for whoever is reading the statement
+                tokens.push(token);
+                Err(SyntaxError::UnexpectedEol {
+                    expected: "an expression".to_string(),
+                    span: start,
+                }
+                .into())
+            }
+            kind => Err(SyntaxError::UnexpectedToken {
+                expected: "an expression".to_string(),
+                found: kind.to_string(),
+                span: start,
+            }
+            .into()),
         }
     }
-    pub fn get_type(&self) -> Type {
+    pub fn span(&self) -> Span {
         match self {
-            Evaluation::Literal(ref lit) => match lit {
-                Literal::Integer(_) => Type::Int,
-                Literal::Float(_) => Type::Float,
-                Literal::String(_) => Type::Str,
-                Literal::Bool(_) => Type::Bool,
-                Literal::Void => Type::NoType,
-            },
-            Evaluation::PrimOp { op, arg1, arg2 } => get_prim_op_type(*op, arg1.get_type(), {
+            Evaluation::Literal(_, span) => *span,
+            Evaluation::PrimOp { span, .. } => *span,
+            Evaluation::Variable(_, _, span) => *span,
+            Evaluation::Conditional { span, .. } => *span,
+            Evaluation::FuncCall { span, .. } => *span,
+            Evaluation::Partial { span, .. } => *span,
+            Evaluation::Lambda { span, .. } => *span,
+            Evaluation::Apply { span, .. } => *span,
+            Evaluation::Let { span, .. } => *span,
+            Evaluation::Thunk(thunk) => thunk.eval.span(),
+            Evaluation::Match { span, .. } => *span,
+            Evaluation::Construct { span, .. } => *span,
+            Evaluation::Field { span, .. } => *span,
+            Evaluation::Update { span, .. } => *span,
+            Evaluation::Variant { span, .. } => *span,
+        }
+    }
+    /// Every variable referenced by this expression, in the order they appear.
+    pub fn variables_used(&self, out: &mut Vec<(Symbol, Span)>) {
+        self.names_used(false, out);
+    }
+    /// Every variable and top-level function referenced by this expression, in the order they
+    /// appear.
+    pub fn references(&self, out: &mut Vec<(Symbol, Span)>) {
+        self.names_used(true, out);
+    }
+    fn names_used(&self, calls: bool, out: &mut Vec<(Symbol, Span)>) {
+        match self {
+            Evaluation::Literal(..) => {}
+            Evaluation::PrimOp { arg1, arg2, .. } => {
+                arg1.names_used(calls, out);
                 if let Some(arg2) = arg2.as_ref() {
-                    arg2.get_type()
-                } else {
-                    Type::NoType
+                    arg2.names_used(calls, out);
                 }
-            }),
+            }
+            Evaluation::Variable(symbol, _, span) => out.push((symbol.clone(), *span)),
+            Evaluation::Conditional {
+                cond,
+                then,
+                otherwise,
+                ..
+            } => {
+                cond.names_used(calls, out);
+                then.names_used(calls, out);
+                otherwise.names_used(calls, out);
+            }
+            Evaluation::FuncCall {
+                name, args, span, ..
+            }
+            | Evaluation::Partial {
+                name, args, span, ..
+            } => {
+                if calls {
+                    out.push((name.clone(), *span));
+                }
+                for arg in args {
+                    arg.names_used(calls, out);
+                }
+            }
+            Evaluation::Lambda { params, body, .. } => {
+                let mut used = Vec::new();
+                body.names_used(calls, &mut used);
+                out.extend(
+                    used.into_iter()
+                        .filter(|(symbol, _)| params.iter().all(|(param, _)| param != symbol)),
+                );
+            }
+            Evaluation::Apply { func, args, .. } => {
+                func.names_used(calls, out);
+                for arg in args {
+                    arg.names_used(calls, out);
+                }
+            }
+            Evaluation::Let { bindings, body, .. } => {
+                let mut bound: Vec<&Symbol> = Vec::new();
+                let mut used = Vec::new();
+                for (name, eval) in bindings {
+                    eval.names_used(calls, &mut used);
+                    out.extend(
+                        used.drain(..)
+                            .filter(|(symbol, _)| !bound.contains(&symbol)),
+                    );
+                    bound.push(name);
+                }
+                body.names_used(calls, &mut used);
+                out.extend(
+                    used.into_iter()
+                        .filter(|(symbol, _)| !bound.contains(&symbol)),
+                );
+            }
+            Evaluation::Thunk(_) => {}
+            Evaluation::Match {
+                scrutinee, arms, ..
+            } => {
+                scrutinee.names_used(calls, out);
+                for arm in arms {
+                    let bound = arm.pattern.names();
+                    let mut used = Vec::new();
+                    if let Some(guard) = &arm.guard {
+                        guard.names_used(calls, &mut used);
+                    }
+                    arm.body.names_used(calls, &mut used);
+                    out.extend(
+                        used.into_iter()
+                            .filter(|(symbol, _)| !bound.contains(&symbol)),
+                    );
+                }
+            }
+            Evaluation::Construct { args, .. } | Evaluation::Variant { args, .. } => {
+                for arg in args {
+                    arg.names_used(calls, out);
+                }
+            }
+            Evaluation::Field { record, .. } => record.names_used(calls, out),
+            Evaluation::Update { record, fields, .. } => {
+                record.names_used(calls, out);
+                for (_, value) in fields {
+                    value.names_used(calls, out);
+                }
+            }
+        }
+    }
+    fn with_span(mut self, new_span: Span) -> Self {
+        match &mut self {
+            Evaluation::Literal(_, span) => *span = new_span,
+            Evaluation::PrimOp { span, .. } => *span = new_span,
+            Evaluation::Variable(_, _, span) => *span = new_span,
+            Evaluation::Conditional { span, .. } => *span = new_span,
+            Evaluation::FuncCall { span, .. } => *span = new_span,
+            Evaluation::Partial { span, .. } => *span = new_span,
+            Evaluation::Lambda { span, .. } => *span = new_span,
+            Evaluation::Apply { span, .. } => *span = new_span,
+            Evaluation::Let { span, .. } => *span = new_span,
+            Evaluation::Thunk(_) => {}
+            Evaluation::Match { span, .. } => *span = new_span,
+            Evaluation::Construct { span, .. } => *span = new_span,
+            Evaluation::Field { span, .. } => *span = new_span,
+            Evaluation::Update { span, .. } => *span = new_span,
+            Evaluation::Variant { span, .. } => *span = new_span,
+        }
+        self
+    }
+    pub fn get_type(&self) -> Type {
+        match self {
+            Evaluation::Literal(lit, _) => lit.get_type(),
+            Evaluation::PrimOp { t, .. } => *t,
             Evaluation::FuncCall { return_type: t, .. } => *t,
-            Evaluation::Variable(_, t) => *t,
+            Evaluation::Variable(_, t, _) => *t,
             Evaluation::Conditional { then, .. } => then.get_type(),
+            Evaluation::Partial { t, .. } => *t,
+            Evaluation::Lambda { t, .. } => *t,
+            Evaluation::Apply { return_type, .. } => *return_type,
+            Evaluation::Let { body, .. } => body.get_type(),
+            Evaluation::Thunk(thunk) => thunk.eval.get_type(),
+            Evaluation::Match { arms, .. } => arms[0].body.get_type(),
+            Evaluation::Construct { record, .. } => Type::Record(*record),
+            Evaluation::Field { t, .. } => *t,
+            Evaluation::Update { record, .. } => record.get_type(),
+            Evaluation::Variant { data, .. } => Type::Data(*data),
+        }
+    }
+    /// Whether two captured values certainly are the same: equal values, or lazy bindings of
+    /// the same expression over the same captured values, whether or not they have been computed.
+    pub fn same_capture(&self, other: &Evaluation) -> bool {
+        match (self, other) {
+            (Evaluation::Literal(a, _), Evaluation::Literal(b, _)) => a == b,
+            (Evaluation::Thunk(a), Evaluation::Thunk(b)) => {
+                Arc::ptr_eq(&a.eval, &b.eval)
+                    && a.captured.len() == b.captured.len()
+                    && a.captured
+                        .iter()
+                        .zip(&b.captured)
+                        .all(|((x, a), (y, b))| x == y && a.same_capture(b))
+            }
+            _ => false,
         }
     }
     pub fn evaluate(
         &self,
         variables: &mut Rc<RefCell<Map<Symbol, Evaluation>>>,
-        functions: &mut Rc<RefCell<Map<Symbol, Function>>>,
-    ) -> Literal {
+        globals: &Arc<Globals>,
+    ) -> Result<Literal, ProgramError> {
         match self {
-            Evaluation::Literal(literal) => literal.clone(),
-            Evaluation::Variable(symbol, _) => {
-                let out = variables
-                    .borrow()
-                    .get(symbol)
-                    .unwrap()
-                    .evaluate(&mut variables.clone(), &mut functions.clone());
-                out
+            Evaluation::Literal(literal, _) => Ok(literal.clone()),
+            Evaluation::Variable(symbol, _, _) => {
+                let local = variables.borrow().get(symbol).cloned();
+                match local {
+                    Some(local) => local.evaluate(variables, globals),
+                    // a top-level variable only sees other top-level definitions
+                    None => globals.variables[symbol]
+                        .evaluate(&mut Rc::new(RefCell::new(Map::new())), globals),
+                }
             }
             Evaluation::Conditional {
                 cond,
                 then,
                 otherwise,
+                ..
             } => {
-                let cond = cond.evaluate(variables, functions);
+                let cond = cond.evaluate(variables, globals)?;
                 if let Literal::Bool(b) = cond {
                     if b.get() {
-                        then.evaluate(variables, functions)
+                        then.evaluate(variables, globals)
                     } else {
-                        otherwise.evaluate(variables, functions)
+                        otherwise.evaluate(variables, globals)
                     }
                 } else {
                     panic!()
                 }
             }
             Evaluation::FuncCall { name, args, .. } => {
-                let func = (*functions.borrow().get(name).unwrap()).clone();
-                match func {
-                    Function::Simple {
-                        args: ref needed_args,
-                        ..
-                    } => {
-                        let mut give_vars: Rc<RefCell<Map<Symbol, Evaluation>>> =
-                            Rc::new(RefCell::new(Map::new()));
-                        for (sym, eval) in variables.borrow().iter() {
-                            give_vars.borrow_mut().insert(sym.clone(), eval.clone());
-                        }
-                        for (sym, to_eval) in needed_args.iter().zip(args.iter()) {
-                            let eval = to_eval.evaluate(&mut give_vars, functions);
-                            give_vars
-                                .borrow_mut()
-                                .insert(sym.0.clone(), Evaluation::Literal(eval));
-                        }
-                        func.evaluate(&mut give_vars, functions)
-                    }
-                    Function::Breakout {
-                        args: ref needed_args,
-                        ..
-                    } => {
-                        let mut give_vars: Rc<RefCell<Map<Symbol, Evaluation>>> =
-                            Rc::new(RefCell::new(Map::new()));
-                        for (sym, eval) in variables.borrow().iter() {
-                            give_vars.borrow_mut().insert(sym.clone(), eval.clone());
+                let args = evaluate_arguments(args, variables, globals)?;
+                globals.functions[name].call(args, globals)
+            }
+            Evaluation::Partial { name, args, t, .. } => {
+                let args = evaluate_arguments(args, variables, globals)?;
+                Ok(Literal::Function(Closure::named(name.clone(), args, *t)))
+            }
+            Evaluation::Lambda {
+                params, body, t, ..
+            } => {
+                let captured = captured(body, &variables.borrow(), params);
+                Ok(Literal::Function(Closure::lambda(
+                    params.clone(),
+                    body.clone(),
+                    captured,
+                    *t,
+                )))
+            }
+            Evaluation::Let { bindings, body, .. } => {
+                let mut scope = variables.borrow().clone();
+                for (name, eval) in bindings {
+                    let thunk = Thunk {
+                        eval: eval.clone(),
+                        captured: captured(eval, &scope, &[]),
+                        value: Arc::new(Mutex::new(None)),
+                    };
+                    scope.insert(name.clone(), Evaluation::Thunk(thunk));
+                }
+                body.evaluate(&mut Rc::new(RefCell::new(scope)), globals)
+            }
+            Evaluation::Thunk(thunk) => {
+                // holding the lock while computing makes sure the value is computed only once
+                let mut value = thunk.value.lock().unwrap();
+                if let Some(value) = value.as_ref() {
+                    return Ok(value.clone());
+                }
+                let scope = thunk.captured.iter().cloned().collect();
+                let result = thunk
+                    .eval
+                    .evaluate(&mut Rc::new(RefCell::new(scope)), globals)?;
+                *value = Some(result.clone());
+                Ok(result)
+            }
+            Evaluation::Match {
+                scrutinee,
+                arms,
+                span,
+            } => {
+                let value = scrutinee.evaluate(variables, globals)?;
+                for arm in arms {
+                    let mut bound = Vec::new();
+                    if !arm.pattern.matches(&value, &mut bound) {
+                        continue;
+                    }
+                    let mut scope = variables.clone();
+                    if !bound.is_empty() {
+                        let mut with_bound = variables.borrow().clone();
+                        for (name, value) in bound {
+                            with_bound.insert(name, Evaluation::Literal(value, arm.pattern.span()));
                         }
-                        for (sym, to_eval) in needed_args.iter().zip(args.iter()) {
-                            let eval = to_eval.evaluate(&mut give_vars, functions);
-                            give_vars
-                                .borrow_mut()
-                                .insert(sym.0.clone(), Evaluation::Literal(eval));
+                        scope = Rc::new(RefCell::new(with_bound));
+                    }
+                    if let Some(guard) = &arm.guard {
+                        match guard.evaluate(&mut scope, globals)? {
+                            Literal::Bool(b) if b.get() => {}
+                            _ => continue,
                         }
-                        func.evaluate(&mut give_vars, functions)
                     }
+                    return arm.body.evaluate(&mut scope, globals);
+                }
+                Err(ProgramError {
+                    code: ErrorCode::NonExhaustiveMatch,
+                    message: format!(
+                        "no arm of the `match` matched `{}`",
+                        value.display(&globals.types)
+                    ),
+                    span: Some(*span),
+                    related: Vec::new(),
+                })
+            }
+            Evaluation::Apply {
+                func,
+                args,
+                return_type,
+                ..
+            } => {
+                let closure = match func.evaluate(variables, globals)? {
+                    Literal::Function(closure) => closure,
+                    _ => panic!(),
+                };
+                let args = evaluate_arguments(args, variables, globals)?;
+                closure.call(args, *return_type, globals)
+            }
+            Evaluation::Construct { record, args, .. } => {
+                let values = evaluate_arguments(args, variables, globals)?;
+                let values = values.into_iter().map(|(value, _)| value).collect();
+                Ok(Literal::Record(Record::new(*record, values)))
+            }
+            Evaluation::Field { record, index, .. } => {
+                match record.evaluate(variables, globals)? {
+                    Literal::Record(record) => Ok(record.get(*index)),
+                    _ => panic!(),
+                }
+            }
+            Evaluation::Update { record, fields, .. } => {
+                let mut record = match record.evaluate(variables, globals)? {
+                    Literal::Record(record) => record,
+                    _ => panic!(),
+                };
+                for (index, value) in fields {
+                    record = record.with(*index, value.evaluate(variables, globals)?);
+                }
+                Ok(Literal::Record(record))
+            }
+            Evaluation::Variant {
+                data, index, args, ..
+            } => {
+                let values = evaluate_arguments(args, variables, globals)?;
+                let values = values.into_iter().map(|(value, _)| value).collect();
+                Ok(Literal::Data(Data::new(*data, *index, values)))
+            }
+            Evaluation::PrimOp {
+                op,
+                arg1,
+                arg2,
+                span,
+                ..
+            } => exec_prim_op(*op, arg1, arg2.clone(), *span, variables.clone(), globals),
+        }
+    }
+}
+
+fn evaluate_arguments(
+    args: &[Evaluation],
+    variables: &mut Rc<RefCell<Map<Symbol, Evaluation>>>,
+    globals: &Arc<Globals>,
+) -> Result<Vec<(Literal, Span)>, ProgramError> {
+    args.iter()
+        .map(|arg| Ok((arg.evaluate(variables, globals)?, arg.span())))
+        .collect()
+}
+
+/// Reads the arguments of a call of `name`, checking them against `needed_types`. A `)` ends
+/// the arguments early, leaving a partial application.
+fn consume_arguments(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    name: &Symbol,
+    needed_types: Vec<Type>,
+    start: Span,
+) -> Result<(Vec<Evaluation>, Span), CompileError> {
+    let mut args: Vec<Evaluation> = Vec::new();
+    let mut span = start;
+    for needed_type in needed_types {
+        skip_continued_lines(tokens);
+        if tokens.last().map(|t| &t.kind) == Some(&TokenKind::Lang(PreToken::DEL(Delimeter::RPar)))
+        {
+            break;
+        }
+        let mut eval = Evaluation::from_tokens(tokens, global_state)?;
+        if eval.get_type() != needed_type {
+            eval = apply_rest(tokens, global_state, eval)?;
+        }
+        if eval.get_type() != needed_type {
+            let mut message = format!("wrong argument type for `{}`", name);
+            if let Evaluation::FuncCall { name: called, .. } = &eval {
+                let args = global_state.get_args(called);
+                let ret = global_state.get_type(called).unwrap();
+                let called_type = global_state.types_mut().function(args, ret);
+                if called_type == needed_type {
+                    message += &format!(", write `({})` to pass the function itself", called);
+                }
+            }
+            return Err(TypeError {
+                code: ErrorCode::ArgumentType,
+                message,
+                expected: needed_type.display(global_state.types()).to_string(),
+                found: eval.get_type().display(global_state.types()).to_string(),
+                span: eval.span(),
+                related: global_state
+                    .get_span(name)
+                    .filter(|_| !global_state.is_local(name))
+                    .map(|s| (s, format!("`{}` is declared here", name)))
+                    .into_iter()
+                    .collect(),
+            }
+            .into());
+        }
+        span = span.to(eval.span());
+        args.push(eval);
+    }
+    Ok((args, span))
+}
+
+/// Reads the arguments given to `func`, named `name` in errors, if it is a function value.
+/// Without any, `func` is the function itself.
+fn consume_application(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    name: &Symbol,
+    func: Evaluation,
+) -> Result<Evaluation, CompileError> {
+    let Type::Func(func_type) = func.get_type() else {
+        return Ok(func);
+    };
+    let needed_types = func_type.args(global_state.types()).to_vec();
+    let ret = func_type.ret(global_state.types());
+    let (args, span) = consume_arguments(
+        tokens,
+        global_state,
+        name,
+        needed_types.clone(),
+        func.span(),
+    )?;
+    if args.is_empty() {
+        return Ok(func);
+    }
+    let return_type = if args.len() < needed_types.len() {
+        global_state
+            .types_mut()
+            .function(needed_types[args.len()..].to_vec(), ret)
+    } else {
+        ret
+    };
+    Ok(Evaluation::Apply {
+        func: Box::new(func),
+        args,
+        return_type,
+        span,
+    })
+}
+
+/// Applies `func` to the arguments after it when it is a function value that more of the
+/// expression follows, as in `(g 2) 3`.
+fn apply_rest(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    func: Evaluation,
+) -> Result<Evaluation, CompileError> {
+    if !matches!(func.get_type(), Type::Func(_)) || !starts_argument(tokens.last()) {
+        return Ok(func);
+    }
+    let name = applied_name(&func);
+    consume_application(tokens, global_state, &name, func)
+}
+
+/// Reads an operand of `op`. Only `==` and `!=` take functions, so for the others a function
+/// value in parentheses is applied to the arguments after it.
+fn consume_operand(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    op: Operator,
+) -> Result<Evaluation, CompileError> {
+    let eval = Evaluation::from_tokens(tokens, global_state)?;
+    match op {
+        Operator::Eq | Operator::Neq => Ok(eval),
+        _ => apply_rest(tokens, global_state, eval),
+    }
+}
+
+/// Whether `token` starts another argument, rather than ending the expression before it.
+fn starts_argument(token: Option<&Token>) -> bool {
+    matches!(
+        token.map(|t| &t.kind),
+        Some(
+            TokenKind::Lit(_)
+                | TokenKind::Symb(_)
+                | TokenKind::Lang(
+                    PreToken::OP(_)
+                        | PreToken::DEL(Delimeter::LPar)
+                        | PreToken::TYPE(Type::Record(_))
+                        | PreToken::KW(
+                            Keyword::Let | Keyword::Lambda | Keyword::Match | Keyword::With
+                        )
+                )
+        )
+    )
+}
+
+/// The name of the function that `func` evaluates to, for errors about its arguments.
+fn applied_name(func: &Evaluation) -> Symbol {
+    match func {
+        Evaluation::Variable(name, ..)
+        | Evaluation::FuncCall { name, .. }
+        | Evaluation::Partial { name, .. } => name.clone(),
+        Evaluation::Apply { func, .. } => applied_name(func),
+        _ => Symbol::new("function".to_string()),
+    }
+}
+
+/// Reads `name.field` when `symbol` is a variable followed by the names of fields, each a
+/// field of the one before, rather than a name from another module.
+fn field_access(
+    global_state: &GlobalState,
+    symbol: &Symbol,
+    start: Span,
+) -> Result<Option<Evaluation>, CompileError> {
+    if global_state.resolve(symbol, start).is_ok() {
+        return Ok(None);
+    }
+    let name = symbol.name();
+    for (dot, _) in name.match_indices('.') {
+        let base = match global_state.resolve(&Symbol::new(name[..dot].to_string()), start) {
+            Ok(base) if !global_state.is_function(&base) || global_state.is_local(&base) => base,
+            _ => continue,
+        };
+        let Some(t) = global_state.get_type(&base) else {
+            continue;
+        };
+        let base_span = Span {
+            end_col: start.col + name[..dot].chars().count(),
+            ..start
+        };
+        let variable = Evaluation::Variable(base, t, base_span);
+        let path_span = Span {
+            col: base_span.end_col,
+            ..start
+        };
+        return fields_of(global_state.types(), variable, &name[dot..], path_span).map(Some);
+    }
+    Ok(None)
+}
+
+/// Reads the fields `path` names, written `.field.field` at `path_span`, each a field of the
+/// one before.
+fn fields_of(
+    types: &TypeTable,
+    mut eval: Evaluation,
+    path: &str,
+    path_span: Span,
+) -> Result<Evaluation, CompileError> {
+    let mut col = path_span.col + 1;
+    for field_name in path[1..].split('.') {
+        let field_span = Span {
+            col,
+            end_col: col + field_name.chars().count(),
+            ..path_span
+        };
+        col = field_span.end_col + 1;
+        let record = match eval.get_type() {
+            Type::Record(record) => record,
+            t => {
+                let t = t.display(types);
+                return Err(ProgramError {
+                    code: ErrorCode::UnknownField,
+                    message: format!("{} is not a record, so it has no field `{}`", t, field_name),
+                    span: Some(field_span),
+                    related: vec![(eval.span(), format!("this is {}", t))],
                 }
+                .into());
             }
-            Evaluation::PrimOp { op, arg1, arg2 } => exec_prim_op(
-                *op,
-                arg1.clone(),
-                arg2.clone(),
-                variables.clone(),
-                functions.clone(),
-            ),
+        };
+        let (index, field) = record
+            .field(types, field_name)
+            .ok_or_else(|| no_field(types, record, field_name, field_span))?;
+        eval = Evaluation::Field {
+            span: eval.span().to(field_span),
+            record: Box::new(eval),
+            index,
+            t: field.t,
+        };
+    }
+    Ok(eval)
+}
+
+fn no_field(types: &TypeTable, record: RecordType, name: &str, span: Span) -> CompileError {
+    let fields: Vec<&str> = record
+        .fields(types)
+        .iter()
+        .map(|f| f.name.as_str())
+        .collect();
+    let message = if fields.is_empty() {
+        format!(
+            "`{}` has no field `{}`, as it has no fields",
+            record.name(types),
+            name
+        )
+    } else {
+        format!(
+            "`{}` has no field `{}`; its fields are {}",
+            record.name(types),
+            name,
+            fields.join(", ")
+        )
+    };
+    ProgramError {
+        code: ErrorCode::UnknownField,
+        message,
+        span: Some(span),
+        related: Vec::new(),
+    }
+    .into()
+}
+
+/// The values in `scope` of the variables `eval` uses, apart from `params`.
+fn captured(
+    eval: &Evaluation,
+    scope: &Map<Symbol, Evaluation>,
+    params: &[(Symbol, Type)],
+) -> Vec<(Symbol, Evaluation)> {
+    let mut used = Vec::new();
+    eval.variables_used(&mut used);
+    let mut captured: Vec<(Symbol, Evaluation)> = Vec::new();
+    for (symbol, _) in used {
+        if captured.iter().any(|(s, _)| *s == symbol) || params.iter().any(|(p, _)| *p == symbol) {
+            continue;
         }
+        if let Some(value) = scope.get(&symbol) {
+            captured.push((symbol, value.clone()));
+        }
+    }
+    captured
+}
+
+/// Where the `where` of the expression at the top of `tokens` is, if it has one: the first one
+/// outside parentheses and brackets before the expression ends.
+fn find_where(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    let mut lambdas = 0;
+    for (i, token) in tokens.iter().enumerate().rev() {
+        match &token.kind {
+            TokenKind::Lang(PreToken::DEL(Delimeter::LPar | Delimeter::LBracket)) => depth += 1,
+            TokenKind::Lang(PreToken::DEL(Delimeter::RPar | Delimeter::RBracket)) if depth == 0 => {
+                return None
+            }
+            TokenKind::Lang(PreToken::DEL(Delimeter::RPar | Delimeter::RBracket)) => depth -= 1,
+            TokenKind::Lang(PreToken::KW(Keyword::Lambda)) => lambdas += 1,
+            TokenKind::Lang(PreToken::KW(Keyword::Kerchow)) if lambdas > 0 => lambdas -= 1,
+            TokenKind::Lang(PreToken::KW(Keyword::Kerchow | Keyword::Bar)) | TokenKind::Doc(_) => {
+                return None
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Where)) if depth == 0 => return Some(i),
+            _ if starts_definition(Some(token)) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reads `type name := expression` bindings for as long as they follow, where the type may be
+/// left out. Each binding is in scope for the ones after it; the types their names had before
+/// go in `shadowed`.
+fn consume_bindings(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    bindings: &mut Vec<(Symbol, Arc<Evaluation>)>,
+    shadowed: &mut Vec<Option<Type>>,
+) -> Result<(), CompileError> {
+    loop {
+        skip_continued_lines(tokens);
+        let declared = match tokens.last().map(|t| &t.kind) {
+            Some(TokenKind::Lang(PreToken::TYPE(_) | PreToken::DEL(Delimeter::LPar))) => {
+                Some(consume_type(tokens, global_state.types_mut())?)
+            }
+            Some(TokenKind::Symb(_)) => None,
+            _ if bindings.is_empty() => return Err(unexpected(tokens.pop(), "a binding")),
+            _ => return Ok(()),
+        };
+        let (name, name_span) = match tokens.pop() {
+            Some(Token {
+                kind: TokenKind::Symb(name),
+                span,
+            }) => (name, span),
+            token => return Err(unexpected(token, "a name")),
+        };
+        expect_token(tokens, PreToken::KW(Keyword::Define))?;
+        let eval = Evaluation::from_tokens(tokens, global_state)?;
+        if let Some((t, type_span)) = declared {
+            if eval.get_type() != t {
+                let types = global_state.types();
+                return Err(TypeError {
+                    code: ErrorCode::TypeMismatch,
+                    message: "type mismatch".to_string(),
+                    expected: t.display(types).to_string(),
+                    found: eval.get_type().display(types).to_string(),
+                    span: eval.span(),
+                    related: vec![(
+                        type_span.to(name_span),
+                        format!("`{}` was declared as {} here", name, t.display(types)),
+                    )],
+                }
+                .into());
+            }
+        }
+        shadowed.push(global_state.override_variables(name.clone(), eval.get_type()));
+        bindings.push((name, Arc::new(eval)));
+    }
+}
+
+fn restore_bindings(
+    global_state: &mut GlobalState,
+    bindings: &[(Symbol, Arc<Evaluation>)],
+    shadowed: Vec<Option<Type>>,
+) {
+    for ((name, _), previous) in bindings.iter().zip(shadowed).rev() {
+        global_state.restore_override(name.clone(), previous);
+    }
+}
+
+fn check_cond_test(types: &TypeTable, cond: &Evaluation) -> Result<(), CompileError> {
+    if cond.get_type() != Type::Bool {
+        return Err(TypeError {
+            code: ErrorCode::CondNotBool,
+            message: "cond condition must be a bool".to_string(),
+            expected: Type::Bool.display(types).to_string(),
+            found: cond.get_type().display(types).to_string(),
+            span: cond.span(),
+            related: Vec::new(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that `branch` has the type of `first`, which the error calls `first_name`.
+fn check_cond_branch(
+    types: &TypeTable,
+    first: &Evaluation,
+    branch: &Evaluation,
+    first_name: &str,
+) -> Result<(), CompileError> {
+    if first.get_type() != branch.get_type() {
+        let first_type = first.get_type().display(types);
+        return Err(TypeError {
+            code: ErrorCode::CondBranchTypes,
+            message: "cond branches have different types".to_string(),
+            expected: first_type.to_string(),
+            found: branch.get_type().display(types).to_string(),
+            span: branch.span(),
+            related: vec![(
+                first.span(),
+                format!("{} has type {}", first_name, first_type),
+            )],
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads the clauses of a `cond [test expression] ... [else expression]` that started at
+/// `start`, turning them into nested conditionals.
+fn consume_cond_clauses(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+    start: Span,
+) -> Result<Evaluation, CompileError> {
+    let mut clauses: Vec<(Span, Evaluation, Evaluation)> = Vec::new();
+    let mut end = start;
+    loop {
+        skip_continued_lines(tokens);
+        if tokens.last().map(|t| &t.kind)
+            != Some(&TokenKind::Lang(PreToken::DEL(Delimeter::LBracket)))
+        {
+            return Err(ProgramError {
+                code: ErrorCode::MissingElse,
+                message: "`cond` has no `else` clause".to_string(),
+                span: Some(start.to(end)),
+                related: Vec::new(),
+            }
+            .into());
+        }
+        let open = tokens.pop().unwrap().span;
+        if tokens.last().map(|t| &t.kind) == Some(&TokenKind::Lang(PreToken::KW(Keyword::Else))) {
+            tokens.pop();
+            let otherwise = Evaluation::from_tokens(tokens, global_state)?;
+            if let Some((_, _, first)) = clauses.first() {
+                check_cond_branch(global_state.types(), first, &otherwise, "the first clause")?;
+            }
+            let close = expect_token(tokens, PreToken::DEL(Delimeter::RBracket))?;
+            let mut eval = otherwise;
+            for (open, cond, then) in clauses.into_iter().rev() {
+                eval = Evaluation::Conditional {
+                    span: open.to(close.span),
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: Box::new(eval),
+                };
+            }
+            return Ok(eval.with_span(start.to(close.span)));
+        }
+        let cond = Evaluation::from_tokens(tokens, global_state)?;
+        check_cond_test(global_state.types(), &cond)?;
+        let then = Evaluation::from_tokens(tokens, global_state)?;
+        if let Some((_, _, first)) = clauses.first() {
+            check_cond_branch(global_state.types(), first, &then, "the first clause")?;
+        }
+        end = expect_token(tokens, PreToken::DEL(Delimeter::RBracket))?.span;
+        clauses.push((open, cond, then));
+    }
+}
+
+/// Reads the rest of a `match` arm after its pattern: an optional `if` guard, `->` and the
+/// expression it gives.
+fn consume_arm(
+    tokens: &mut Vec<Token>,
+    global_state: &mut GlobalState,
+) -> Result<(Option<Evaluation>, Evaluation), CompileError> {
+    let mut guard = None;
+    if tokens.last().map(|t| &t.kind) == Some(&TokenKind::Lang(PreToken::KW(Keyword::If))) {
+        tokens.pop();
+        let eval = Evaluation::from_tokens(tokens, global_state)?;
+        if eval.get_type() != Type::Bool {
+            return Err(TypeError {
+                code: ErrorCode::TypeMismatch,
+                message: "a match guard must be a bool".to_string(),
+                expected: Type::Bool.display(global_state.types()).to_string(),
+                found: eval.get_type().display(global_state.types()).to_string(),
+                span: eval.span(),
+                related: Vec::new(),
+            }
+            .into());
+        }
+        guard = Some(eval);
+    }
+    expect_token(tokens, PreToken::KW(Keyword::Arrow))?;
+    let body = Evaluation::from_tokens(tokens, global_state)?;
+    Ok((guard, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ErrorCode;
+    use crate::parser::Literal;
+    use crate::primitives::Int;
+    use crate::testing::{compile, errors, run};
+
+    fn int(n: i64) -> Literal {
+        Literal::Integer(Int::new(n))
+    }
+
+    #[test]
+    fn let_and_where_bind_names() {
+        let program = "\
+int func area := w : int h : int => let int inner := * (- w 2) (- h 2) in - (* w h) inner
+int func hyp := a : int b : int => + aa bb
+  where
+    aa := * a a
+    bb := * b b
+int main := + (area 4 5) (hyp 3 4)
+";
+        assert_eq!(run(program).unwrap(), int(39));
+    }
+
+    #[test]
+    fn bindings_can_use_the_ones_before_them() {
+        let program = "\
+int func f := n : int => + c 0
+  where
+    a := + n 1
+    int b := * a 2
+    c := + a b
+int main := let x := f 1 y := * x 10 in y
+";
+        assert_eq!(run(program).unwrap(), int(60));
+    }
+
+    #[test]
+    fn where_works_in_stages_and_parentheses() {
+        let program = "\
+int func f := n : int
+  | int k := * m m where m := + n 1
+=> + k (+ j 1 where j := 5)
+int main := f 2
+";
+        assert_eq!(run(program).unwrap(), int(15));
+    }
+
+    #[test]
+    fn unused_bindings_are_never_computed() {
+        let program = "int main := let int boom := / 1 0 in 7\n";
+        assert_eq!(run(program).unwrap(), int(7));
+        let program = "int main := let int boom := / 1 0 in + boom 7\n";
+        assert_eq!(run(program).unwrap_err().code, ErrorCode::DivisionByZero);
+    }
+
+    #[test]
+    fn bindings_are_checked_against_their_types() {
+        let found = errors("int main := let int x := \"one\" in x\n");
+        assert_eq!(found[0].code, ErrorCode::TypeMismatch);
+        let found = errors("int main := let x := \"one\" in + x 1\n");
+        assert_eq!(found[0].code, ErrorCode::ArgumentType);
+    }
+
+    /// Runs `program` and prints its result the way `breakout` does.
+    fn output(program: &str) -> String {
+        let global_state = compile(program).unwrap();
+        let types = global_state.types().clone();
+        global_state
+            .eval_main()
+            .unwrap()
+            .display(&types)
+            .to_string()
+    }
+
+    const POINT: &str = "\
+type point := x : int y : int
+point func shift := p : point dx : int => with p [x + p.x dx]
+";
+
+    #[test]
+    fn records_are_built_read_and_updated() {
+        let program = format!(
+            "{}int main := + (shift (point 1 2) 3).x (point 1 2).y\n",
+            POINT
+        );
+        assert_eq!(run(&program).unwrap(), int(6));
+        let program = format!("{}point main := shift (point 1 2) 3\n", POINT);
+        assert_eq!(output(&program), "point { x: 4, y: 2 }");
+    }
+
+    #[test]
+    fn records_are_compared_field_by_field() {
+        let program = format!(
+            "{}bool main := && (== (shift (point 1 2) 1) (point 2 2)) (!= (point 1 2) (point 2 1))\n",
+            POINT
+        );
+        assert_eq!(output(&program), "true");
+    }
+
+    #[test]
+    fn records_can_be_stage_variables() {
+        let program = format!(
+            "{}int func f := n : int\n  | point p := point n n\n=>\n  | point q := shift p 1 | kick\n=> + q.x q.y\n\
+             int main := f 2\n",
+            POINT
+        );
+        assert_eq!(run(&program).unwrap(), int(5));
+    }
+
+    #[test]
+    fn misused_records_are_reported() {
+        let found = errors(&format!("{}int main := (point 1 2).z\n", POINT));
+        assert_eq!(found[0].code, ErrorCode::UnknownField);
+        let found = errors(&format!("{}point main := with (point 1 2) [z 3]\n", POINT));
+        assert_eq!(found[0].code, ErrorCode::UnknownField);
+        let found = errors(&format!("{}point main := point 1 true\n", POINT));
+        assert_eq!(found[0].code, ErrorCode::ArgumentType);
+        let found = errors(&format!("{}int main := point 1 2\n", POINT));
+        assert_eq!(found[0].code, ErrorCode::TypeMismatch);
+        assert_eq!(found[0].labels[0].message, "expected int, found point");
     }
 }
@@ -0,0 +1,665 @@
+use crate::cst::{self, TriviaKind};
+use crate::errors::ErrorCode;
+use crate::parser::{ParsingError, TokenKind};
+use crate::scanner::{Keyword, Operator, PreToken, TypeTable};
+use crate::span::Span;
+
+/// Expressions are wrapped onto further lines once a line grows longer than this.
+const MAX_WIDTH: usize = 100;
+
+enum Kind {
+    Code(TokenKind),
+    Comment,
+    Directive,
+}
+
+/// A token, comment or `include`/`import` line, with its text as written.
+struct Word {
+    kind: Kind,
+    text: String,
+    span: Span,
+    /// Whether the word is the `=>` of a lambda, which belongs to an expression.
+    in_lambda: bool,
+}
+impl Word {
+    fn is(&self, token: PreToken) -> bool {
+        matches!(&self.kind, Kind::Code(TokenKind::Lang(t)) if *t == token)
+    }
+    /// Whether the word is a `=>` of the definition itself.
+    fn is_kerchow(&self) -> bool {
+        self.is(PreToken::KW(Keyword::Kerchow)) && !self.in_lambda
+    }
+    fn is_code(&self) -> bool {
+        matches!(self.kind, Kind::Code(_))
+    }
+    /// Whether the word starts a definition. A name in the first column is taken to be the
+    /// type of one, as types imported from other files are not known here.
+    fn starts_definition(&self) -> bool {
+        self.span.col == 1
+            && matches!(
+                self.kind,
+                Kind::Code(
+                    TokenKind::Lang(PreToken::TYPE(_) | PreToken::KW(Keyword::Pub | Keyword::Type))
+                        | TokenKind::Symb(_)
+                )
+            )
+    }
+    /// Whether the word has to stay on the same line as the word before it.
+    fn sticks_to_previous(&self) -> bool {
+        self.text == ")"
+            || self.text == "]"
+            || self.is(PreToken::KW(Keyword::Bar))
+            || self.is(PreToken::KW(Keyword::Kick))
+            || self.in_lambda
+    }
+}
+
+/// Reprints the source of a file in the canonical layout, keeping its comments.
+pub fn format(contents: &str, file: usize) -> Result<String, ParsingError> {
+    let words = words(contents, file)?;
+    let mut writer = Writer::default();
+    let mut last_line = None;
+    let mut i = 0;
+    while i < words.len() {
+        let word = &words[i];
+        let end = if word.starts_definition() {
+            definition_end(&words, i)
+        } else {
+            i + 1
+        };
+        if last_line.is_some_and(|line| word.span.line > line + 1) {
+            writer.blank_line();
+        }
+        match word.kind {
+            Kind::Code(_) if word.starts_definition() => {
+                write_definition(&mut writer, &words[i..end])
+            }
+            Kind::Code(_) => {
+                return Err(ParsingError {
+                    code: ErrorCode::UnexpectedToken,
+                    span: word.span,
+                    message: format!(
+                        "expected a definition at the start of a line, found `{}`",
+                        word.text
+                    ),
+                })
+            }
+            _ => {
+                writer.start(0);
+                writer.push(&word.text, false);
+            }
+        }
+        last_line = words[i..end].iter().map(|w| w.span.end_line).max();
+        i = end;
+    }
+    writer.finish();
+    Ok(writer.out)
+}
+
+/// The tokens, comments and directives of a file, read from its syntax tree.
+fn words(contents: &str, file: usize) -> Result<Vec<Word>, ParsingError> {
+    let tree = cst::build(contents, file, &mut TypeTable::new())?;
+    let mut words = Vec::new();
+    let mut open_lambdas = 0;
+    for token in tree.tokens() {
+        for trivia in &token.leading {
+            if trivia.kind == TriviaKind::Comment {
+                words.push(Word {
+                    kind: Kind::Comment,
+                    text: trivia.text.trim_end().to_string(),
+                    span: trivia.span,
+                    in_lambda: false,
+                });
+            }
+        }
+        let (kind, text) = match &token.token.kind {
+            TokenKind::Lang(PreToken::EOL) => continue,
+            TokenKind::Doc(doc) if doc.is_empty() => (Kind::Comment, "##".to_string()),
+            TokenKind::Doc(doc) => (Kind::Comment, format!("## {}", doc)),
+            TokenKind::Directive(line) => (Kind::Directive, line.trim().to_string()),
+            kind => (Kind::Code(kind.clone()), token.text.clone()),
+        };
+        let in_lambda = match &token.token.kind {
+            TokenKind::Lang(PreToken::KW(Keyword::Lambda)) => {
+                open_lambdas += 1;
+                false
+            }
+            TokenKind::Lang(PreToken::KW(Keyword::Kerchow)) if open_lambdas > 0 => {
+                open_lambdas -= 1;
+                true
+            }
+            _ => false,
+        };
+        words.push(Word {
+            kind,
+            text,
+            span: token.token.span,
+            in_lambda,
+        });
+    }
+    Ok(words)
+}
+
+/// The end of the definition starting at `start`. Comments on their own lines after its last
+/// token belong to whatever follows.
+fn definition_end(words: &[Word], start: usize) -> usize {
+    let mut end = words[start + 1..]
+        .iter()
+        .position(|w| w.starts_definition() || matches!(w.kind, Kind::Directive))
+        .map_or(words.len(), |p| start + 1 + p);
+    while end > start + 1 && is_own_line_comment(words, end - 1) {
+        end -= 1;
+    }
+    end
+}
+
+fn is_own_line_comment(words: &[Word], i: usize) -> bool {
+    matches!(words[i].kind, Kind::Comment)
+        && (i == 0 || words[i - 1].span.end_line < words[i].span.line)
+}
+
+/// The width of `words` when written on one line.
+fn line_width(words: &[Word]) -> usize {
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let space = i > 0 && !matches!(words[i - 1].text.as_str(), "(" | "[" | "\\");
+            let space = space && !matches!(word.text.as_str(), ")" | "]");
+            usize::from(space) + word.text.lines().next().unwrap_or("").chars().count()
+        })
+        .sum()
+}
+
+/// Where the bracket closing the `(` or `[` that `words` starts with is.
+fn group_end(words: &[Word]) -> usize {
+    let mut depth = 0;
+    for (i, word) in words.iter().enumerate() {
+        match word.text.as_str() {
+            "(" | "[" => depth += 1,
+            ")" | "]" if depth == 1 => return i,
+            ")" | "]" => depth -= 1,
+            _ => {}
+        }
+    }
+    words.len() - 1
+}
+
+/// Where the arms of a `match` or the clauses of a `cond` start in `words`, the words after the
+/// keyword: the `[ ... ]` groups that follow one after another.
+fn arm_starts(words: &[Word]) -> Vec<usize> {
+    let mut depth = 0;
+    let mut next = None;
+    for (i, word) in words.iter().enumerate() {
+        match word.text.as_str() {
+            "(" => depth += 1,
+            ")" | "]" if depth == 0 => break,
+            ")" => depth -= 1,
+            "[" if depth == 0 => {
+                next = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let mut starts = Vec::new();
+    while let Some(start) = next {
+        starts.push(start);
+        let end = start + group_end(&words[start..]);
+        next = words[end + 1..]
+            .iter()
+            .position(Word::is_code)
+            .map(|p| end + 1 + p)
+            .filter(|&p| words[p].text == "[");
+    }
+    starts
+}
+
+/// Whether the word at `i` is the first code word on its line in the source.
+fn starts_source_line(words: &[Word], i: usize) -> bool {
+    words[..i]
+        .iter()
+        .rev()
+        .find(|w| w.is_code())
+        .is_none_or(|prev| prev.span.end_line < words[i].span.line)
+}
+
+/// Where each binding of a `where` clause starts in `words`, the words after the `where`. A
+/// binding runs from its optional type up to the next one.
+fn binding_starts(words: &[Word]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut depth = 0;
+    let mut lets = 0;
+    for (i, word) in words.iter().enumerate() {
+        match word.text.as_str() {
+            "(" | "[" => depth += 1,
+            ")" | "]" if depth == 0 => break,
+            ")" | "]" => depth -= 1,
+            _ if word.is(PreToken::KW(Keyword::Let)) => lets += 1,
+            _ if word.is(PreToken::KW(Keyword::In)) => lets -= 1,
+            _ if depth == 0 && lets == 0 && i > 0 && word.is(PreToken::KW(Keyword::Define)) => {
+                starts.push(type_start(words, i - 1))
+            }
+            _ => {}
+        }
+    }
+    starts
+}
+
+/// Where the type written before the name at `name` starts, if there is one.
+fn type_start(words: &[Word], name: usize) -> usize {
+    let is_type = |word: &Word| {
+        matches!(word.kind, Kind::Code(TokenKind::Lang(PreToken::TYPE(_))))
+            || word.is(PreToken::KW(Keyword::Arrow))
+    };
+    let mut start = name;
+    while start > 0 {
+        if is_type(&words[start - 1]) {
+            start -= 1;
+            continue;
+        }
+        if words[start - 1].text != ")" {
+            break;
+        }
+        // a function type in parentheses, as opposed to the end of the expression before it
+        let mut depth = 0;
+        let mut open = None;
+        for k in (0..start).rev() {
+            match words[k].text.as_str() {
+                ")" => depth += 1,
+                "(" if depth == 1 => {
+                    open = Some(k);
+                    break;
+                }
+                "(" => depth -= 1,
+                _ if is_type(&words[k]) => {}
+                _ => break,
+            }
+        }
+        match open {
+            Some(open) => start = open,
+            None => break,
+        }
+    }
+    start
+}
+
+fn count_code(words: &[Word]) -> usize {
+    words.iter().filter(|w| w.is_code()).count()
+}
+
+/// Writes a definition: variables and simple functions on one line, breakout functions with
+/// one stage assignment per line and each `=>` in the first column.
+fn write_definition(writer: &mut Writer, words: &[Word]) {
+    let define = words
+        .iter()
+        .position(|w| w.is(PreToken::KW(Keyword::Define)));
+    let is_function = words[..define.unwrap_or(words.len())]
+        .iter()
+        .any(|w| w.is(PreToken::KW(Keyword::Func)));
+    let body = define.and_then(|define| {
+        words[define..]
+            .iter()
+            .position(|w| w.is_kerchow() || w.is(PreToken::KW(Keyword::Bar)))
+    });
+    let first_stage = match (define, body) {
+        (Some(define), Some(body)) if is_function => define + body,
+        (Some(define), _) if !is_function => {
+            write_unit(writer, words, 0, 2, count_code(&words[..=define]));
+            return;
+        }
+        _ => {
+            write_unit(writer, words, 0, 2, usize::MAX);
+            return;
+        }
+    };
+    if words[first_stage].is_kerchow() {
+        write_unit(writer, words, 0, 2, count_code(&words[..=first_stage]));
+        return;
+    }
+    write_unit(writer, &words[..first_stage], 0, 2, usize::MAX);
+    let mut i = first_stage;
+    while i < words.len() {
+        let next_code = |from: usize| {
+            words[from..]
+                .iter()
+                .position(|w| w.is_code())
+                .map(|p| from + p)
+        };
+        let word = &words[i];
+        if word.is(PreToken::KW(Keyword::Bar)) {
+            // a stage assignment runs up to the next `|` or `=>`, apart from its `| kick`
+            let mut end = i + 1;
+            while end < words.len() {
+                if words[end].is_kerchow() {
+                    break;
+                }
+                if words[end].is(PreToken::KW(Keyword::Bar)) {
+                    match next_code(end + 1) {
+                        Some(kick) if words[kick].is(PreToken::KW(Keyword::Kick)) => end = kick,
+                        _ => break,
+                    }
+                }
+                end += 1;
+            }
+            write_segment(writer, &words[i..end], 2, 4);
+            i = end;
+        } else {
+            match next_code(i + 1) {
+                // a `=>` at the end of its line separates two stages
+                Some(next) if words[next].span.line > word.span.end_line => {
+                    write_segment(writer, &words[i..next], 0, 2);
+                    i = next;
+                }
+                _ => {
+                    write_unit(writer, &words[i..], 0, 2, 2);
+                    i = words.len();
+                }
+            }
+        }
+    }
+}
+
+/// Writes part of a breakout function, putting the comments on their own lines after it
+/// between the stage lines.
+fn write_segment(writer: &mut Writer, words: &[Word], indent: usize, wrap_indent: usize) {
+    let mut end = words.len();
+    while end > 1 && is_own_line_comment(words, end - 1) {
+        end -= 1;
+    }
+    write_unit(writer, &words[..end], indent, wrap_indent, 4);
+    for comment in &words[end..] {
+        writer.start(2);
+        writer.push(&comment.text, false);
+    }
+}
+
+/// Writes `words` on a new line at `indent`, wrapping long lines onto lines indented by
+/// `wrap_indent` between the arguments of the line, so that a bracketed group is never split.
+/// The first `fixed` code words and the one after them always stay on the
+/// first line. The clauses of a `cond` always go on lines of their own, the arms of a `match`
+/// when they are on lines of their own in the source or do not fit on the line, and a `where`
+/// clause goes on a line of its own
+/// at `wrap_indent`, with each of its bindings on a line of its own below it.
+fn write_unit(
+    writer: &mut Writer,
+    words: &[Word],
+    indent: usize,
+    wrap_indent: usize,
+    fixed: usize,
+) {
+    writer.start(indent);
+    // the indent of each word that has to start a line
+    let mut line_starts: Vec<Option<usize>> = vec![None; words.len()];
+    let mut wrap = wrap_indent;
+    let mut depth = 0;
+    // the depth of the brackets at the start of the current line
+    let mut line_depth = 0;
+    let mut written = 0;
+    for (i, word) in words.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|p| &words[p]);
+        if word.is_code() {
+            if depth == 0 && word.is(PreToken::KW(Keyword::Where)) {
+                line_starts[i] = Some(wrap_indent);
+                for start in binding_starts(&words[i + 1..]) {
+                    line_starts[i + 1 + start] = Some(wrap_indent + 2);
+                }
+            }
+            let outer_depth = depth;
+            match word.text.as_str() {
+                "(" | "[" => depth += 1,
+                ")" | "]" => depth -= 1,
+                _ => {}
+            }
+            let space = prev.is_none_or(|p| !matches!(p.text.as_str(), "(" | "[" | "\\"))
+                && !matches!(word.text.as_str(), ")" | "]");
+            let mut width = word.text.lines().next().unwrap_or("").chars().count();
+            // a bracketed group moves to a line of its own rather than being split
+            if matches!(word.text.as_str(), "(" | "[") {
+                width =
+                    line_width(&words[i..=i + group_end(&words[i..])]).min(MAX_WIDTH - wrap - 1);
+            }
+            if let Some(line_indent) = line_starts[i] {
+                writer.start(line_indent);
+                wrap = line_indent + 2;
+                line_depth = outer_depth;
+            } else if written > fixed
+                && outer_depth == line_depth
+                && space
+                && !word.sticks_to_previous()
+                && writer.width() + 1 + width > MAX_WIDTH
+            {
+                // only between the arguments of the line, never inside a call or clause
+                writer.start(wrap);
+            }
+            writer.push(&word.text, space);
+            written += 1;
+            let next_code = words[i + 1..].iter().find(|w| w.is_code());
+            // the clauses of a `cond` inside brackets go further in than the line around it
+            let clause_indent = writer.indent() + if depth > line_depth { 4 } else { 2 };
+            if word.is(PreToken::OP(Operator::Cond)) && next_code.is_some_and(|w| w.text == "[") {
+                for clause in arm_starts(&words[i + 1..]) {
+                    line_starts[i + 1 + clause] = Some(clause_indent);
+                }
+            } else if word.is(PreToken::KW(Keyword::Match)) {
+                let arms = arm_starts(&words[i + 1..]);
+                if let Some(&last) = arms.last() {
+                    let end = i + 1 + last + group_end(&words[i + 1 + last..]);
+                    if arms
+                        .iter()
+                        .any(|&arm| starts_source_line(words, i + 1 + arm))
+                        || writer.width() + 1 + line_width(&words[i + 1..=end]) > MAX_WIDTH
+                    {
+                        for arm in arms {
+                            line_starts[i + 1 + arm] = Some(wrap);
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        if prev.is_none_or(|p| p.span.end_line < word.span.line) {
+            writer.start(wrap);
+        }
+        writer.push(&word.text, true);
+        // a block comment may sit between tokens of a line; anything else ends the line
+        let continues_line = words
+            .get(i + 1)
+            .is_some_and(|next| next.span.line == word.span.end_line);
+        if !(word.text.starts_with("#[") && continues_line) {
+            writer.start(wrap);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    out: String,
+    line: String,
+}
+impl Writer {
+    /// Ends the current line and starts a new one at `indent`.
+    fn start(&mut self, indent: usize) {
+        self.finish();
+        self.line = " ".repeat(indent);
+    }
+    fn finish(&mut self) {
+        if !self.line.trim().is_empty() {
+            self.out += self.line.trim_end();
+            self.out.push('\n');
+        }
+        self.line.clear();
+    }
+    fn blank_line(&mut self) {
+        self.finish();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+    /// The indent of the current line.
+    fn indent(&self) -> usize {
+        self.line.chars().take_while(|c| *c == ' ').count()
+    }
+    fn width(&self) -> usize {
+        self.line.rsplit('\n').next().unwrap_or("").chars().count()
+    }
+    fn push(&mut self, text: &str, space: bool) {
+        if space && !self.line.trim().is_empty() {
+            self.line.push(' ');
+        }
+        self.line += text;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::run;
+
+    const PROGRAM: &str = "\
+# a staged function
+int func  sum_fib := n : int
+| int a := fib n | kick
+  | int b := fib (+ n 1) | kick
+=>
+=> + a b
+
+## The nth Fibonacci number.
+int func fib := a : int => cond (< a 2) 1 (+ fib (- a 1) fib (- a 2))
+int func grade := score : int => cond
+  [>= score 90 4]
+  [>= score 80 3]
+  [else 0]
+str func sign := n : int => match n [0 -> \"zero\"] [m if > m 0 -> \"positive\"] [_ -> \"negative\"]
+int func hyp := a : int b : int => + aa bb
+  where
+    aa := * a a
+    bb := * b b
+int -> int add5 := \\x : int => + x 5   #[ inline ]# # trailing
+int main := + (sum_fib 5) (+ (grade 85) (+ (hyp 3 4) (add5 (cond (== (sign 3) \"positive\") 1 0))))
+";
+
+    /// Formats `contents` twice, checking that the second pass changes nothing.
+    fn round_trip(contents: &str) -> String {
+        let once = format(contents, 0).unwrap();
+        assert_eq!(format(&once, 0).unwrap(), once);
+        once
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format(PROGRAM, 0).unwrap();
+        assert_ne!(once, PROGRAM);
+        assert_eq!(format(&once, 0).unwrap(), once);
+    }
+
+    #[test]
+    fn formatted_programs_still_compile_and_run_the_same() {
+        let formatted = format(PROGRAM, 0).unwrap();
+        assert_eq!(run(&formatted).unwrap(), run(PROGRAM).unwrap());
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let formatted = format(PROGRAM, 0).unwrap();
+        for comment in [
+            "# a staged function",
+            "## The nth Fibonacci number.",
+            "#[ inline ]#",
+        ] {
+            assert!(formatted.contains(comment), "lost `{}`", comment);
+        }
+        assert!(formatted.contains("# trailing"));
+    }
+
+    #[test]
+    fn long_lines_are_wrapped() {
+        let long = format!("int main := + 1 {}0\n", "+ 1 ".repeat(40));
+        let formatted = format(&long, 0).unwrap();
+        assert!(formatted.lines().count() > 1);
+        assert!(formatted
+            .lines()
+            .all(|line| line.chars().count() <= MAX_WIDTH));
+        assert_eq!(format(&formatted, 0).unwrap(), formatted);
+    }
+
+    #[test]
+    fn where_bindings_get_a_line_each() {
+        let flat = "int func hyp := a : int b : int => + aa bb where aa := * a a int bb := * b b\n";
+        assert_eq!(
+            format(flat, 0).unwrap(),
+            "int func hyp := a : int b : int => + aa bb\n  where\n    aa := * a a\n    int bb := * b b\n"
+        );
+    }
+
+    #[test]
+    fn match_arms_keep_their_own_lines() {
+        let arms = "str func sign := n : int => match n\n  [0 -> \"zero\"]\n  [_ -> \"other\"]\n";
+        assert_eq!(format(arms, 0).unwrap(), arms);
+        let flat = "str func sign := n : int => match n [0 -> \"zero\"] [_ -> \"other\"]\n";
+        assert_eq!(format(flat, 0).unwrap(), flat);
+        let long = format!(
+            "str func sign := n : int => match n [0 -> \"{}\"] [_ -> \"other\"]\n",
+            "zero".repeat(20)
+        );
+        let formatted = format(&long, 0).unwrap();
+        assert_eq!(formatted.lines().count(), 3);
+        assert!(formatted
+            .lines()
+            .skip(1)
+            .all(|line| line.starts_with("  [")));
+    }
+
+    #[test]
+    fn cond_clauses_get_a_line_each() {
+        let flat =
+            "int func grade := score : int => cond [>= score 90 4] [>= score 80 3] [else 0]\n";
+        assert_eq!(
+            format(flat, 0).unwrap(),
+            "int func grade := score : int => cond\n  [>= score 90 4]\n  [>= score 80 3]\n  [else 0]\n"
+        );
+        let simple = "int func fib := a : int => cond (< a 2) 1 (+ fib (- a 1) fib (- a 2))\n";
+        assert_eq!(format(simple, 0).unwrap(), simple);
+    }
+
+    #[test]
+    fn nested_cond_clauses_go_further_in() {
+        let nested = "\
+str func sign := n : int => match n [0 -> \"zero\"] [m -> cond
+    [> m 0 \"positive\"]
+    [else \"negative\"]]
+int func pick := n : int => cond
+  [> n 0 (cond
+      [> n 10 2]
+      [else 1])]
+  [else 0]
+int func twice := n : int => + 1 (\\x : int => cond
+    [> x 0 1]
+    [else 2]) n
+";
+        assert_eq!(round_trip(nested), nested);
+        let flat = "int func pick := n : int => + 1 (cond [> n 0 1] [else 2])\n";
+        assert_eq!(
+            round_trip(flat),
+            "int func pick := n : int => + 1 (cond\n    [> n 0 1]\n    [else 2])\n"
+        );
+    }
+
+    #[test]
+    fn calls_are_not_split_when_wrapping() {
+        let call = format!("(add3 {0} {0} {0})", "1".repeat(9));
+        let long = format!(
+            "int func add3 := a : int b : int c : int => + a + b c\nint main := + {0} + {0} {0}\n",
+            call
+        );
+        let formatted = round_trip(&long);
+        assert_eq!(
+            formatted,
+            format!(
+                "int func add3 := a : int b : int c : int => + a + b c\nint main := + {0} + {0}\n  {0}\n",
+                call
+            )
+        );
+        assert_eq!(run(&formatted).unwrap(), run(&long).unwrap());
+    }
+}
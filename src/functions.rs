@@ -1,10 +1,14 @@
+use crate::errors::{ErrorCode, ProgramError};
 use crate::expressions::Evaluation;
+use crate::globalstate::Globals;
 use crate::parser::Literal;
 use crate::parser::Symbol;
 use crate::scanner::Type;
+use crate::span::Span;
 use std::cell::RefCell;
 use std::collections::HashMap as Map;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::thread;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -31,52 +35,67 @@ impl Ord for RunType {
 
 #[derive(Debug, Clone)]
 pub struct FunctionStage {
-    assignments: Vec<(Symbol, Box<Evaluation>, RunType)>,
+    assignments: Vec<(Symbol, Box<Evaluation>, RunType, Span)>,
 }
 impl FunctionStage {
-    pub fn new(mut a: Vec<(Symbol, Box<Evaluation>, RunType)>) -> Self {
-        a.sort_by(|x, y| x.2.cmp(&y.2));
+    pub fn new(mut a: Vec<(Symbol, Box<Evaluation>, RunType, Span)>) -> Self {
+        a.sort_by_key(|x| x.2);
         FunctionStage {
             assignments: a.to_vec(),
         }
     }
+    /// The variables of this stage that are computed on threads of their own.
+    pub fn kicked(&self) -> Vec<&Symbol> {
+        self.assignments
+            .iter()
+            .filter(|(_, _, run_type, _)| *run_type == RunType::Thread)
+            .map(|(name, ..)| name)
+            .collect()
+    }
     pub fn evaluate(
         &self,
         vars: &mut Rc<RefCell<Map<Symbol, Evaluation>>>,
-        funcs: &mut Rc<RefCell<Map<Symbol, Function>>>,
-    ) {
+        globals: &Arc<Globals>,
+    ) -> Result<(), ProgramError> {
         let mut handles = Vec::new();
-        for (name, eval_to, run_type) in &self.assignments {
+        for (name, eval_to, run_type, span) in &self.assignments {
             match run_type {
                 RunType::Regular => {
-                    let eval = eval_to.evaluate(vars, funcs);
+                    let eval = eval_to.evaluate(vars, globals)?;
                     vars.borrow_mut()
-                        .insert(name.clone(), Evaluation::Literal(eval));
+                        .insert(name.clone(), Evaluation::Literal(eval, *span));
                 }
                 RunType::Thread => {
                     let mut new_vars = Map::new();
-                    let mut new_funcs = Map::new();
                     for x in vars.borrow().iter() {
                         new_vars.insert(x.0.clone(), x.1.clone());
                     }
-                    for x in funcs.borrow().iter() {
-                        new_funcs.insert(x.0.clone(), x.1.clone());
-                    }
                     let move_eval_to = eval_to.clone();
+                    let globals = globals.clone();
                     let handle = thread::spawn(move || {
                         let v = new_vars;
-                        let f = new_funcs;
-                        move_eval_to
-                            .evaluate(&mut Rc::new(RefCell::new(v)), &mut Rc::new(RefCell::new(f)))
+                        move_eval_to.evaluate(&mut Rc::new(RefCell::new(v)), &globals)
                     });
-                    handles.push((name, handle));
+                    handles.push((name, handle, span));
                 }
             }
         }
-        for (name, handle) in handles {
+        for (name, handle, span) in handles {
+            let eval = match handle.join() {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(ProgramError {
+                        code: ErrorCode::ThreadPanicked,
+                        message: format!("the thread computing `{}` panicked", name),
+                        span: Some(*span),
+                        related: Vec::new(),
+                    })
+                }
+            };
             vars.borrow_mut()
-                .insert(name.clone(), Evaluation::Literal(handle.join().unwrap()));
+                .insert(name.clone(), Evaluation::Literal(eval, *span));
         }
+        Ok(())
     }
 }
 
@@ -86,6 +105,7 @@ pub enum Function {
         args: Vec<(Symbol, Type)>,
         body: Option<Box<Evaluation>>,
         return_type: Type,
+        span: Span,
     },
     Breakout {
         name: Symbol,
@@ -93,6 +113,7 @@ pub enum Function {
         stages: Vec<FunctionStage>,
         final_eval: Box<Evaluation>,
         return_type: Type,
+        span: Span,
     },
 }
 impl Clone for Function {
@@ -103,11 +124,13 @@ impl Clone for Function {
                 args,
                 body,
                 return_type,
+                span,
             } => Function::Simple {
                 name: name.clone(),
                 args: args.clone(),
                 body: body.clone(),
                 return_type: *return_type,
+                span: *span,
             },
             Function::Breakout {
                 name,
@@ -115,58 +138,338 @@ impl Clone for Function {
                 stages,
                 final_eval,
                 return_type,
+                span,
             } => Function::Breakout {
                 name: name.clone(),
                 args: args.clone(),
                 stages: stages.to_vec(),
                 final_eval: final_eval.clone(),
                 return_type: *return_type,
+                span: *span,
             },
         }
     }
 }
 impl Function {
-    pub fn set_body(&mut self, b: Option<Box<Evaluation>>) {
-        match self {
-            Function::Simple {
-                name: _,
-                args: _,
-                body,
-                return_type: _,
-            } => *body = b,
-            _ => todo!(),
-        }
-    }
     pub fn get_type(&self) -> Type {
         match self {
             Function::Simple { return_type, .. } => *return_type,
             Function::Breakout { return_type, .. } => *return_type,
         }
     }
+    pub fn get_args(&self) -> &[(Symbol, Type)] {
+        match self {
+            Function::Simple { args, .. } => args,
+            Function::Breakout { args, .. } => args,
+        }
+    }
+    /// The stages of a breakout function; simple functions have none.
+    pub fn get_stages(&self) -> &[FunctionStage] {
+        match self {
+            Function::Simple { .. } => &[],
+            Function::Breakout { stages, .. } => stages,
+        }
+    }
     pub fn get_arg_types(&self) -> Vec<Type> {
         match self {
             Function::Simple { args, .. } => args.iter().map(|(_, t)| *t).collect(),
             Function::Breakout { args, .. } => args.iter().map(|(_, t)| *t).collect(),
         }
     }
+    /// Every variable and top-level function the function's body refers to, leaving out its
+    /// arguments and stage variables.
+    pub fn references(&self) -> Vec<(Symbol, Span)> {
+        let mut used = Vec::new();
+        let mut local: Vec<&Symbol> = self.get_args().iter().map(|(name, _)| name).collect();
+        match self {
+            Function::Simple { body, .. } => {
+                if let Some(body) = body {
+                    body.references(&mut used);
+                }
+            }
+            Function::Breakout {
+                stages, final_eval, ..
+            } => {
+                for stage in stages {
+                    for (name, eval, _, _) in &stage.assignments {
+                        eval.references(&mut used);
+                        local.push(name);
+                    }
+                }
+                final_eval.references(&mut used);
+            }
+        }
+        used.retain(|(name, _)| !local.contains(&name));
+        used
+    }
+    /// Runs the function with `args` bound to its arguments. The body sees nothing of the
+    /// caller's scope, only its arguments and the top-level definitions.
+    pub fn call(
+        &self,
+        args: Vec<(Literal, Span)>,
+        globals: &Arc<Globals>,
+    ) -> Result<Literal, ProgramError> {
+        let mut give_vars = Map::new();
+        for ((name, _), (value, span)) in self.get_args().iter().zip(args) {
+            give_vars.insert(name.clone(), Evaluation::Literal(value, span));
+        }
+        self.evaluate(&mut Rc::new(RefCell::new(give_vars)), globals)
+    }
     pub fn evaluate(
         &self,
-        global_vars: &mut Rc<RefCell<Map<Symbol, Evaluation>>>,
-        global_funcs: &mut Rc<RefCell<Map<Symbol, Function>>>,
-    ) -> Literal {
+        local_vars: &mut Rc<RefCell<Map<Symbol, Evaluation>>>,
+        globals: &Arc<Globals>,
+    ) -> Result<Literal, ProgramError> {
         match self {
-            Function::Simple { body, .. } => body
-                .as_ref()
-                .unwrap()
-                .evaluate(&mut global_vars.clone(), &mut global_funcs.clone()),
+            Function::Simple { body, .. } => body.as_ref().unwrap().evaluate(local_vars, globals),
             Function::Breakout {
                 stages, final_eval, ..
             } => {
                 for stage in stages {
-                    stage.evaluate(&mut global_vars.clone(), &mut global_funcs.clone());
+                    stage.evaluate(local_vars, globals)?;
                 }
-                final_eval.evaluate(&mut global_vars.clone(), &mut global_funcs.clone())
+                final_eval.evaluate(local_vars, globals)
             }
         }
     }
 }
+
+/// What a function value runs when it is called.
+#[derive(Debug)]
+enum Callee {
+    /// An anonymous function, along with the variables it uses from where it was written.
+    Lambda {
+        params: Vec<(Symbol, Type)>,
+        body: Arc<Evaluation>,
+        captured: Vec<(Symbol, Evaluation)>,
+    },
+    /// A top-level function.
+    Named(Symbol),
+}
+
+/// A function used as a value, along with the arguments it has been partially applied to.
+/// Function values are equal if they run the same function with the same arguments so far; an
+/// anonymous function is the same if it comes from the same `\` with the same captured values,
+/// where a captured `let` binding is the same if it binds the same expression to the same
+/// values, so a function value always equals itself.
+#[derive(Debug, Clone)]
+pub struct Closure {
+    callee: Arc<Callee>,
+    applied: Vec<(Literal, Span)>,
+    /// The type of the function of the arguments still missing.
+    t: Type,
+}
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        let same_callee = match (self.callee.as_ref(), other.callee.as_ref()) {
+            (Callee::Named(a), Callee::Named(b)) => a == b,
+            (
+                Callee::Lambda {
+                    body: a,
+                    captured: captured_a,
+                    ..
+                },
+                Callee::Lambda {
+                    body: b,
+                    captured: captured_b,
+                    ..
+                },
+            ) => {
+                Arc::ptr_eq(a, b)
+                    && captured_a.len() == captured_b.len()
+                    && captured_a
+                        .iter()
+                        .zip(captured_b)
+                        .all(|((a, x), (b, y))| a == b && x.same_capture(y))
+            }
+            _ => false,
+        };
+        same_callee
+            && self
+                .applied
+                .iter()
+                .map(|(value, _)| value)
+                .eq(other.applied.iter().map(|(value, _)| value))
+    }
+}
+impl Eq for Closure {}
+impl Closure {
+    pub fn lambda(
+        params: Vec<(Symbol, Type)>,
+        body: Arc<Evaluation>,
+        captured: Vec<(Symbol, Evaluation)>,
+        t: Type,
+    ) -> Self {
+        Closure {
+            callee: Arc::new(Callee::Lambda {
+                params,
+                body,
+                captured,
+            }),
+            applied: Vec::new(),
+            t,
+        }
+    }
+    /// The top-level function `name` applied to `applied`, which are fewer arguments than it
+    /// takes, leaving a function of type `t`.
+    pub fn named(name: Symbol, applied: Vec<(Literal, Span)>, t: Type) -> Self {
+        Closure {
+            callee: Arc::new(Callee::Named(name)),
+            applied,
+            t,
+        }
+    }
+    pub fn get_type(&self) -> Type {
+        self.t
+    }
+    /// Calls the function with `args`, or returns the function of the rest of its arguments
+    /// if there are fewer than it still needs. Either way the result has type `return_type`,
+    /// which the compiler already worked out.
+    pub fn call(
+        &self,
+        args: Vec<(Literal, Span)>,
+        return_type: Type,
+        globals: &Arc<Globals>,
+    ) -> Result<Literal, ProgramError> {
+        let missing = match self.t {
+            Type::Func(func) => func.args(&globals.types).len(),
+            _ => unreachable!(),
+        };
+        let given = args.len();
+        let mut all = self.applied.clone();
+        all.extend(args);
+        if given < missing {
+            return Ok(Literal::Function(Closure {
+                callee: self.callee.clone(),
+                applied: all,
+                t: return_type,
+            }));
+        }
+        match self.callee.as_ref() {
+            Callee::Lambda {
+                params,
+                body,
+                captured,
+            } => {
+                // only what the function captured where it was written, never the caller's scope
+                let mut give_vars: Map<Symbol, Evaluation> = captured.iter().cloned().collect();
+                for ((name, _), (value, span)) in params.iter().zip(all) {
+                    give_vars.insert(name.clone(), Evaluation::Literal(value, span));
+                }
+                body.evaluate(&mut Rc::new(RefCell::new(give_vars)), globals)
+            }
+            Callee::Named(name) => globals.functions[name].call(all, globals),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ErrorCode;
+    use crate::parser::{Literal, Symbol};
+    use crate::primitives::{Bool, Int};
+    use crate::testing::{compile, errors, run};
+
+    #[test]
+    fn functions_do_not_see_the_locals_of_their_caller() {
+        let program = "\
+int k := 1
+int -> int f := \\x : int => + x k
+int y := + k 0
+int func g := k : int => f 0
+int func h := k : int => y
+int main := + (* 1000 (g 100)) (h 100)
+";
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(1001)));
+    }
+
+    #[test]
+    fn lambdas_keep_what_they_captured() {
+        let program = "\
+int -> int func adder := n : int => \\x : int => + x n
+int func apply := f : int -> int n : int => f 1
+int main := apply (adder 10) 5
+";
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(11)));
+    }
+
+    #[test]
+    fn function_values_equal_themselves() {
+        let program = "\
+int -> int func scaled := n : int => let int m := * n 2 in \\x : int => * x m
+int -> int g := scaled 3
+int -> int h := scaled 4
+int func inc := n : int => + n 1
+bool main := && (== (g) (g)) (&& (!= (g) (h)) (&& (== (inc) (inc)) (== (g) (scaled 3))))
+";
+        assert_eq!(run(program).unwrap(), Literal::Bool(Bool::new(true)));
+    }
+
+    #[test]
+    fn function_values_in_parentheses_take_the_arguments_after_them() {
+        for (main, value) in [
+            ("((add3 1) 2) 3", 6),
+            ("(g 2) 3", 6),
+            ("+ ((add3 1) 2) 3 (g 2) 3", 12),
+            ("twice (g 3) 1", 9),
+            ("twice (add3 1 1) 0", 4),
+            ("add3 (g 2) 3 1 1", 8),
+        ] {
+            let program = format!(
+                "\
+int func add3 := a : int b : int c : int => + a + b c
+int func g := a : int b : int => * a b
+int func twice := f : int -> int x : int => f f x
+int main := {}
+",
+                main
+            );
+            assert_eq!(
+                run(&program).unwrap(),
+                Literal::Integer(Int::new(value)),
+                "{}",
+                main
+            );
+        }
+    }
+
+    #[test]
+    fn partial_applications_have_the_type_of_the_rest() {
+        let program = "\
+int func add := a : int b : int => + a b
+int -> int inc := (add 1)
+int int -> int same := (add)
+int main := + (inc 1) (same 2 3)
+";
+        let global_state = compile(program).unwrap();
+        for (name, t) in [("inc", "int -> int"), ("same", "int int -> int")] {
+            let found = global_state
+                .get_type(&Symbol::new(name.to_string()))
+                .unwrap();
+            assert_eq!(found.display(global_state.types()).to_string(), t);
+        }
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(7)));
+    }
+
+    #[test]
+    fn partial_applications_can_be_applied_further() {
+        let program = "\
+int func add3 := a : int b : int c : int => + a + b c
+int func twice := f : int -> int x : int => f f x
+int int -> int add1 := (add3 1)
+int -> int add3_ := (add1 2)
+int main := + (add3_ 3) (twice (add3 1 1) 0)
+";
+        assert_eq!(run(program).unwrap(), Literal::Integer(Int::new(10)));
+    }
+
+    #[test]
+    fn partial_applications_check_their_arguments() {
+        let found = errors("int func add := a : int b : int => + a b\nint -> int f := (add true)\nint main := f 1\n");
+        assert_eq!(found[0].code, ErrorCode::ArgumentType);
+        let found = errors(
+            "int func add := a : int b : int => + a b\nstr -> int f := (add 1)\nint main := 1\n",
+        );
+        assert_eq!(found[0].code, ErrorCode::TypeMismatch);
+    }
+}
@@ -1,16 +1,21 @@
 #![allow(dead_code)]
-use crate::errors::ProgramError;
+use crate::errors::{CompileError, ErrorCode, NameError, ProgramError};
 use crate::expressions::Evaluation;
 use crate::functions::Function;
+use crate::modules::ModuleTable;
 use crate::parser::{Literal, Symbol};
-use crate::scanner::Type;
+use crate::scanner::{keyword_names, Type, TypeTable};
+use crate::span::Span;
 use std::cell::RefCell;
 use std::collections::HashMap as Map;
+use std::collections::HashSet;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct SymbolTable {
     token_to_id: Map<Symbol, usize>,
     id_to_token: Map<usize, Symbol>,
+    id_to_span: Map<usize, Span>,
     count: usize,
 }
 impl SymbolTable {
@@ -19,19 +24,30 @@ impl SymbolTable {
             count: 0,
             token_to_id: Map::new(),
             id_to_token: Map::new(),
+            id_to_span: Map::new(),
         }
     }
-    fn add(&mut self, symbol: Symbol) -> Result<usize, ProgramError> {
-        if self.token_to_id.contains_key(&symbol) {
+    fn add(&mut self, symbol: Symbol, span: Span) -> Result<usize, ProgramError> {
+        if let Some(id) = self.token_to_id.get(&symbol) {
             return Err(ProgramError {
+                code: ErrorCode::DuplicateDefinition,
                 message: symbol.clone().to_string() + " already exists",
+                span: Some(span),
+                related: vec![(self.id_to_span[id], "first defined here".to_string())],
             });
         }
         self.count += 1;
         self.token_to_id.insert(symbol.clone(), self.count);
         self.id_to_token.insert(self.count, symbol);
+        self.id_to_span.insert(self.count, span);
         Ok(self.count)
     }
+    fn get_span(&self, symbol: &Symbol) -> Option<Span> {
+        self.token_to_id
+            .get(symbol)
+            .and_then(|id| self.id_to_span.get(id))
+            .cloned()
+    }
     fn get_id(&self, symbol: Symbol) -> Option<usize> {
         self.token_to_id.get(&symbol).cloned()
     }
@@ -46,16 +62,34 @@ impl SymbolTable {
     }
 }
 
+/// The top-level definitions of a running program. Every function call starts from these and
+/// its arguments, so a body never sees the locals of whoever called it.
+pub struct Globals {
+    pub variables: Map<Symbol, Evaluation>,
+    pub functions: Map<Symbol, Function>,
+    pub types: TypeTable,
+}
+
 pub struct GlobalState {
     symbol_table: SymbolTable,
     variables: Rc<RefCell<Map<Symbol, Evaluation>>>,
+    declared_variables: Map<Symbol, Type>,
     variable_override: Rc<RefCell<Map<Symbol, Type>>>,
     func_table: Rc<RefCell<Map<Symbol, Function>>>,
     main_evaluation: Option<Evaluation>,
+    modules: ModuleTable,
+    /// Definitions marked `pub`, which other modules may use.
+    exported: HashSet<Symbol>,
+    /// The `##` doc comments written above definitions and types.
+    docs: Map<Symbol, String>,
+    /// The function, record and sum types of the program.
+    types: TypeTable,
+    /// Types declared with `type`, in the order they were declared.
+    declared_types: Vec<(Type, Span)>,
 }
 
 impl GlobalState {
-    pub fn new() -> Self {
+    pub fn new(modules: ModuleTable, types: TypeTable) -> Self {
         let symbol_table = SymbolTable::new();
         let variables = Rc::new(RefCell::new(Map::new()));
         let variable_override = Rc::new(RefCell::new(Map::new()));
@@ -63,34 +97,231 @@ impl GlobalState {
         GlobalState {
             symbol_table,
             variables,
+            declared_variables: Map::new(),
             variable_override,
             func_table,
             main_evaluation: None,
+            modules,
+            exported: HashSet::new(),
+            docs: Map::new(),
+            types,
+            declared_types: Vec::new(),
+        }
+    }
+    /// The name a top-level definition of `name` at `span` is stored under, prefixed with its
+    /// module's name unless it is in the main module.
+    pub fn qualify(&self, name: &Symbol, span: Span) -> Symbol {
+        let module = self.modules.module_of(span.file);
+        Symbol::new(self.modules.qualify(module, &name.name()))
+    }
+    /// Lets other modules use the (qualified) definition `name`.
+    pub fn export(&mut self, name: Symbol) {
+        self.exported.insert(name);
+    }
+    /// Finds the definition `symbol` refers to when used at `span`: an argument or stage
+    /// variable, a definition in the same module, a selectively imported name, or
+    /// `alias.name` for a `pub` definition of an imported module.
+    pub fn resolve(&self, symbol: &Symbol, span: Span) -> Result<Symbol, CompileError> {
+        if self.variable_override.borrow().contains_key(symbol) {
+            return Ok(symbol.clone());
+        }
+        let module = self.modules.module_of(span.file);
+        let name = symbol.name();
+        let (target, member) = match name.split_once('.') {
+            Some((alias, member)) => match self.modules.get(module).imports.get(alias) {
+                Some((target, _)) => (*target, member),
+                None => return Err(self.unknown_symbol(symbol, span).into()),
+            },
+            None => {
+                let own = Symbol::new(self.modules.qualify(module, &name));
+                if self.symbol_table.symbol_exists(own.clone()) {
+                    return Ok(own);
+                }
+                match self.modules.get(module).selected.get(&name) {
+                    Some((target, _)) => (*target, name.as_str()),
+                    None => return Err(self.unknown_symbol(symbol, span).into()),
+                }
+            }
+        };
+        let qualified = Symbol::new(self.modules.qualify(target, member));
+        if !self.symbol_table.symbol_exists(qualified.clone()) {
+            return Err(self.unknown_symbol(symbol, span).into());
+        }
+        if target != module && !self.exported.contains(&qualified) {
+            return Err(self.private_symbol(symbol, &qualified, span).into());
+        }
+        Ok(qualified)
+    }
+    fn private_symbol(&self, symbol: &Symbol, qualified: &Symbol, span: Span) -> NameError {
+        NameError {
+            code: ErrorCode::PrivateSymbol,
+            name: symbol.name(),
+            message: format!("`{}` is private to its module", symbol),
+            span,
+            suggestions: Vec::new(),
+            keywords: Vec::new(),
+            related: self
+                .get_span(qualified)
+                .map(|s| (s, "defined here without `pub`".to_string()))
+                .into_iter()
+                .collect(),
+        }
+    }
+    /// Checks that every name listed in a selective import exists and is `pub`.
+    pub fn check_imports(&self) -> Vec<CompileError> {
+        let mut selected: Vec<(usize, &String, usize, Span)> = self
+            .modules
+            .iter()
+            .flat_map(|(module, m)| {
+                m.selected
+                    .iter()
+                    .map(move |(name, (target, span))| (module, name, *target, *span))
+            })
+            .collect();
+        selected.sort_by_key(|(_, _, _, span)| (span.file, span.line, span.col));
+        selected
+            .into_iter()
+            .filter_map(|(module, name, target, span)| {
+                let symbol = Symbol::new(name.clone());
+                let qualified = Symbol::new(self.modules.qualify(target, name));
+                if !self.symbol_table.symbol_exists(qualified.clone()) {
+                    let mut error = self.unknown_symbol(&symbol, span);
+                    error.message = format!(
+                        "module `{}` has no definition `{}`",
+                        self.modules.get(target).name,
+                        name
+                    );
+                    error.suggestions = closest(name, self.exported_names(target));
+                    Some(error.into())
+                } else if target != module && !self.exported.contains(&qualified) {
+                    Some(self.private_symbol(&symbol, &qualified, span).into())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+    /// The plain names of the `pub` definitions of `module`.
+    fn exported_names(&self, module: usize) -> Vec<String> {
+        self.exported
+            .iter()
+            .filter_map(|s| {
+                self.modules
+                    .unqualify(module, &s.name())
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+    /// Every name an expression in `module` can refer to, spelled as it would be written there.
+    fn visible_names(&self, module: usize) -> Vec<String> {
+        let m = self.modules.get(module);
+        let mut names: Vec<String> = self
+            .symbol_table
+            .token_to_id
+            .keys()
+            .filter_map(|s| {
+                self.modules
+                    .unqualify(module, &s.name())
+                    .map(str::to_string)
+            })
+            .chain(self.variable_override.borrow().keys().map(|s| s.name()))
+            .chain(m.selected.keys().cloned())
+            .collect();
+        for (alias, (target, _)) in &m.imports {
+            for name in self.exported_names(*target) {
+                names.push(format!("{}.{}", alias, name));
+            }
         }
+        names
+    }
+    /// Records the type of a top-level variable before its value is parsed, so it can be
+    /// used anywhere in the program.
+    pub fn declare_variable(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        t: Type,
+    ) -> Result<(), ProgramError> {
+        self.symbol_table.add(name.clone(), span)?;
+        self.declared_variables.insert(name, t);
+        Ok(())
+    }
+    /// Records a function's signature before its body is parsed, so it can be called anywhere
+    /// in the program, including from functions defined above it.
+    pub fn declare_function(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        function: Function,
+    ) -> Result<(), ProgramError> {
+        self.symbol_table.add(name.clone(), span)?;
+        self.func_table.borrow_mut().insert(name, function);
+        Ok(())
+    }
+    /// Whether the definition at `span` is the one `name` was declared by, rather than a
+    /// duplicate that was already reported.
+    fn is_declared_at(&self, name: &Symbol, span: Span) -> bool {
+        self.symbol_table.get_span(name) == Some(span)
     }
-    pub fn add_variable(&mut self, name: Symbol, value: Evaluation) -> Result<(), ProgramError> {
-        self.symbol_table.add(name.clone())?;
+    pub fn add_variable(&mut self, name: Symbol, span: Span, value: Evaluation) {
+        if !self.is_declared_at(&name, span) {
+            return;
+        }
         if name.name() == "main" {
             self.main_evaluation = Some(value);
         } else {
             self.variables.borrow_mut().insert(name, value);
         }
-        Ok(())
     }
-    pub fn add_function(&mut self, name: Symbol, function: Function) -> Result<(), ProgramError> {
-        self.symbol_table.add(name.clone())?;
-        self.func_table.borrow_mut().insert(name, function);
-        Ok(())
+    pub fn set_doc(&mut self, name: Symbol, span: Span, doc: String) {
+        if self.is_declared_at(&name, span) {
+            self.docs.insert(name, doc);
+        }
     }
-    pub fn set_function_body(&mut self, name: Symbol, body: Evaluation) {
-        self.func_table
-            .borrow_mut()
-            .get_mut(&name)
-            .unwrap()
-            .set_body(Some(Box::new(body)));
+    /// Records the declaration of `t`, whose doc comment is looked up by the type's name.
+    pub fn add_type(&mut self, t: Type, span: Span, doc: Option<String>) {
+        if let Some(doc) = doc {
+            let name = t.display(&self.types).to_string();
+            self.docs.insert(Symbol::new(name), doc);
+        }
+        self.declared_types.push((t, span));
+    }
+    pub fn declared_types(&self) -> &[(Type, Span)] {
+        &self.declared_types
+    }
+    pub fn types(&self) -> &TypeTable {
+        &self.types
+    }
+    pub fn types_mut(&mut self) -> &mut TypeTable {
+        &mut self.types
+    }
+    /// Every top-level definition, in the order they were declared.
+    pub fn definitions(&self) -> Vec<(Symbol, Span)> {
+        (1..=self.symbol_table.count)
+            .filter_map(|id| {
+                let symbol = self.symbol_table.get_symbol(id)?;
+                let span = self.symbol_table.get_span(&symbol)?;
+                Some((symbol, span))
+            })
+            .collect()
     }
-    pub fn set_function_body_breakout(&mut self, name: Symbol, body: Function) {
-        self.func_table.borrow_mut().insert(name, body.clone());
+    pub fn get_function(&self, symbol: &Symbol) -> Option<Function> {
+        self.func_table.borrow().get(symbol).cloned()
+    }
+    pub fn is_exported(&self, symbol: &Symbol) -> bool {
+        self.exported.contains(symbol)
+    }
+    pub fn modules(&self) -> &ModuleTable {
+        &self.modules
+    }
+    /// The doc comment of a top-level definition or type, if it has one.
+    pub fn get_doc(&self, name: &Symbol) -> Option<&str> {
+        self.docs.get(name).map(String::as_str)
+    }
+    pub fn add_function(&mut self, name: Symbol, span: Span, function: Function) {
+        if self.is_declared_at(&name, span) {
+            self.func_table.borrow_mut().insert(name, function);
+        }
     }
     pub fn get_args(&self, symbol: &Symbol) -> Vec<Type> {
         self.func_table
@@ -99,6 +330,10 @@ impl GlobalState {
             .map(|f| f.get_arg_types())
             .unwrap()
     }
+    /// Where a global variable or function was defined.
+    pub fn get_span(&self, symbol: &Symbol) -> Option<Span> {
+        self.symbol_table.get_span(symbol)
+    }
     pub fn is_function(&self, symbol: &Symbol) -> bool {
         self.func_table
             .borrow()
@@ -113,31 +348,217 @@ impl GlobalState {
             .map(|_| true)
             .unwrap_or(false)
     }
-    pub fn override_variables(&mut self, symbol: Symbol, value: Type) {
-        self.variable_override.borrow_mut().insert(symbol, value);
+    /// Gives the local `symbol` the type `value`, returning the type it had before.
+    pub fn override_variables(&mut self, symbol: Symbol, value: Type) -> Option<Type> {
+        self.variable_override.borrow_mut().insert(symbol, value)
+    }
+    /// Puts back what `override_variables` replaced, when the scope of a local ends.
+    pub fn restore_override(&mut self, symbol: Symbol, previous: Option<Type>) {
+        match previous {
+            Some(t) => self.variable_override.borrow_mut().insert(symbol, t),
+            None => self.variable_override.borrow_mut().remove(&symbol),
+        };
+    }
+    /// Whether `symbol` is an argument, stage variable or lambda parameter in scope.
+    pub fn is_local(&self, symbol: &Symbol) -> bool {
+        self.variable_override.borrow().contains_key(symbol)
     }
     pub fn clear_overrides(&mut self) {
         self.variable_override.borrow_mut().clear();
     }
-    pub fn get_type(&self, symbol: &Symbol) -> Type {
+    pub fn get_type(&self, symbol: &Symbol) -> Option<Type> {
         if let Some(id) = self.variable_override.borrow().get(symbol) {
-            return *id;
-        } else if let Some(id) = self.variables.borrow().get(symbol) {
-            return id.get_type();
+            return Some(*id);
+        } else if let Some(t) = self.declared_variables.get(symbol) {
+            return Some(*t);
         } else if let Some(id) = self.func_table.borrow().get(symbol) {
-            return id.get_type();
+            return Some(id.get_type());
         }
-        panic!("Type not found, symbol: {}", symbol);
+        None
     }
-    pub fn eval_main(mut self) -> Result<Literal, ProgramError> {
+    /// Builds the error for a symbol that is not in scope, suggesting similarly spelled
+    /// variables, functions and arguments, as well as keywords the symbol may be a typo of.
+    pub fn unknown_symbol(&self, symbol: &Symbol, span: Span) -> NameError {
+        let name = symbol.name();
+        let in_scope = self.visible_names(self.modules.module_of(span.file));
+        NameError {
+            code: ErrorCode::UnknownSymbol,
+            message: format!("cannot find `{}` in this scope", name),
+            name,
+            span,
+            suggestions: closest(&symbol.name(), in_scope),
+            keywords: closest(
+                &symbol.name(),
+                keyword_names()
+                    .filter(|k| k.chars().all(char::is_alphabetic))
+                    .map(str::to_string),
+            ),
+            related: Vec::new(),
+        }
+    }
+    /// Finds top-level variables whose values depend on themselves, directly or through the
+    /// functions they call, which could never finish evaluating. The error points at the first
+    /// variable of the cycle and each reference in it.
+    pub fn find_variable_cycle(&self) -> Option<ProgramError> {
+        let variables = self.variables.borrow();
+        let functions = self.func_table.borrow();
+        let main = Symbol::new("main".to_string());
+        let references = |name: &Symbol| {
+            let mut used = Vec::new();
+            if *name == main {
+                if let Some(eval) = self.main_evaluation.as_ref() {
+                    eval.references(&mut used);
+                }
+            } else if let Some(eval) = variables.get(name) {
+                eval.references(&mut used);
+            } else if let Some(function) = functions.get(name) {
+                used = function.references();
+            }
+            used.retain(|(name, _)| {
+                self.declared_variables.contains_key(name) || functions.contains_key(name)
+            });
+            used.reverse();
+            used
+        };
+        let mut names: Vec<&Symbol> = self.declared_variables.keys().collect();
+        names.sort_by_cached_key(|name| self.get_span(name).map(|s| (s.file, s.line, s.col)));
+        for root in names {
+            // a depth-first search from `root` for a path back to it
+            let mut seen: Vec<Symbol> = vec![root.clone()];
+            let mut path = vec![(root.clone(), Span::default(), references(root))];
+            while let Some((_, _, used)) = path.last_mut() {
+                let (next, span) = match used.pop() {
+                    Some(next) => next,
+                    None => {
+                        path.pop();
+                        continue;
+                    }
+                };
+                if next == *root {
+                    let mut related: Vec<(Span, String)> = path
+                        .windows(2)
+                        .map(|w| (w[1].1, format!("`{}` uses `{}` here", w[0].0, w[1].0)))
+                        .collect();
+                    let last = &path.last().unwrap().0;
+                    related.push((span, format!("`{}` uses `{}` here", last, root)));
+                    return Some(ProgramError {
+                        code: ErrorCode::CyclicDefinition,
+                        message: format!("the value of `{}` depends on itself", root),
+                        span: self.get_span(root),
+                        related,
+                    });
+                }
+                if !seen.contains(&next) {
+                    seen.push(next.clone());
+                    let used = references(&next);
+                    path.push((next, span, used));
+                }
+            }
+        }
+        None
+    }
+    pub fn eval_main(self) -> Result<Literal, ProgramError> {
         let eval = match self.main_evaluation {
             Some(e) => e,
             None => {
-                println!("No main function found");
-                panic!()
+                return Err(ProgramError::new(
+                    ErrorCode::MissingMain,
+                    "No main function found".to_string(),
+                ))
             }
         };
-        let literal = eval.evaluate(&mut self.variables, &mut self.func_table);
-        Ok(literal)
+        let globals = Arc::new(Globals {
+            variables: self.variables.take(),
+            functions: self.func_table.take(),
+            types: self.types,
+        });
+        eval.evaluate(&mut Rc::new(RefCell::new(Map::new())), &globals)
+    }
+}
+
+/// Edit distance between two strings, counted in chars, where swapping two neighbouring
+/// chars counts as a single edit (optimal string alignment).
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// The candidates close enough to `name` to be a likely typo, closest first, at most three. A
+/// candidate has to keep at least one character of `name`, so one-letter names get no
+/// suggestions.
+fn closest(name: &str, candidates: impl IntoIterator<Item = String>) -> Vec<String> {
+    let len = name.chars().count();
+    let max_distance = (len / 3).max(1);
+    let mut scored: Vec<(usize, String)> = candidates
+        .into_iter()
+        .filter(|c| c != name)
+        .map(|c| (edit_distance(name, &c), c))
+        .filter(|(d, c)| *d <= max_distance && *d < len && *d < c.chars().count())
+        .collect();
+    scored.sort();
+    scored.dedup();
+    scored.into_iter().take(3).map(|(_, c)| c).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::errors;
+
+    #[test]
+    fn swapped_letters_are_one_edit() {
+        assert_eq!(edit_distance("square", "square"), 0);
+        assert_eq!(edit_distance("sqaure", "square"), 1);
+        assert_eq!(edit_distance("sqare", "square"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn only_close_names_are_suggested() {
+        let names = || ["square", "squares", "cube", "x"].map(str::to_string);
+        assert_eq!(closest("squres", names()), ["squares", "square"]);
+        assert_eq!(closest("sqare", names()), ["square"]);
+        assert!(closest("y", names()).is_empty());
+        assert!(closest("area", names()).is_empty());
+    }
+
+    #[test]
+    fn unknown_symbols_suggest_names_in_scope() {
+        let found = errors("int func f := count : int => + cuont 1\nint main := f 3\n");
+        assert_eq!(found[0].code, ErrorCode::UnknownSymbol);
+        assert_eq!(found[0].message, "cannot find `cuont` in this scope");
+        assert_eq!(found[0].notes, ["did you mean `count`?"]);
+
+        let found = errors("int func square := n : int => * n n\nint main := sqare 3\n");
+        assert_eq!(found[0].notes, ["did you mean `square`?"]);
+    }
+
+    #[test]
+    fn unknown_symbols_suggest_keywords() {
+        let errors = errors("int main := cnod true 1 2\n");
+        assert_eq!(errors[0].code, ErrorCode::UnknownSymbol);
+        assert_eq!(
+            errors[0].notes,
+            ["`cnod` looks like a typo of the keyword `cond`"]
+        );
     }
 }
@@ -0,0 +1,475 @@
+use crate::errors::ErrorCode;
+use crate::parser::ParsingError;
+use crate::scanner::{is_directive, token_for, PreToken, PreTokenized};
+use crate::span::Span;
+
+/// Characters that make up operators; a run of them is split into the longest known operators.
+const OPERATOR_CHARS: &str = ":=><!&|+-*/%\\";
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Turns the lines of a file into tokens, ending every line with an `EOL` token. Triple-quoted
+/// strings and block comments may continue onto the following lines of the same file.
+pub fn lex(lines: &[(String, usize, usize)]) -> Result<Vec<(PreTokenized, Span)>, ParsingError> {
+    let mut lexer = Lexer {
+        lines,
+        index: 0,
+        chars: Vec::new(),
+        pos: 0,
+        file: 0,
+        line_no: 0,
+        out: Vec::new(),
+    };
+    while lexer.index < lines.len() {
+        lexer.load_line(lexer.index);
+        lexer.lex_line()?;
+        let end = lexer.chars.len() + 1;
+        lexer.out.push((
+            PreTokenized::T(PreToken::EOL),
+            Span::new(lexer.file, lexer.line_no, end, end + 1),
+        ));
+        lexer.index += 1;
+    }
+    Ok(lexer.out)
+}
+
+/// Which lines of `contents` start inside a triple-quoted string or a block comment, and so
+/// can never be directives. Lines that fail to lex are left for `lex` to report.
+pub fn hidden_lines(contents: &str) -> Vec<bool> {
+    let lines: Vec<(String, usize, usize)> = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (line.to_string(), 0, i + 1))
+        .collect();
+    let mut hidden = vec![false; lines.len()];
+    let mut lexer = Lexer {
+        lines: &lines,
+        index: 0,
+        chars: Vec::new(),
+        pos: 0,
+        file: 0,
+        line_no: 0,
+        out: Vec::new(),
+    };
+    while lexer.index < lines.len() {
+        let start = lexer.index;
+        if !is_directive(&lines[start].0) {
+            lexer.load_line(start);
+            let _ = lexer.lex_line();
+            lexer.out.clear();
+            hidden[start + 1..=lexer.index].fill(true);
+        }
+        lexer.index += 1;
+    }
+    hidden
+}
+
+struct Lexer<'a> {
+    lines: &'a [(String, usize, usize)],
+    index: usize,
+    chars: Vec<char>,
+    pos: usize,
+    file: usize,
+    line_no: usize,
+    out: Vec<(PreTokenized, Span)>,
+}
+
+impl Lexer<'_> {
+    fn load_line(&mut self, index: usize) {
+        let (line, file, line_no) = &self.lines[index];
+        self.index = index;
+        self.chars = line.chars().collect();
+        self.pos = 0;
+        self.file = *file;
+        self.line_no = *line_no;
+    }
+
+    /// Moves on to the next line if it belongs to the same file.
+    fn next_line(&mut self) -> bool {
+        match self.lines.get(self.index + 1) {
+            Some((_, file, _)) if *file == self.file => {
+                self.load_line(self.index + 1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// The span from the char at `start` up to the current position.
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(
+            self.file,
+            self.line_no,
+            start + 1,
+            self.pos.max(start + 1) + 1,
+        )
+    }
+
+    fn text_from(&self, start: usize) -> String {
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn lex_line(&mut self) -> Result<(), ParsingError> {
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            match c {
+                c if c.is_whitespace() || c == ',' || c == ';' => self.pos += 1,
+                '#' if self.starts_with("#[") => self.block_comment()?,
+                '#' if self.starts_with("##") => {
+                    let text = self.chars[start + 2..].iter().collect::<String>();
+                    let text = text
+                        .strip_prefix(' ')
+                        .unwrap_or(&text)
+                        .trim_end()
+                        .to_string();
+                    self.pos = self.chars.len();
+                    self.out
+                        .push((PreTokenized::Doc(text), self.span_from(start)));
+                }
+                '#' => {
+                    self.pos = self.chars.len();
+                    let text = self.text_from(start).trim_end().to_string();
+                    self.out
+                        .push((PreTokenized::Comment(text), self.span_from(start)));
+                }
+                '"' => self.string(false)?,
+                'r' if self.chars.get(self.pos + 1) == Some(&'"') => {
+                    self.pos += 1;
+                    self.string(true)?;
+                }
+                '(' | ')' | '[' | ']' => {
+                    self.pos += 1;
+                    let token = token_for(&c.to_string()).unwrap();
+                    self.out
+                        .push((PreTokenized::T(token), self.span_from(start)));
+                }
+                _ if self.starts_number(self.pos) => self.number(),
+                '-' if self.starts_number(self.pos + 1) => self.number(),
+                c if is_word_char(c) => self.word(),
+                c if OPERATOR_CHARS.contains(c) => self.operator()?,
+                c => {
+                    self.pos += 1;
+                    return Err(ParsingError {
+                        code: ErrorCode::UnexpectedCharacter,
+                        span: self.span_from(start),
+                        message: format!("unexpected character `{}`", c),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A `#[ ... ]#` comment, which may span several lines and contain other block comments.
+    fn block_comment(&mut self) -> Result<(), ParsingError> {
+        let (start_line, start_col) = (self.line_no, self.pos + 1);
+        let mut depth = 0;
+        let mut text = String::new();
+        loop {
+            if self.starts_with("#[") {
+                depth += 1;
+                self.pos += 2;
+                text += "#[";
+            } else if self.starts_with("]#") {
+                depth -= 1;
+                self.pos += 2;
+                text += "]#";
+                if depth == 0 {
+                    let span = Span {
+                        file: self.file,
+                        line: start_line,
+                        col: start_col,
+                        end_line: self.line_no,
+                        end_col: self.pos + 1,
+                    };
+                    self.out.push((PreTokenized::Comment(text), span));
+                    return Ok(());
+                }
+            } else if let Some(c) = self.peek() {
+                self.pos += 1;
+                text.push(c);
+            } else if self.next_line() {
+                text.push('\n');
+            } else {
+                return Err(ParsingError {
+                    code: ErrorCode::UnterminatedComment,
+                    span: Span {
+                        file: self.file,
+                        line: start_line,
+                        col: start_col,
+                        end_line: start_line,
+                        end_col: start_col + 2,
+                    },
+                    message: "unterminated block comment".to_string(),
+                });
+            }
+        }
+    }
+
+    /// A string literal, either `"..."` or a `"""..."""` that may span several lines. Raw
+    /// strings, prefixed with `r`, keep backslashes as they are.
+    fn string(&mut self, raw: bool) -> Result<(), ParsingError> {
+        let start = if raw { self.pos - 1 } else { self.pos };
+        let (start_line, start_col) = (self.line_no, start + 1);
+        let unterminated = |lexer: &Lexer| ParsingError {
+            code: ErrorCode::UnterminatedString,
+            span: Span {
+                file: lexer.file,
+                line: start_line,
+                col: start_col,
+                end_line: lexer.line_no,
+                end_col: lexer.chars.len() + 1,
+            },
+            message: "unterminated string".to_string(),
+        };
+        let triple = self.starts_with("\"\"\"");
+        let quote = if triple { "\"\"\"" } else { "\"" };
+        self.pos += quote.len();
+        // a line break straight after the opening quotes is not part of the string
+        if triple && self.pos == self.chars.len() {
+            self.next_line();
+        }
+        let mut value = String::new();
+        loop {
+            if self.starts_with(quote) {
+                self.pos += quote.len();
+                break;
+            }
+            match self.peek() {
+                None if triple => {
+                    if !self.next_line() {
+                        return Err(unterminated(self));
+                    }
+                    value.push('\n');
+                }
+                None => return Err(unterminated(self)),
+                Some('\\') if !raw => value.push(self.escape()?),
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        let span = Span {
+            file: self.file,
+            line: start_line,
+            col: start_col,
+            end_line: self.line_no,
+            end_col: self.pos + 1,
+        };
+        self.out.push((PreTokenized::Str(value), span));
+        Ok(())
+    }
+
+    /// The char written by the escape sequence at the current position.
+    fn escape(&mut self) -> Result<char, ParsingError> {
+        let start = self.pos;
+        self.pos += 2;
+        let c = match self.chars.get(start + 1) {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('u') if self.peek() == Some('{') => {
+                let digits_start = self.pos + 1;
+                let close = self.chars[digits_start..].iter().position(|c| *c == '}');
+                let code = close.and_then(|len| {
+                    let digits: String = self.chars[digits_start..digits_start + len]
+                        .iter()
+                        .collect();
+                    self.pos = digits_start + len + 1;
+                    if len > 6 {
+                        return None;
+                    }
+                    u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                });
+                match code {
+                    Some(c) => c,
+                    None => {
+                        return Err(ParsingError {
+                            code: ErrorCode::InvalidEscape,
+                            span: self.span_from(start),
+                            message: "invalid unicode escape, expected `\\u{...}` with up to six \
+                                hex digits"
+                                .to_string(),
+                        })
+                    }
+                }
+            }
+            _ => {
+                self.pos = self.pos.min(self.chars.len());
+                return Err(ParsingError {
+                    code: ErrorCode::InvalidEscape,
+                    span: self.span_from(start),
+                    message: format!("unknown escape sequence `{}`", self.text_from(start)),
+                });
+            }
+        };
+        Ok(c)
+    }
+
+    /// Whether a number starts at `pos`: a digit, or a `.` followed by one.
+    fn starts_number(&self, pos: usize) -> bool {
+        match self.chars.get(pos) {
+            Some(c) if c.is_ascii_digit() => true,
+            Some('.') => self.chars.get(pos + 1).is_some_and(|c| c.is_ascii_digit()),
+            _ => false,
+        }
+    }
+
+    /// A number, with an optional leading `-`. Its digits are checked by the parser.
+    fn number(&mut self) {
+        let start = self.pos;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        let hex = self.starts_with("0x") || self.starts_with("0X");
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '-' || c == '+')
+                && !hex
+                && matches!(self.chars.get(self.pos - 1), Some('e') | Some('E'));
+            if is_word_char(c) || exponent_sign {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        self.out.push((
+            PreTokenized::Num(self.text_from(start)),
+            self.span_from(start),
+        ));
+    }
+
+    /// A name or keyword.
+    fn word(&mut self) {
+        let start = self.pos;
+        while self.peek().is_some_and(is_word_char) {
+            self.pos += 1;
+        }
+        let text = self.text_from(start);
+        let token = match token_for(&text) {
+            Some(token) => PreTokenized::T(token),
+            None => PreTokenized::S(text),
+        };
+        self.out.push((token, self.span_from(start)));
+    }
+
+    /// The longest operator starting at the current position.
+    fn operator(&mut self) -> Result<(), ParsingError> {
+        let start = self.pos;
+        let mut end = start;
+        while self
+            .chars
+            .get(end)
+            .is_some_and(|c| OPERATOR_CHARS.contains(*c))
+        {
+            end += 1;
+        }
+        while end > start {
+            let text: String = self.chars[start..end].iter().collect();
+            if let Some(token) = token_for(&text) {
+                self.pos = end;
+                self.out
+                    .push((PreTokenized::T(token), self.span_from(start)));
+                return Ok(());
+            }
+            end -= 1;
+        }
+        self.pos += 1;
+        Err(ParsingError {
+            code: ErrorCode::UnexpectedCharacter,
+            span: self.span_from(start),
+            message: format!("unknown operator `{}`", self.text_from(start)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(source: &str) -> Result<String, ParsingError> {
+        let lines = [(source.to_string(), 0, 1)];
+        match lex(&lines)?.remove(0).0 {
+            PreTokenized::Str(value) => Ok(value),
+            token => panic!("expected a string, found {:?}", token),
+        }
+    }
+
+    #[test]
+    fn escapes_are_replaced() {
+        assert_eq!(string(r#""a\n\t\r\0\"\\b""#).unwrap(), "a\n\t\r\0\"\\b");
+        assert_eq!(string(r#"r"C:\data\n""#).unwrap(), r"C:\data\n");
+        assert_eq!(
+            string(r#""\q""#).unwrap_err().code,
+            ErrorCode::InvalidEscape
+        );
+        let unterminated = lex(&[("\"abc".to_string(), 0, 1)]).unwrap_err();
+        assert_eq!(unterminated.code, ErrorCode::UnterminatedString);
+    }
+
+    #[test]
+    fn numbers_are_read_whole() {
+        let lines = [("- 1.5e-3 -7 0xFF_FF 3f".to_string(), 0, 1)];
+        let numbers: Vec<PreTokenized> = lex(&lines)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .filter(|token| matches!(token, PreTokenized::Num(_)))
+            .collect();
+        let expected = ["1.5e-3", "-7", "0xFF_FF", "3f"];
+        assert_eq!(numbers, expected.map(|n| PreTokenized::Num(n.to_string())));
+    }
+
+    #[test]
+    fn unicode_escapes_take_up_to_six_digits() {
+        assert_eq!(string(r#""\u{41}\u{1F600}""#).unwrap(), "A\u{1F600}");
+        assert_eq!(string(r#""\u{10FFFF}""#).unwrap(), "\u{10FFFF}");
+        let error = string(r#""\u{0000000041}""#).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidEscape);
+        assert_eq!(
+            string(r#""\u{}""#).unwrap_err().code,
+            ErrorCode::InvalidEscape
+        );
+    }
+
+    #[test]
+    fn long_strings_hide_their_lines() {
+        let contents = "str s := \"\"\"\ninclude a.bo\n\"\"\"\ninclude b.bo";
+        assert_eq!(hidden_lines(contents), [false, true, true, false]);
+    }
+
+    #[test]
+    fn quotes_in_comments_and_strings_hide_nothing() {
+        let contents = "# note: \"\"\"\nstr s := \"a \\\"\\\"\\\" b\"\ninclude a.bo";
+        assert_eq!(hidden_lines(contents), [false, false, false]);
+    }
+
+    #[test]
+    fn block_comments_hide_their_lines() {
+        let contents = "#[ a\n#[ nested ]#\ninclude a.bo\n]#\ninclude b.bo";
+        assert_eq!(hidden_lines(contents), [false, true, true, true, false]);
+    }
+
+    #[test]
+    fn comment_openers_in_strings_and_comments_hide_nothing() {
+        let contents = "str s := \"#[\"\n# not a block #[\ninclude a.bo";
+        assert_eq!(hidden_lines(contents), [false, false, false]);
+    }
+}
@@ -1,56 +1,220 @@
 use ast_generator::generate_ast;
 mod ast_generator;
+mod cli;
+mod cst;
+mod diagnostics;
+mod doc;
 mod errors;
+mod explain;
 mod expressions;
+mod formatter;
 mod functions;
 mod globalstate;
+mod lexer;
+mod modules;
 mod parser;
+mod patterns;
 mod primitives;
 mod scanner;
+mod span;
+#[cfg(test)]
+mod testing;
 
+use diagnostics::Diagnostic;
 use std::time::SystemTime;
 
 use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::thread;
 
 const STACK_SIZE: usize = 256 * 1024 * 1024;
 
-fn run() {
-    let start = SystemTime::now();
+fn report(diagnostic: Diagnostic, sources: &span::SourceMap, format: cli::ErrorFormat) {
+    match format {
+        cli::ErrorFormat::Human => {
+            println!("{}", diagnostic.render(sources, diagnostics::use_color()))
+        }
+        cli::ErrorFormat::Json => println!("{}", diagnostic.to_json(sources)),
+    }
+}
+
+fn explain(code: &str) -> ExitCode {
+    match errors::ErrorCode::from_code(code) {
+        Some(code) => {
+            println!("{}: {}\n", code, code.title());
+            print!("{}", explain::explanation(code));
+            ExitCode::SUCCESS
+        }
+        None => {
+            println!("`{}` is not a Breakout error code", code);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Parses the program and builds its AST, reporting any errors.
+fn compile(
+    options: &cli::Options,
+    sources: &mut span::SourceMap,
+) -> Option<globalstate::GlobalState> {
+    let human = options.error_format == cli::ErrorFormat::Human;
+    let path = options.path.as_str();
+    let mut search_paths: Vec<PathBuf> = options.include_paths.iter().map(PathBuf::from).collect();
+    if let Some(breakout_path) = env::var_os("BREAKOUT_PATH") {
+        search_paths.extend(env::split_paths(&breakout_path));
+    }
+    let mut modules = modules::ModuleTable::new();
+    let mut types = scanner::TypeTable::new();
+    let mut tokens = match parser::parse(path, search_paths, sources, &mut modules, &mut types) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            report((&e).into(), sources, options.error_format);
+            return None;
+        }
+    };
+    let mut global_state = globalstate::GlobalState::new(modules, types);
+    if let Err((errors, left_out)) =
+        generate_ast(&mut tokens, &mut global_state, options.max_errors)
+    {
+        for e in &errors {
+            report(e.into(), sources, options.error_format);
+        }
+        if human && left_out {
+            println!("Stopped after {} errors", errors.len());
+        } else if human {
+            println!("Found {} error(s)", errors.len());
+        }
+        if human {
+            println!("For more information about an error, try `breakout explain <code>`");
+        }
+        return None;
+    }
+    Some(global_state)
+}
 
-    let args: Vec<String> = env::args().collect();
-    let path: &str = args[1].as_str();
-    let out = parser::parse(path);
-    let mut global_state = globalstate::GlobalState::new();
-    let ast = generate_ast(&mut out.unwrap(), &mut global_state);
-    if ast.is_err() {
-        println!("{}", ast.unwrap_err());
-        return;
+fn doc(options: &cli::Options, out_dir: &str) -> ExitCode {
+    let mut sources = span::SourceMap::new();
+    let Some(global_state) = compile(options, &mut sources) else {
+        return ExitCode::FAILURE;
+    };
+    let pages = doc::pages(&global_state, &sources);
+    match doc::write(&pages, std::path::Path::new(out_dir)) {
+        Ok(files) => {
+            for file in files {
+                println!("Wrote {}", file.display());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("could not write documentation to `{}`: {}", out_dir, e);
+            ExitCode::FAILURE
+        }
     }
+}
+
+/// Formats each file in place. With `check`, files are left alone and the ones that are not
+/// formatted are listed instead.
+fn fmt(paths: &[String], check: bool) -> ExitCode {
+    let mut sources = span::SourceMap::new();
+    let mut success = true;
+    for path in paths {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                let error = errors::ProgramError {
+                    code: errors::ErrorCode::UnreadableFile,
+                    message: format!("could not read `{}`: {}", path, e),
+                    span: None,
+                    related: Vec::new(),
+                };
+                report((&error).into(), &sources, cli::ErrorFormat::Human);
+                success = false;
+                continue;
+            }
+        };
+        let file = sources.add(path.clone(), contents.clone());
+        let formatted = match formatter::format(&contents, file) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                report((&e).into(), &sources, cli::ErrorFormat::Human);
+                success = false;
+                continue;
+            }
+        };
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path);
+            success = false;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            println!("could not write `{}`: {}", path, e);
+            success = false;
+        }
+    }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn run() -> ExitCode {
+    let start = SystemTime::now();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::Command::from_args(&args) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Doc(options, out_dir)) => return doc(&options, &out_dir),
+        Ok(cli::Command::Fmt { paths, check }) => return fmt(&paths, check),
+        Ok(cli::Command::Explain(code)) => return explain(&code),
+        Err(e) => {
+            println!("{}\n{}", e, cli::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let human = options.error_format == cli::ErrorFormat::Human;
+    let mut sources = span::SourceMap::new();
+    let Some(global_state) = compile(&options, &mut sources) else {
+        return ExitCode::FAILURE;
+    };
     let end = SystemTime::now();
-    println!(
-        "Parsed Source in: {}ms\n",
-        end.duration_since(start).unwrap().as_millis()
-    );
+    if human {
+        println!(
+            "Parsed Source in: {}ms\n",
+            end.duration_since(start).unwrap().as_millis()
+        );
+    }
 
     let exec_start = SystemTime::now();
 
-    let output = global_state.eval_main();
+    let types = global_state.types().clone();
+    let output = match global_state.eval_main() {
+        Ok(output) => output,
+        Err(e) => {
+            report((&e).into(), &sources, options.error_format);
+            return ExitCode::FAILURE;
+        }
+    };
 
     let exec_end = SystemTime::now();
 
-    println!("{}", output.unwrap());
-    println!(
-        "\nExecuted in: {}ms\n",
-        exec_end.duration_since(exec_start).unwrap().as_millis()
-    );
+    println!("{}", output.display(&types));
+    if human {
+        println!(
+            "\nExecuted in: {}ms\n",
+            exec_end.duration_since(exec_start).unwrap().as_millis()
+        );
+    }
+    ExitCode::SUCCESS
 }
 
-fn main() {
+fn main() -> ExitCode {
     let child = thread::Builder::new()
         .stack_size(STACK_SIZE)
         .spawn(run)
         .unwrap();
 
-    child.join().unwrap();
+    child.join().unwrap()
 }
@@ -0,0 +1,159 @@
+use crate::span::Span;
+use std::collections::HashMap as Map;
+
+/// A namespace of definitions. The program's main file is module 0; every file loaded with
+/// `import` gets a module of its own, while `include`d files join the module including them.
+pub struct Module {
+    /// Prefix given to the module's definitions, e.g. `math` for `math.sqrt`. Empty for the
+    /// main module, whose definitions keep their plain names.
+    pub name: String,
+    /// Imported modules, by the alias they are referred to with.
+    pub imports: Map<String, (usize, Span)>,
+    /// Names brought in unqualified by a selective import, and the module they come from.
+    pub selected: Map<String, (usize, Span)>,
+}
+
+#[derive(Default)]
+pub struct ModuleTable {
+    modules: Vec<Module>,
+    file_module: Map<usize, usize>,
+}
+impl ModuleTable {
+    pub fn new() -> ModuleTable {
+        ModuleTable {
+            modules: Vec::new(),
+            file_module: Map::new(),
+        }
+    }
+    /// Adds a module, numbering its name if another module already uses it.
+    pub fn add(&mut self, name: &str) -> usize {
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while self.modules.iter().any(|m| m.name == unique) {
+            n += 1;
+            unique = format!("{}{}", name, n);
+        }
+        self.modules.push(Module {
+            name: unique,
+            imports: Map::new(),
+            selected: Map::new(),
+        });
+        self.modules.len() - 1
+    }
+    pub fn get(&self, module: usize) -> &Module {
+        &self.modules[module]
+    }
+    pub fn get_mut(&mut self, module: usize) -> &mut Module {
+        &mut self.modules[module]
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Module)> {
+        self.modules.iter().enumerate()
+    }
+    pub fn set_file(&mut self, file: usize, module: usize) {
+        self.file_module.insert(file, module);
+    }
+    /// The module the source file `file` belongs to.
+    pub fn module_of(&self, file: usize) -> usize {
+        self.file_module.get(&file).copied().unwrap_or(0)
+    }
+    /// The name a definition called `name` in `module` is stored under.
+    pub fn qualify(&self, module: usize, name: &str) -> String {
+        match self.modules.get(module) {
+            Some(m) if !m.name.is_empty() => format!("{}.{}", m.name, name),
+            _ => name.to_string(),
+        }
+    }
+    /// The plain name of `qualified` if it is defined in `module`.
+    pub fn unqualify<'a>(&self, module: usize, qualified: &'a str) -> Option<&'a str> {
+        match self.modules.get(module) {
+            Some(m) if !m.name.is_empty() => qualified
+                .strip_prefix(m.name.as_str())
+                .and_then(|rest| rest.strip_prefix('.')),
+            _ if qualified.contains('.') => None,
+            _ => Some(qualified),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::Diagnostic;
+    use crate::errors::ErrorCode;
+    use crate::parser::Literal;
+    use crate::primitives::Int;
+    use crate::testing::compile_files;
+
+    const MATH: &str = "\
+pub int func square := n : int => * n n
+int func helper := n : int => + n n
+";
+
+    fn run_files(files: &[(&str, &str)]) -> Literal {
+        compile_files(files).0.unwrap().eval_main().unwrap()
+    }
+
+    fn error_codes(files: &[(&str, &str)]) -> Vec<ErrorCode> {
+        match compile_files(files).0 {
+            Ok(_) => panic!("compiles: {:?}", files),
+            Err(errors) => errors.iter().map(|e| Diagnostic::from(e).code).collect(),
+        }
+    }
+
+    #[test]
+    fn imported_definitions_are_qualified() {
+        let files = [
+            ("main.bo", "import math\nint main := math.square 3\n"),
+            ("math.bo", MATH),
+        ];
+        assert_eq!(run_files(&files), Literal::Integer(Int::new(9)));
+    }
+
+    #[test]
+    fn imports_can_be_renamed_or_selected() {
+        let files = [
+            (
+                "main.bo",
+                "import \"lib/math.bo\" as m\nimport \"lib/math.bo\" (square)\nint main := + (m.square 2) (square 3)\n",
+            ),
+            ("lib/math.bo", MATH),
+        ];
+        assert_eq!(run_files(&files), Literal::Integer(Int::new(13)));
+    }
+
+    #[test]
+    fn modules_keep_their_names_to_themselves() {
+        let square = "int func square := n : int => 0\n";
+        let files = [
+            (
+                "main.bo",
+                &*format!("import math\n{}int main := math.square 3\n", square),
+            ),
+            ("math.bo", MATH),
+        ];
+        assert_eq!(run_files(&files), Literal::Integer(Int::new(9)));
+        let files = [
+            ("main.bo", "import math\nint main := square 3\n"),
+            ("math.bo", MATH),
+        ];
+        assert_eq!(error_codes(&files), [ErrorCode::UnknownSymbol]);
+    }
+
+    #[test]
+    fn private_definitions_cannot_be_used_by_importers() {
+        let files = [
+            ("main.bo", "import math\nint main := math.helper 3\n"),
+            ("math.bo", MATH),
+        ];
+        assert_eq!(error_codes(&files), [ErrorCode::PrivateSymbol]);
+    }
+
+    #[test]
+    fn modules_with_the_same_name_get_distinct_prefixes() {
+        let mut modules = ModuleTable::new();
+        let first = modules.add("math");
+        let second = modules.add("math");
+        assert_eq!(modules.get(first).name, "math");
+        assert_eq!(modules.get(second).name, "math2");
+    }
+}
@@ -1,8 +1,17 @@
 #![allow(dead_code)]
-use crate::primitives::{Bool, Float, Int, Str};
+use crate::ast_generator::starts_definition;
+use crate::cst;
+use crate::errors::{CompileError, ErrorCode};
+use crate::functions::Closure;
+use crate::modules::ModuleTable;
+use crate::primitives::{Bool, Data, Float, Int, Record, Str};
 use crate::scanner::*;
+use crate::span::{SourceMap, Span};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Symbol {
@@ -29,6 +38,9 @@ pub enum Literal {
     Float(Float),
     String(Str),
     Bool(Bool),
+    Function(Closure),
+    Record(Record),
+    Data(Data),
     Void,
 }
 impl Literal {
@@ -38,113 +50,368 @@ impl Literal {
             Literal::Float(_) => Type::Float,
             Literal::String(_) => Type::Str,
             Literal::Bool(_) => Type::Bool,
+            Literal::Function(f) => f.get_type(),
+            Literal::Record(r) => Type::Record(r.get_type()),
+            Literal::Data(d) => Type::Data(d.get_type()),
             Literal::Void => Type::NoType,
         }
     }
+    /// The value as the program prints it.
+    pub fn display<'a>(&'a self, types: &'a TypeTable) -> LiteralDisplay<'a> {
+        LiteralDisplay {
+            literal: self,
+            types,
+        }
+    }
 }
 
-impl std::fmt::Display for Literal {
+/// Writes a value, looking up the names of its type in the table of its program.
+pub struct LiteralDisplay<'a> {
+    literal: &'a Literal,
+    types: &'a TypeTable,
+}
+impl std::fmt::Display for LiteralDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
+        match self.literal {
             Literal::Integer(n) => write!(f, "{}", n),
             Literal::Float(n) => write!(f, "{}", n),
             Literal::String(s) => write!(f, "{}", s),
             Literal::Bool(b) => write!(f, "{}", b),
+            Literal::Function(func) => {
+                write!(f, "<function {}>", func.get_type().display(self.types))
+            }
+            Literal::Record(r) => r.write(f, self.types),
+            Literal::Data(d) => d.write(f, self.types),
             Literal::Void => write!(f, "void"),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Token {
+pub enum TokenKind {
     Lang(PreToken),
     Symb(Symbol),
     Lit(Literal),
+    /// The text of a `##` doc comment, which documents the definition after it.
+    Doc(String),
+    /// An `include` or `import` line, which the scanner has already acted on.
+    Directive(String),
+}
+
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Lang(t) => write!(f, "{}", t),
+            TokenKind::Symb(s) => write!(f, "symbol `{}`", s),
+            TokenKind::Lit(Literal::String(s)) => write!(f, "literal \"{}\"", s),
+            // literals in the source are never functions, records or sum values, which are the
+            // only ones that need the types of a program to be written
+            TokenKind::Lit(l) => write!(f, "literal `{}`", l.display(&TypeTable::new())),
+            TokenKind::Doc(_) => write!(f, "doc comment"),
+            TokenKind::Directive(_) => write!(f, "directive"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+impl Token {
+    pub fn new(kind: TokenKind, span: Span) -> Token {
+        Token { kind, span }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ParsingError {
-    pub line: usize,
+    pub code: ErrorCode,
+    pub span: Span,
     pub message: String,
 }
 
 impl fmt::Display for ParsingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error on line {}:\n\t{}", self.line, self.message)
+        write!(
+            f,
+            "Parsing Error [{}] at {}: {}",
+            self.code, self.span, self.message
+        )
     }
 }
 impl Error for ParsingError {}
 
-fn parse_literal(s: String, desired_type: Option<Type>) -> Result<Token, ParsingError> {
-    if s.starts_with("\"") && s.ends_with("\"") {
-        Ok(Token::Lit(Literal::String(Str::new(
-            s[1..s.len() - 1].to_string(),
-        ))))
-    } else if s.parse::<i64>().is_ok() && desired_type.unwrap_or(Type::Int) == Type::Int {
-        Ok(Token::Lit(Literal::Integer(Int::new(s.parse().unwrap()))))
-    } else if s.parse::<f64>().is_ok() {
-        Ok(Token::Lit(Literal::Float(Float::new(s.parse().unwrap()))))
-    } else if s == "true" || s == "false" {
-        if s == "true" {
-            Ok(Token::Lit(Literal::Bool(Bool::new(true))))
-        } else {
-            Ok(Token::Lit(Literal::Bool(Bool::new(false))))
-        }
-    } else {
-        panic!("where is my literal??")
+/// Reads a number literal: decimal, `0x`/`0b`/`0o` ints, `_` separators, exponents and an
+/// optional `i` or `f` suffix. Without a suffix, a number is a float if it has a fraction or an
+/// exponent and an int otherwise.
+fn parse_literal(s: &str, span: Span) -> Result<Literal, ParsingError> {
+    let invalid = |message: String| ParsingError {
+        code: ErrorCode::InvalidLiteral,
+        span,
+        message,
+    };
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let radix = match unsigned.get(..2) {
+        Some("0x") | Some("0X") => 16,
+        Some("0b") | Some("0B") => 2,
+        Some("0o") | Some("0O") => 8,
+        _ => 10,
+    };
+    let (body, suffix) = match unsigned.chars().last() {
+        Some('i') => (&unsigned[..unsigned.len() - 1], Some(Type::Int)),
+        Some('f') if radix == 10 => (&unsigned[..unsigned.len() - 1], Some(Type::Float)),
+        _ => (unsigned, None),
+    };
+    let digits = if radix == 10 { body } else { &body[2..] };
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') {
+        return Err(invalid(format!("`{}` is not a valid number", s)));
+    }
+    let digits = digits.replace('_', "");
+    let sign = if negative { "-" } else { "" };
+    let is_float = radix == 10 && digits.contains(['.', 'e', 'E']);
+    match (suffix, is_float) {
+        (Some(Type::Int), true) => Err(invalid(format!(
+            "`{}` has a fraction or exponent, so it cannot be an int",
+            s
+        ))),
+        (Some(Type::Float), _) | (None, true) => match (sign.to_string() + &digits).parse() {
+            Ok(value) if f64::is_infinite(value) => {
+                Err(invalid(format!("`{}` does not fit in a float", s)))
+            }
+            Ok(value) => Ok(Literal::Float(Float::new(value))),
+            Err(_) => Err(invalid(format!("`{}` is not a valid float", s))),
+        },
+        _ => match i64::from_str_radix(&(sign.to_string() + &digits), radix) {
+            Ok(value) => Ok(Literal::Integer(Int::new(value))),
+            Err(e)
+                if *e.kind() == std::num::IntErrorKind::PosOverflow
+                    || *e.kind() == std::num::IntErrorKind::NegOverflow =>
+            {
+                Err(invalid(format!("`{}` does not fit in an int", s)))
+            }
+            Err(_) => Err(invalid(format!("`{}` is not a valid int", s))),
+        },
     }
 }
 
-fn parse_symbol(s: String) -> Result<Token, ParsingError> {
-    Ok(Token::Symb(Symbol::new(s)))
+fn parse_symbol(s: String) -> Result<TokenKind, ParsingError> {
+    Ok(TokenKind::Symb(Symbol::new(s)))
 }
 
-fn parse_word(s: String, desired_type: Option<Type>) -> Result<Token, ParsingError> {
-    if s.starts_with("\"")
-        || s.starts_with("0")
-        || s.starts_with("1")
-        || s.starts_with("2")
-        || s.starts_with("3")
-        || s.starts_with("4")
-        || s.starts_with("5")
-        || s.starts_with("6")
-        || s.starts_with("7")
-        || s.starts_with("8")
-        || s.starts_with("9")
-        || s.starts_with(".")
-        || s == "true"
-        || s == "false"
-    {
-        parse_literal(s, desired_type)
-    } else {
-        Ok(Token::Symb(Symbol::new(s)))
+fn parse_word(s: String) -> TokenKind {
+    match s.as_str() {
+        "true" => TokenKind::Lit(Literal::Bool(Bool::new(true))),
+        "false" => TokenKind::Lit(Literal::Bool(Bool::new(false))),
+        _ => TokenKind::Symb(Symbol::new(s)),
     }
 }
 
-fn parse_line(line: &str) -> Result<Vec<Token>, ParsingError> {
+pub fn parse(
+    path: &str,
+    search_paths: Vec<PathBuf>,
+    sources: &mut SourceMap,
+    modules: &mut ModuleTable,
+    types: &mut TypeTable,
+) -> Result<Vec<Token>, CompileError> {
+    let mut scanner = Scanner::new(sources, modules, search_paths);
+    scanner.load_file(path)?;
+    let mut lines = Vec::new();
+    while let Some(line) = scanner.get_next_line() {
+        lines.push(line);
+    }
+    // every file is read through its syntax tree; the scanned lines give the order in which
+    // the tokens of included files appear
+    let mut files = HashMap::new();
     let mut out: Vec<Token> = Vec::new();
-    let pre_tokens = tokenize_line(line.to_string());
-    let mut desired_type: Option<Type> = None;
-    for token in pre_tokens {
-        if let PreTokenized::T(PreToken::TYPE(t)) = token {
-            desired_type = Some(t);
+    for (_, file, line_no) in &lines {
+        let tokens = match files.entry(*file) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let contents = &sources.get(*file).unwrap().contents;
+                let tree = cst::build(contents, *file, types)?;
+                entry.insert(tree.into_program_tokens().into_iter().peekable())
+            }
+        };
+        // the lines of a file are scanned in order, skipping its `include` and `import` lines
+        while tokens.next_if(|t| t.span.line < *line_no).is_some() {}
+        while let Some(token) = tokens.next_if(|t| t.span.line == *line_no) {
+            out.push(token);
+        }
+    }
+    resolve_type_names(out.iter_mut().map(|t| &mut t.kind).collect(), types);
+    Ok(join_indented_lines(keep_attached_docs(out)))
+}
+
+/// Turns the names of the types declared with `type` into type tokens, so they can be used
+/// wherever a type keyword can. Type names are shared by every file of the program.
+pub fn resolve_type_names(mut kinds: Vec<&mut TokenKind>, types: &mut TypeTable) {
+    let mut declared: HashMap<String, Type> = HashMap::new();
+    for i in 1..kinds.len() {
+        if *kinds[i - 1] != TokenKind::Lang(PreToken::KW(Keyword::Type)) {
+            continue;
+        }
+        let name = match &*kinds[i] {
+            TokenKind::Symb(name) => name.name(),
+            TokenKind::Lang(PreToken::TYPE(Type::Record(record))) => record.name(types).to_string(),
+            TokenKind::Lang(PreToken::TYPE(Type::Data(data))) => data.name(types).to_string(),
+            _ => continue,
+        };
+        // a name defined twice keeps the kind of its first definition, so that the second
+        // one is reported as a duplicate rather than misread
+        if declared.contains_key(&name) {
+            continue;
         }
-        match token {
-            PreTokenized::T(t) => out.push(Token::Lang(t)),
-            PreTokenized::S(s) => {
-                out.push(parse_word(s, desired_type)?);
+        // the fields of a record are written `name : type`, while a variant starts with its
+        // name and no `:`
+        let body: Vec<&TokenKind> = kinds[i + 1..]
+            .iter()
+            .map(|k| &**k)
+            .filter(|k| **k != TokenKind::Lang(PreToken::EOL))
+            .take(3)
+            .collect();
+        let is_sum = match body[..] {
+            [_, TokenKind::Lang(PreToken::KW(Keyword::Bar)), ..] => true,
+            [_, TokenKind::Symb(_), next, ..] => {
+                *next != TokenKind::Lang(PreToken::DEL(Delimeter::Colon))
             }
+            [_, TokenKind::Symb(_)] => true,
+            _ => false,
+        };
+        let t = if is_sum {
+            Type::Data(types.data(&name))
+        } else {
+            Type::Record(types.record(&name))
+        };
+        declared.insert(name, t);
+    }
+    for kind in kinds.iter_mut() {
+        let t = match &**kind {
+            TokenKind::Symb(name) => declared.get(&name.name()),
+            TokenKind::Lang(PreToken::TYPE(Type::Record(record))) => {
+                declared.get(record.name(types))
+            }
+            TokenKind::Lang(PreToken::TYPE(Type::Data(data))) => declared.get(data.name(types)),
+            _ => None,
+        };
+        if let Some(t) = t {
+            **kind = TokenKind::Lang(PreToken::TYPE(*t));
         }
     }
-    Ok(out)
 }
 
-pub fn parse(path: &str) -> Result<Vec<Token>, Box<dyn Error>> {
-    let mut scanner = Scanner::new();
-    scanner.load_file(path)?;
-    let mut out: Vec<Token> = Vec::new();
-    while let Some(line) = scanner.get_next_line() {
-        out.append(&mut parse_line(&line)?);
+/// The parser's view of a token read by the lexer.
+pub fn token_kind(token: PreTokenized, span: Span) -> Result<TokenKind, ParsingError> {
+    Ok(match token {
+        PreTokenized::T(t) => TokenKind::Lang(t),
+        PreTokenized::S(s) => parse_word(s),
+        PreTokenized::Num(s) => TokenKind::Lit(parse_literal(&s, span)?),
+        PreTokenized::Str(s) => TokenKind::Lit(Literal::String(Str::new(s))),
+        PreTokenized::Doc(s) => TokenKind::Doc(s),
+        PreTokenized::Comment(_) => unreachable!("comments are trivia, not tokens"),
+    })
+}
+
+/// Drops doc comments that are not followed by a definition, as there is nothing to attach
+/// them to.
+fn keep_attached_docs(tokens: Vec<Token>) -> Vec<Token> {
+    let attached: Vec<bool> = (0..tokens.len())
+        .map(|i| {
+            matches!(tokens[i].kind, TokenKind::Doc(_)) && {
+                let next = tokens[i..].iter().find(|t| {
+                    !matches!(t.kind, TokenKind::Doc(_) | TokenKind::Lang(PreToken::EOL))
+                });
+                starts_definition(next)
+            }
+        })
+        .collect();
+    tokens
+        .into_iter()
+        .zip(attached)
+        .filter(|(t, attached)| *attached || !matches!(t.kind, TokenKind::Doc(_)))
+        .map(|(t, _)| t)
+        .collect()
+}
+
+/// Joins indented lines onto the line above, so long expressions can be laid out over several
+/// lines. Lines starting with `|` or `=>` stay separate, as they give functions their shape.
+fn join_indented_lines(tokens: Vec<Token>) -> Vec<Token> {
+    let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut line_ends: Vec<Token> = Vec::new();
+    for token in tokens {
+        if token.kind == TokenKind::Lang(PreToken::EOL) {
+            line_ends.push(token);
+            continue;
+        }
+        let continues = token.span.col > 1
+            && !out.is_empty()
+            && !matches!(
+                token.kind,
+                TokenKind::Lang(PreToken::KW(Keyword::Bar | Keyword::Kerchow)) | TokenKind::Doc(_)
+            );
+        if continues {
+            line_ends.clear();
+        } else {
+            out.append(&mut line_ends);
+        }
+        out.push(token);
+    }
+    out.append(&mut line_ends);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(s: &str) -> Result<Literal, ErrorCode> {
+        parse_literal(s, Span::default()).map_err(|e| e.code)
+    }
+
+    #[test]
+    fn ints_can_be_written_in_several_bases() {
+        for (s, value) in [
+            ("42", 42),
+            ("-7", -7),
+            ("0xFF", 255),
+            ("0b1010", 10),
+            ("0o17", 15),
+            ("1_000_000", 1_000_000),
+            ("12i", 12),
+        ] {
+            assert_eq!(number(s), Ok(Literal::Integer(Int::new(value))), "{}", s);
+        }
+    }
+
+    #[test]
+    fn fractions_exponents_and_suffixes_make_floats() {
+        for (s, value) in [("2.5", 2.5), (".5", 0.5), ("1.5e-3", 1.5e-3), ("3f", 3.0)] {
+            assert_eq!(number(s), Ok(Literal::Float(Float::new(value))), "{}", s);
+        }
+    }
+
+    #[test]
+    fn malformed_numbers_are_rejected() {
+        for s in ["1.5i", "1__", "_1", "0x", "0xFFf1.0", "1e", "0b102"] {
+            assert_eq!(number(s), Err(ErrorCode::InvalidLiteral), "{}", s);
+        }
+    }
+
+    #[test]
+    fn numbers_too_large_for_their_type_are_rejected() {
+        assert_eq!(
+            number("9223372036854775807"),
+            Ok(Literal::Integer(Int::new(i64::MAX)))
+        );
+        assert_eq!(
+            number("9223372036854775808"),
+            Err(ErrorCode::InvalidLiteral)
+        );
+        assert_eq!(number("1e400"), Err(ErrorCode::InvalidLiteral));
+        assert_eq!(number("-1e400"), Err(ErrorCode::InvalidLiteral));
+        assert_eq!(number("1e-400"), Ok(Literal::Float(Float::new(0.0))));
     }
-    Ok(out)
 }
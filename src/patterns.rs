@@ -0,0 +1,507 @@
+use crate::ast_generator::unexpected;
+use crate::errors::{CompileError, ErrorCode, TypeError};
+use crate::parser::{Literal, Symbol, Token, TokenKind};
+use crate::scanner::{DataType, Delimeter, PreToken, Type, TypeTable};
+use crate::span::Span;
+
+/// What an arm of a `match` compares the value against.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, which matches anything.
+    Wildcard(Span),
+    /// A name, which matches anything and binds the value to it.
+    Binding(Symbol, Span),
+    /// A literal, which matches the values equal to it.
+    Literal(Literal, Span),
+    /// A variant of a data type with a pattern for each of its fields, such as `cons x _`.
+    Constructor {
+        data: DataType,
+        variant: usize,
+        args: Vec<Pattern>,
+        span: Span,
+    },
+}
+impl Pattern {
+    /// Reads a pattern for values of type `t`.
+    pub fn from_tokens(
+        tokens: &mut Vec<Token>,
+        types: &TypeTable,
+        t: Type,
+    ) -> Result<Pattern, CompileError> {
+        match tokens.pop() {
+            Some(Token {
+                kind: TokenKind::Symb(name),
+                span,
+            }) if name.name() == "_" => Ok(Pattern::Wildcard(span)),
+            Some(Token {
+                kind: TokenKind::Symb(name),
+                span,
+            }) if constructor(types, t, &name).is_some() => {
+                let (data, variant) = constructor(types, t, &name).unwrap();
+                let mut args = Vec::new();
+                let mut span = span;
+                for field in &data.variants(types)[variant].fields {
+                    let arg = Pattern::from_argument(tokens, types, *field)?;
+                    span = span.to(arg.span());
+                    args.push(arg);
+                }
+                Ok(Pattern::Constructor {
+                    data,
+                    variant,
+                    args,
+                    span,
+                })
+            }
+            Some(Token {
+                kind: TokenKind::Lang(PreToken::DEL(Delimeter::LPar)),
+                span,
+            }) => {
+                let pattern = Pattern::from_tokens(tokens, types, t)?;
+                match tokens.pop() {
+                    Some(Token {
+                        kind: TokenKind::Lang(PreToken::DEL(Delimeter::RPar)),
+                        span: close,
+                    }) => Ok(pattern.with_span(span.to(close))),
+                    token => Err(unexpected(token, "`)`")),
+                }
+            }
+            Some(Token {
+                kind: TokenKind::Symb(name),
+                span,
+            }) => Ok(Pattern::Binding(name, span)),
+            Some(Token {
+                kind: TokenKind::Lit(literal),
+                span,
+            }) => {
+                if literal.get_type() != t {
+                    return Err(TypeError {
+                        code: ErrorCode::TypeMismatch,
+                        message: "the pattern can never match the value".to_string(),
+                        expected: t.display(types).to_string(),
+                        found: literal.get_type().display(types).to_string(),
+                        span,
+                        related: Vec::new(),
+                    }
+                    .into());
+                }
+                Ok(Pattern::Literal(literal, span))
+            }
+            token => Err(unexpected(token, "a pattern")),
+        }
+    }
+    /// Reads the pattern for a field of a constructor pattern, where a constructor with fields
+    /// of its own has to be in parentheses.
+    fn from_argument(
+        tokens: &mut Vec<Token>,
+        types: &TypeTable,
+        t: Type,
+    ) -> Result<Pattern, CompileError> {
+        match tokens.last() {
+            Some(Token {
+                kind: TokenKind::Symb(name),
+                span,
+            }) if constructor(types, t, name).is_some() => {
+                let (data, variant) = constructor(types, t, name).unwrap();
+                if !data.variants(types)[variant].fields.is_empty() {
+                    return Err(unexpected(
+                        tokens.pop(),
+                        "a pattern, with parentheses around a constructor that has fields",
+                    ));
+                }
+                let span = *span;
+                tokens.pop();
+                Ok(Pattern::Constructor {
+                    data,
+                    variant,
+                    args: Vec::new(),
+                    span,
+                })
+            }
+            _ => Pattern::from_tokens(tokens, types, t),
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            Pattern::Wildcard(span) => *span,
+            Pattern::Binding(_, span) => *span,
+            Pattern::Literal(_, span) => *span,
+            Pattern::Constructor { span, .. } => *span,
+        }
+    }
+    fn with_span(self, span: Span) -> Pattern {
+        match self {
+            Pattern::Wildcard(_) => Pattern::Wildcard(span),
+            Pattern::Binding(name, _) => Pattern::Binding(name, span),
+            Pattern::Literal(literal, _) => Pattern::Literal(literal, span),
+            Pattern::Constructor {
+                data,
+                variant,
+                args,
+                ..
+            } => Pattern::Constructor {
+                data,
+                variant,
+                args,
+                span,
+            },
+        }
+    }
+    /// The names the pattern binds when it matches a value of type `t`, with their types.
+    pub fn bindings(&self, types: &TypeTable, t: Type) -> Vec<(Symbol, Type)> {
+        match self {
+            Pattern::Binding(name, _) => vec![(name.clone(), t)],
+            Pattern::Constructor {
+                data,
+                variant,
+                args,
+                ..
+            } => args
+                .iter()
+                .zip(&data.variants(types)[*variant].fields)
+                .flat_map(|(arg, t)| arg.bindings(types, *t))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+    /// The names the pattern binds.
+    pub fn names(&self) -> Vec<&Symbol> {
+        match self {
+            Pattern::Binding(name, _) => vec![name],
+            Pattern::Constructor { args, .. } => args.iter().flat_map(|arg| arg.names()).collect(),
+            _ => Vec::new(),
+        }
+    }
+    /// Whether `value` matches the pattern, adding what it binds to `bound`.
+    pub fn matches(&self, value: &Literal, bound: &mut Vec<(Symbol, Literal)>) -> bool {
+        match self {
+            Pattern::Wildcard(_) => true,
+            Pattern::Binding(name, _) => {
+                bound.push((name.clone(), value.clone()));
+                true
+            }
+            Pattern::Literal(literal, _) => literal == value,
+            Pattern::Constructor { variant, args, .. } => match value {
+                Literal::Data(data) if data.variant() == *variant => args
+                    .iter()
+                    .zip(data.fields())
+                    .all(|(arg, field)| arg.matches(field, bound)),
+                _ => false,
+            },
+        }
+    }
+    fn matches_anything(&self) -> bool {
+        matches!(self, Pattern::Wildcard(_) | Pattern::Binding(..))
+    }
+}
+
+/// The variant of data type `t` that `name` refers to, with or without its module prefix.
+fn constructor(types: &TypeTable, t: Type, name: &Symbol) -> Option<(DataType, usize)> {
+    let Type::Data(data) = t else {
+        return None;
+    };
+    let name = name.name();
+    let name = name.rsplit('.').next().unwrap_or(&name);
+    data.variant(types, name).map(|(index, _)| (data, index))
+}
+
+/// Describes the values of type `t` that none of `patterns` match for an error message, or
+/// returns `None` if they cover everything.
+pub fn missing(patterns: &[&Pattern], types: &TypeTable, t: Type) -> Option<String> {
+    if patterns.iter().any(|p| p.matches_anything()) {
+        return None;
+    }
+    if let Type::Data(_) = t {
+        let rows: Vec<Vec<Option<&Pattern>>> = patterns.iter().map(|p| vec![Some(*p)]).collect();
+        return unmatched(&rows, types, &[t])
+            .map(|values| format!("`{}` is not matched", values[0]));
+    }
+    if t == Type::Bool {
+        let missing: Vec<String> = [true, false]
+            .into_iter()
+            .filter(|b| {
+                !patterns.iter().any(
+                    |p| matches!(p, Pattern::Literal(Literal::Bool(value), _) if value.get() == *b),
+                )
+            })
+            .map(|b| format!("`{}`", b))
+            .collect();
+        return match missing.len() {
+            0 => None,
+            1 => Some(format!("{} is not matched", missing[0])),
+            _ => Some(format!("{} are not matched", missing.join(" and "))),
+        };
+    }
+    Some(format!(
+        "{} values other than the ones listed are not matched, add a `_` arm",
+        t.display(types)
+    ))
+}
+
+/// Finds values of the types of `columns` that no row of patterns matches, with `None` standing
+/// for a `_`, and writes one of them out as patterns. A column is only split by constructor when
+/// its patterns name every constructor, which keeps recursive types from being unfolded forever.
+fn unmatched(
+    rows: &[Vec<Option<&Pattern>>],
+    types: &TypeTable,
+    columns: &[Type],
+) -> Option<Vec<String>> {
+    let Some((t, rest)) = columns.split_first() else {
+        return rows.is_empty().then(Vec::new);
+    };
+    let heads: Vec<&Pattern> = rows
+        .iter()
+        .filter_map(|row| row[0].filter(|p| !p.matches_anything()))
+        .collect();
+    let constructors: Vec<(String, Vec<Type>)> = match t {
+        Type::Data(data) => data
+            .variants(types)
+            .iter()
+            .map(|variant| (variant.name.clone(), variant.fields.clone()))
+            .collect(),
+        Type::Bool => vec![
+            ("true".to_string(), Vec::new()),
+            ("false".to_string(), Vec::new()),
+        ],
+        _ => Vec::new(),
+    };
+    let is_constructor = |p: &Pattern, index: usize| match p {
+        Pattern::Constructor { variant, .. } => *variant == index,
+        Pattern::Literal(Literal::Bool(value), _) => value.get() == (index == 0),
+        _ => false,
+    };
+    let absent = (0..constructors.len()).find(|i| !heads.iter().any(|p| is_constructor(p, *i)));
+    if constructors.is_empty() || absent.is_some() {
+        let rows: Vec<Vec<Option<&Pattern>>> = rows
+            .iter()
+            .filter(|row| row[0].is_none_or(|p| p.matches_anything()))
+            .map(|row| row[1..].to_vec())
+            .collect();
+        let value = match absent {
+            Some(index) => {
+                let (name, fields) = &constructors[index];
+                [name.clone()]
+                    .into_iter()
+                    .chain(fields.iter().map(|_| "_".to_string()))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            None => "_".to_string(),
+        };
+        return unmatched(&rows, types, rest)
+            .map(|values| [value].into_iter().chain(values).collect());
+    }
+    constructors
+        .iter()
+        .enumerate()
+        .find_map(|(index, (name, fields))| {
+            let arity = fields.len();
+            let rows: Vec<Vec<Option<&Pattern>>> = rows
+                .iter()
+                .filter(|row| {
+                    row[0].is_none_or(|p| p.matches_anything() || is_constructor(p, index))
+                })
+                .map(|row| {
+                    let args: Vec<Option<&Pattern>> = match row[0] {
+                        Some(Pattern::Constructor { args, .. }) => args.iter().map(Some).collect(),
+                        _ => vec![None; arity],
+                    };
+                    args.into_iter().chain(row[1..].iter().copied()).collect()
+                })
+                .collect();
+            let columns: Vec<Type> = fields.iter().chain(rest).copied().collect();
+            unmatched(&rows, types, &columns).map(|mut values| {
+                let rest = values.split_off(arity);
+                let mut value = name.clone();
+                for arg in values {
+                    if arg.contains(' ') {
+                        value += &format!(" ({})", arg);
+                    } else {
+                        value += &format!(" {}", arg);
+                    }
+                }
+                [value].into_iter().chain(rest).collect()
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{Bool, Int};
+    use crate::scanner::Variant;
+    use crate::testing::{compile, errors, run};
+
+    /// `type list := nil | cons int list`.
+    fn list(types: &mut TypeTable) -> DataType {
+        let data = types.data("list");
+        let variant = |name: &str, fields: Vec<Type>| Variant {
+            name: name.to_string(),
+            fields,
+            span: Span::default(),
+        };
+        data.define(
+            types,
+            vec![
+                variant("nil", Vec::new()),
+                variant("cons", vec![Type::Int, Type::Data(data)]),
+            ],
+        );
+        data
+    }
+
+    fn nil(data: DataType) -> Pattern {
+        constructor_of(data, 0, Vec::new())
+    }
+
+    fn cons(data: DataType, head: Pattern, tail: Pattern) -> Pattern {
+        constructor_of(data, 1, vec![head, tail])
+    }
+
+    fn constructor_of(data: DataType, variant: usize, args: Vec<Pattern>) -> Pattern {
+        Pattern::Constructor {
+            data,
+            variant,
+            args,
+            span: Span::default(),
+        }
+    }
+
+    fn any() -> Pattern {
+        Pattern::Wildcard(Span::default())
+    }
+
+    fn boolean(value: bool) -> Pattern {
+        Pattern::Literal(Literal::Bool(Bool::new(value)), Span::default())
+    }
+
+    fn rows<'a>(patterns: &[&'a Pattern]) -> Vec<Vec<Option<&'a Pattern>>> {
+        patterns.iter().map(|p| vec![Some(*p)]).collect()
+    }
+
+    #[test]
+    fn every_variant_covers_a_data_type() {
+        let mut types = TypeTable::new();
+        let data = list(&mut types);
+        let (empty, full) = (nil(data), cons(data, any(), any()));
+        assert_eq!(missing(&[&empty, &full], &types, Type::Data(data)), None);
+    }
+
+    #[test]
+    fn a_missing_variant_is_shown_with_wildcards() {
+        let mut types = TypeTable::new();
+        let data = list(&mut types);
+        let empty = nil(data);
+        assert_eq!(
+            unmatched(&rows(&[&empty]), &types, &[Type::Data(data)]),
+            Some(vec!["cons _ _".to_string()])
+        );
+    }
+
+    #[test]
+    fn nested_values_that_are_not_matched_are_found() {
+        let mut types = TypeTable::new();
+        let data = list(&mut types);
+        let (empty, one) = (nil(data), cons(data, any(), nil(data)));
+        assert_eq!(
+            missing(&[&empty, &one], &types, Type::Data(data)),
+            Some("`cons _ (cons _ _)` is not matched".to_string())
+        );
+        let longer = cons(data, any(), cons(data, any(), any()));
+        assert_eq!(
+            missing(&[&empty, &one, &longer], &types, Type::Data(data)),
+            None
+        );
+    }
+
+    #[test]
+    fn literals_do_not_cover_their_field() {
+        let mut types = TypeTable::new();
+        let data = list(&mut types);
+        let head = Pattern::Literal(Literal::Integer(Int::new(1)), Span::default());
+        let (empty, one) = (nil(data), cons(data, head, any()));
+        assert_eq!(
+            missing(&[&empty, &one], &types, Type::Data(data)),
+            Some("`cons _ nil` is not matched".to_string())
+        );
+    }
+
+    #[test]
+    fn bools_need_both_values() {
+        let types = TypeTable::new();
+        let (yes, no) = (boolean(true), boolean(false));
+        assert_eq!(unmatched(&rows(&[&yes, &no]), &types, &[Type::Bool]), None);
+        assert_eq!(
+            unmatched(&rows(&[&yes]), &types, &[Type::Bool]),
+            Some(vec!["false".to_string()])
+        );
+        assert_eq!(
+            missing(&[&no], &types, Type::Bool),
+            Some("`true` is not matched".to_string())
+        );
+    }
+
+    #[test]
+    fn other_types_need_a_catch_all() {
+        let types = TypeTable::new();
+        let zero = Pattern::Literal(Literal::Integer(Int::new(0)), Span::default());
+        assert_eq!(
+            unmatched(&rows(&[&zero]), &types, &[Type::Int]),
+            Some(vec!["_".to_string()])
+        );
+        assert_eq!(missing(&[&zero, &any()], &types, Type::Int), None);
+    }
+
+    const LIST: &str = "\
+type list := nil | cons int list
+type shape := circle int | rect int int
+int func sum := l : list => match l
+  [nil -> 0]
+  [cons x rest -> + x (sum rest)]
+";
+
+    #[test]
+    fn variants_are_built_and_taken_apart() {
+        let program = format!(
+            "{}int func area := s : shape => match s\n  [circle r -> * 3 (* r r)]\n  [rect w h -> * w h]\n\
+             int main := + (sum (cons 1 (cons 2 (cons 3 nil)))) (+ (area (circle 2)) (area (rect 2 5)))\n",
+            LIST
+        );
+        assert_eq!(run(&program).unwrap(), Literal::Integer(Int::new(28)));
+    }
+
+    #[test]
+    fn nested_patterns_look_inside_fields() {
+        let program = format!(
+            "{}int func second := l : list => match l\n  [cons _ (cons y _) -> y]\n  [_ -> 0]\n\
+             int main := + (second (cons 1 (cons 2 nil))) (second (cons 1 nil))\n",
+            LIST
+        );
+        assert_eq!(run(&program).unwrap(), Literal::Integer(Int::new(2)));
+    }
+
+    #[test]
+    fn variants_are_functions() {
+        let program = format!(
+            "{}list func twice := f : int -> list n : int => match f n\n  [cons x _ -> cons x (f x)]\n  [nil -> nil]\n\
+             int main := sum (twice (\\x : int => cons x nil) 4)\n",
+            LIST
+        );
+        assert_eq!(run(&program).unwrap(), Literal::Integer(Int::new(8)));
+        let program = format!("{}list main := cons 1 (cons 2 nil)\n", LIST);
+        let global_state = compile(&program).unwrap();
+        let types = global_state.types().clone();
+        let value = global_state.eval_main().unwrap();
+        assert_eq!(value.display(&types).to_string(), "cons 1 (cons 2 nil)");
+    }
+
+    #[test]
+    fn every_variant_has_to_be_matched() {
+        let found = errors(&format!(
+            "{}int func first := l : list => match l\n  [cons x _ -> x]\nint main := first nil\n",
+            LIST
+        ));
+        assert_eq!(found[0].code, ErrorCode::NonExhaustiveMatch);
+        let found = errors(&format!("{}list main := cons true nil\n", LIST));
+        assert_eq!(found[0].code, ErrorCode::ArgumentType);
+    }
+}
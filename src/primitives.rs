@@ -1,13 +1,16 @@
-use crate::functions::Function;
+use crate::globalstate::Globals;
 use crate::parser::Symbol;
+use crate::span::Span;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::{
+    errors::{CompileError, ErrorCode, ProgramError, TypeError},
     expressions::Evaluation,
     parser::Literal,
-    scanner::{Operator, Type},
+    scanner::{DataType, Operator, PreToken, RecordType, Type, TypeTable},
 };
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -100,30 +103,20 @@ impl Int {
             value: value as i64,
         }
     }
-    pub fn add(a: Int, b: Int) -> Int {
-        Int {
-            value: a.value + b.value,
-        }
+    pub fn add(a: Int, b: Int) -> Option<Int> {
+        a.value.checked_add(b.value).map(Int::new)
     }
-    pub fn sub(a: Int, b: Int) -> Int {
-        Int {
-            value: a.value - b.value,
-        }
+    pub fn sub(a: Int, b: Int) -> Option<Int> {
+        a.value.checked_sub(b.value).map(Int::new)
     }
-    pub fn mul(a: Int, b: Int) -> Int {
-        Int {
-            value: a.value * b.value,
-        }
+    pub fn mul(a: Int, b: Int) -> Option<Int> {
+        a.value.checked_mul(b.value).map(Int::new)
     }
-    pub fn div(a: Int, b: Int) -> Int {
-        Int {
-            value: a.value / b.value,
-        }
+    pub fn div(a: Int, b: Int) -> Option<Int> {
+        a.value.checked_div(b.value).map(Int::new)
     }
-    pub fn rem(a: Int, b: Int) -> Int {
-        Int {
-            value: a.value % b.value,
-        }
+    pub fn rem(a: Int, b: Int) -> Option<Int> {
+        a.value.checked_rem(b.value).map(Int::new)
     }
     pub fn gt(a: Int, b: Int) -> Bool {
         Bool {
@@ -248,6 +241,101 @@ impl std::fmt::Display for Str {
     }
 }
 
+/// A value of a record type, with its fields in the order they were declared.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Record {
+    t: RecordType,
+    fields: Vec<Literal>,
+}
+impl Record {
+    pub fn new(t: RecordType, fields: Vec<Literal>) -> Record {
+        Record { t, fields }
+    }
+    pub fn get_type(&self) -> RecordType {
+        self.t
+    }
+    pub fn get(&self, index: usize) -> Literal {
+        self.fields[index].clone()
+    }
+    /// A copy of the record with the field at `index` set to `value`.
+    pub fn with(&self, index: usize, value: Literal) -> Record {
+        let mut fields = self.fields.clone();
+        fields[index] = value;
+        Record { t: self.t, fields }
+    }
+}
+impl Clone for Record {
+    fn clone(&self) -> Self {
+        Record {
+            t: self.t,
+            fields: self.fields.clone(),
+        }
+    }
+}
+impl Record {
+    /// Writes the record as `point { x: 1, y: 2 }`.
+    pub fn write(&self, f: &mut std::fmt::Formatter<'_>, types: &TypeTable) -> std::fmt::Result {
+        write!(f, "{} {{", self.t.name(types))?;
+        for (i, (field, value)) in self.t.fields(types).iter().zip(&self.fields).enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            match value {
+                Literal::String(s) => write!(f, "{}{}: {:?}", separator, field.name, s.get())?,
+                _ => write!(f, "{}{}: {}", separator, field.name, value.display(types))?,
+            }
+        }
+        write!(f, " }}")
+    }
+}
+
+/// A value of a sum type: the variant it was built with and the values of its fields.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Data {
+    t: DataType,
+    variant: usize,
+    fields: Vec<Literal>,
+}
+impl Data {
+    pub fn new(t: DataType, variant: usize, fields: Vec<Literal>) -> Data {
+        Data { t, variant, fields }
+    }
+    pub fn get_type(&self) -> DataType {
+        self.t
+    }
+    pub fn variant(&self) -> usize {
+        self.variant
+    }
+    pub fn fields(&self) -> &[Literal] {
+        &self.fields
+    }
+}
+impl Clone for Data {
+    fn clone(&self) -> Self {
+        Data {
+            t: self.t,
+            variant: self.variant,
+            fields: self.fields.clone(),
+        }
+    }
+}
+impl Data {
+    /// Writes the value as its constructor applied to its fields, as in `cons 1 nil`.
+    pub fn write(&self, f: &mut std::fmt::Formatter<'_>, types: &TypeTable) -> std::fmt::Result {
+        write!(f, "{}", self.t.variants(types)[self.variant].name)?;
+        for value in &self.fields {
+            match value {
+                Literal::String(s) => write!(f, " {:?}", s.get())?,
+                Literal::Data(data) if !data.fields.is_empty() => {
+                    write!(f, " (")?;
+                    data.write(f, types)?;
+                    write!(f, ")")?;
+                }
+                _ => write!(f, " {}", value.display(types))?,
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn go_to_float(type1: Type, type2: Type) -> Type {
     if type1 == Type::Float || type2 == Type::Float {
         Type::Float
@@ -256,21 +344,39 @@ pub fn go_to_float(type1: Type, type2: Type) -> Type {
     }
 }
 
+fn checked(result: Option<Int>, code: ErrorCode, span: Span) -> Result<Literal, ProgramError> {
+    result.map(Literal::Integer).ok_or_else(|| ProgramError {
+        code,
+        message: code.title().to_string(),
+        span: Some(span),
+        related: Vec::new(),
+    })
+}
+
+fn divide_error(divisor: Int) -> ErrorCode {
+    if divisor.get() == 0 {
+        ErrorCode::DivisionByZero
+    } else {
+        ErrorCode::IntegerOverflow
+    }
+}
+
 pub fn exec_prim_op(
     op: Operator,
-    arg1: Box<Evaluation>,
+    arg1: &Evaluation,
     arg2: Box<Option<Evaluation>>,
+    span: Span,
     variables: Rc<RefCell<HashMap<Symbol, Evaluation>>>,
-    functions: Rc<RefCell<HashMap<Symbol, Function>>>,
-) -> Literal {
-    let mut eval1 = arg1.evaluate(&mut variables.clone(), &mut functions.clone());
+    globals: &Arc<Globals>,
+) -> Result<Literal, ProgramError> {
+    let mut eval1 = arg1.evaluate(&mut variables.clone(), globals)?;
     let mut eval2 = {
         if let Some(a) = arg2.as_ref() {
-            a.evaluate(&mut variables.clone(), &mut functions.clone())
+            a.evaluate(&mut variables.clone(), globals)?
         } else {
             Literal::Void
         }
-    }; //arg2.evaluate(&mut variables.clone(), &mut functions.clone());
+    };
     match (eval1.clone(), eval2.clone()) {
         (Literal::Integer(a), Literal::Float(_)) => {
             eval1 = Literal::Float(Float::from_int(a.get()));
@@ -280,112 +386,182 @@ pub fn exec_prim_op(
         }
         _ => {}
     }
-    match op {
+    Ok(match op {
         Operator::Eq => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::eq(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::eq(a, b)),
             (Literal::Bool(a), Literal::Bool(b)) => Literal::Bool(Bool::eq(a, b)),
             (Literal::String(a), Literal::String(b)) => Literal::Bool(Str::eq(a, b)),
-            _ => panic!(),
+            (Literal::Record(a), Literal::Record(b)) => Literal::Bool(Bool::new(a == b)),
+            (Literal::Data(a), Literal::Data(b)) => Literal::Bool(Bool::new(a == b)),
+            (Literal::Function(a), Literal::Function(b)) => Literal::Bool(Bool::new(a == b)),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Gt => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::gt(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::gt(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Lt => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::lt(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::lt(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Geq => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::geq(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::geq(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Leq => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::leq(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::leq(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Neq => match (eval1, eval2) {
             (Literal::Integer(a), Literal::Integer(b)) => Literal::Bool(Int::neq(a, b)),
             (Literal::Float(a), Literal::Float(b)) => Literal::Bool(Float::neq(a, b)),
             (Literal::Bool(a), Literal::Bool(b)) => Literal::Bool(Bool::neq(a, b)),
             (Literal::String(a), Literal::String(b)) => Literal::Bool(Str::neq(a, b)),
-            _ => panic!(),
+            (Literal::Record(a), Literal::Record(b)) => Literal::Bool(Bool::new(a != b)),
+            (Literal::Data(a), Literal::Data(b)) => Literal::Bool(Bool::new(a != b)),
+            (Literal::Function(a), Literal::Function(b)) => Literal::Bool(Bool::new(a != b)),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Or => match (eval1, eval2) {
             (Literal::Bool(a), Literal::Bool(b)) => Literal::Bool(Bool::or(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::And => match (eval1, eval2) {
             (Literal::Bool(a), Literal::Bool(b)) => Literal::Bool(Bool::and(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Not => match eval1 {
             Literal::Bool(a) => Literal::Bool(Bool::not(a)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Nand => match (eval1, eval2) {
             (Literal::Bool(a), Literal::Bool(b)) => Literal::Bool(Bool::nand(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Mod => match (eval1, eval2) {
-            (Literal::Integer(a), Literal::Integer(b)) => Literal::Integer(Int::rem(a, b)),
-            _ => panic!(),
+            (Literal::Integer(a), Literal::Integer(b)) => {
+                checked(Int::rem(a, b), divide_error(b), span)?
+            }
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Plus => match (eval1, eval2) {
-            (Literal::Integer(a), Literal::Integer(b)) => Literal::Integer(Int::add(a, b)),
+            (Literal::Integer(a), Literal::Integer(b)) => {
+                checked(Int::add(a, b), ErrorCode::IntegerOverflow, span)?
+            }
             (Literal::Float(a), Literal::Float(b)) => Literal::Float(Float::add(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Minus => match (eval1, eval2) {
-            (Literal::Integer(a), Literal::Integer(b)) => Literal::Integer(Int::sub(a, b)),
+            (Literal::Integer(a), Literal::Integer(b)) => {
+                checked(Int::sub(a, b), ErrorCode::IntegerOverflow, span)?
+            }
             (Literal::Float(a), Literal::Float(b)) => Literal::Float(Float::sub(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Mult => match (eval1, eval2) {
-            (Literal::Integer(a), Literal::Integer(b)) => Literal::Integer(Int::mul(a, b)),
+            (Literal::Integer(a), Literal::Integer(b)) => {
+                checked(Int::mul(a, b), ErrorCode::IntegerOverflow, span)?
+            }
             (Literal::Float(a), Literal::Float(b)) => Literal::Float(Float::mul(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Div => match (eval1, eval2) {
-            (Literal::Integer(a), Literal::Integer(b)) => Literal::Integer(Int::div(a, b)),
+            (Literal::Integer(a), Literal::Integer(b)) => {
+                checked(Int::div(a, b), divide_error(b), span)?
+            }
             (Literal::Float(a), Literal::Float(b)) => Literal::Float(Float::div(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Concat => match (eval1, eval2) {
             (Literal::String(a), Literal::String(b)) => Literal::String(Str::concat(a, b)),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
         Operator::Floor => match eval1 {
             Literal::Float(a) => Literal::Integer(Int::from_float(a.get())),
-            _ => panic!(),
+            _ => unreachable!("operand types are checked when the operation is parsed"),
         },
-        Operator::Cond => panic!("uhhhhh"),
-    }
+        Operator::Cond => unreachable!("`cond` is parsed on its own and never becomes a PrimOp"),
+    })
 }
 
-pub fn get_prim_op_type(op: Operator, type1: Type, type2: Type) -> Type {
-    match op {
-        Operator::Eq => Type::Bool,
-        Operator::Gt => Type::Bool,
-        Operator::Lt => Type::Bool,
-        Operator::Geq => Type::Bool,
-        Operator::Leq => Type::Bool,
-        Operator::Neq => Type::Bool,
-        Operator::Or => Type::Bool,
-        Operator::And => Type::Bool,
-        Operator::Nand => Type::Bool,
-        Operator::Not => Type::Bool,
-        Operator::Mod => Type::Int,
-        Operator::Plus => go_to_float(type1, type2),
-        Operator::Minus => go_to_float(type1, type2),
-        Operator::Mult => go_to_float(type1, type2),
-        Operator::Div => go_to_float(type1, type2),
-        Operator::Concat => Type::Str,
-        Operator::Cond => panic!("ermmmmm how did we get here?"),
-        Operator::Floor => Type::Int,
-    }
+/// The type of applying `op` to its operands, or an error if it cannot take them. `arg2` is
+/// `None` for the operators that take one operand.
+pub fn get_prim_op_type(
+    types: &TypeTable,
+    op: Operator,
+    arg1: &Evaluation,
+    arg2: Option<&Evaluation>,
+) -> Result<Type, CompileError> {
+    let is_number = |t: Type| t == Type::Int || t == Type::Float;
+    let type1 = arg1.get_type();
+    let type2 = arg2.map_or(Type::NoType, Evaluation::get_type);
+    let operands = [Some((arg1, type1)), arg2.map(|arg| (arg, type2))];
+    let check = |allowed: &dyn Fn(Type) -> bool, expected: Type| match operands
+        .into_iter()
+        .flatten()
+        .find(|(_, t)| !allowed(*t))
+    {
+        Some((arg, found)) => Err(CompileError::from(TypeError {
+            code: ErrorCode::ArgumentType,
+            message: format!("wrong operand type for {}", PreToken::OP(op)),
+            expected: expected.display(types).to_string(),
+            found: found.display(types).to_string(),
+            span: arg.span(),
+            related: Vec::new(),
+        })),
+        None => Ok(()),
+    };
+    // the type a number operand was expected to have, going by the other one
+    let number = if type1 == Type::Float || type2 == Type::Float {
+        Type::Float
+    } else {
+        Type::Int
+    };
+    Ok(match op {
+        Operator::Eq | Operator::Neq => {
+            if type1 != type2 && !(is_number(type1) && is_number(type2)) {
+                let arg2 = arg2.expect("comparisons take two operands");
+                return Err(TypeError {
+                    code: ErrorCode::TypeMismatch,
+                    message: "only values of the same type can be compared".to_string(),
+                    expected: type1.display(types).to_string(),
+                    found: type2.display(types).to_string(),
+                    span: arg2.span(),
+                    related: Vec::new(),
+                }
+                .into());
+            }
+            Type::Bool
+        }
+        Operator::Gt | Operator::Lt | Operator::Geq | Operator::Leq => {
+            check(&is_number, number)?;
+            Type::Bool
+        }
+        Operator::Or | Operator::And | Operator::Nand | Operator::Not => {
+            check(&|t| t == Type::Bool, Type::Bool)?;
+            Type::Bool
+        }
+        Operator::Mod => {
+            check(&|t| t == Type::Int, Type::Int)?;
+            Type::Int
+        }
+        Operator::Plus | Operator::Minus | Operator::Mult | Operator::Div => {
+            check(&is_number, number)?;
+            go_to_float(type1, type2)
+        }
+        Operator::Concat => {
+            check(&|t| t == Type::Str, Type::Str)?;
+            Type::Str
+        }
+        Operator::Floor => {
+            check(&|t| t == Type::Float, Type::Float)?;
+            Type::Int
+        }
+        Operator::Cond => unreachable!("`cond` is parsed on its own and never becomes a PrimOp"),
+    })
 }
@@ -1,13 +1,21 @@
 use phf::{phf_map, Map};
 use regex::Regex;
-use regex_split::RegexSplit;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use crate::errors::{ErrorCode, ProgramError};
+use crate::lexer::hidden_lines;
+use crate::modules::ModuleTable;
+use crate::span::{SourceMap, Span};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Delimeter {
     Comma,
     LPar,
     RPar,
+    LBracket,
+    RBracket,
     Dot,
     Colon,
     Semicolon,
@@ -21,28 +29,221 @@ pub enum Keyword {
     Define,
     Punch,
     Kick,
+    Pub,
+    Lambda,
+    Arrow,
+    Let,
+    In,
+    Where,
+    Match,
+    If,
+    Else,
+    Type,
+    With,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Type {
     Int,
     Float,
     Str,
     Bool,
     NoType,
+    Func(FuncType),
+    Record(RecordType),
+    Data(DataType),
+}
+impl Type {
+    /// The type as it is written in programs.
+    pub fn display(self, types: &TypeTable) -> TypeDisplay<'_> {
+        TypeDisplay { t: self, types }
+    }
 }
-impl std::fmt::Display for Type {
+
+/// Writes a type as it is written in programs, looking up the parts of function, record and
+/// sum types in the table they come from.
+pub struct TypeDisplay<'a> {
+    t: Type,
+    types: &'a TypeTable,
+}
+impl std::fmt::Display for TypeDisplay<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
+        match self.t {
             Type::Int => write!(f, "int"),
             Type::Float => write!(f, "float"),
             Type::Str => write!(f, "str"),
             Type::Bool => write!(f, "bool"),
             Type::NoType => write!(f, "no_type"),
+            Type::Func(func) => {
+                self.types
+                    .write_signature(f, func.args(self.types), func.ret(self.types))
+            }
+            Type::Record(record) => write!(f, "{}", record.name(self.types)),
+            Type::Data(data) => write!(f, "{}", data.name(self.types)),
         }
     }
 }
 
+/// The function, record and sum types of one program. A `Type` is an index into the table of
+/// the compilation that made it, so it stays `Copy` and reading it needs no lock.
+#[derive(Debug, Clone, Default)]
+pub struct TypeTable {
+    /// Function signatures; equal signatures share an id.
+    funcs: Vec<Signature>,
+    /// Record types by name, filled in once their declarations are read.
+    records: Vec<RecordDef>,
+    /// Sum types, like record types.
+    datas: Vec<DataDef>,
+}
+impl TypeTable {
+    pub fn new() -> TypeTable {
+        TypeTable::default()
+    }
+    /// The type of functions taking `args` and returning `ret`.
+    pub fn function(&mut self, args: Vec<Type>, ret: Type) -> Type {
+        let signature = (args, ret);
+        let id = match self.funcs.iter().position(|t| *t == signature) {
+            Some(id) => id,
+            None => {
+                self.funcs.push(signature);
+                self.funcs.len() - 1
+            }
+        };
+        Type::Func(FuncType(id as u32))
+    }
+    /// The record type called `name`, which has no fields until it is defined.
+    pub fn record(&mut self, name: &str) -> RecordType {
+        let id = match self.records.iter().position(|r| r.name == name) {
+            Some(id) => id,
+            None => {
+                self.records.push(RecordDef {
+                    name: name.to_string(),
+                    fields: Vec::new(),
+                });
+                self.records.len() - 1
+            }
+        };
+        RecordType(id as u32)
+    }
+    /// The sum type called `name`, which has no variants until it is defined.
+    pub fn data(&mut self, name: &str) -> DataType {
+        let id = match self.datas.iter().position(|d| d.name == name) {
+            Some(id) => id,
+            None => {
+                self.datas.push(DataDef {
+                    name: name.to_string(),
+                    variants: Vec::new(),
+                });
+                self.datas.len() - 1
+            }
+        };
+        DataType(id as u32)
+    }
+    /// Writes the type of functions taking `args` and returning `ret`, as in `int str -> bool`.
+    pub fn write_signature(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        args: &[Type],
+        ret: Type,
+    ) -> std::fmt::Result {
+        for arg in args {
+            match arg {
+                Type::Func(_) => write!(f, "({}) ", arg.display(self))?,
+                _ => write!(f, "{} ", arg.display(self))?,
+            }
+        }
+        write!(f, "-> {}", ret.display(self))
+    }
+}
+
+/// The argument types and return type of a function.
+type Signature = (Vec<Type>, Type);
+
+/// The type of a function value, written `int str -> bool`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FuncType(u32);
+impl FuncType {
+    pub fn args(self, types: &TypeTable) -> &[Type] {
+        &types.funcs[self.0 as usize].0
+    }
+    pub fn ret(self, types: &TypeTable) -> Type {
+        types.funcs[self.0 as usize].1
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RecordDef {
+    name: String,
+    fields: Vec<Field>,
+}
+
+/// A field of a record type, `name : type`.
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub t: Type,
+    pub span: Span,
+}
+
+/// A type declared with `type name := field : type ...`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RecordType(u32);
+impl RecordType {
+    pub fn define(self, types: &mut TypeTable, fields: Vec<Field>) {
+        types.records[self.0 as usize].fields = fields;
+    }
+    pub fn name(self, types: &TypeTable) -> &str {
+        &types.records[self.0 as usize].name
+    }
+    pub fn fields(self, types: &TypeTable) -> &[Field] {
+        &types.records[self.0 as usize].fields
+    }
+    /// The position and declaration of the field called `name`.
+    pub fn field<'a>(self, types: &'a TypeTable, name: &str) -> Option<(usize, &'a Field)> {
+        self.fields(types)
+            .iter()
+            .enumerate()
+            .find(|(_, field)| field.name == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DataDef {
+    name: String,
+    variants: Vec<Variant>,
+}
+
+/// A variant of a sum type: the name of its constructor and the types of its fields.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub fields: Vec<Type>,
+    pub span: Span,
+}
+
+/// A type declared with `type name := variant type ... | variant type ...`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DataType(u32);
+impl DataType {
+    pub fn define(self, types: &mut TypeTable, variants: Vec<Variant>) {
+        types.datas[self.0 as usize].variants = variants;
+    }
+    pub fn name(self, types: &TypeTable) -> &str {
+        &types.datas[self.0 as usize].name
+    }
+    pub fn variants(self, types: &TypeTable) -> &[Variant] {
+        &types.datas[self.0 as usize].variants
+    }
+    /// The position and declaration of the variant called `name`.
+    pub fn variant<'a>(self, types: &'a TypeTable, name: &str) -> Option<(usize, &'a Variant)> {
+        self.variants(types)
+            .iter()
+            .enumerate()
+            .find(|(_, variant)| variant.name == name)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Operator {
     Plus,
@@ -66,19 +267,21 @@ pub enum Operator {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum PreToken {
     DEL(Delimeter),
     KW(Keyword),
     OP(Operator),
     TYPE(Type),
     EOL,
-    COMMENT,
 }
 
 const TOKEN_MAP: Map<&str, PreToken> = phf_map! {
 "," => PreToken::DEL(Delimeter::Comma),
 "(" => PreToken::DEL(Delimeter::LPar),
 ")" => PreToken::DEL(Delimeter::RPar),
+"[" => PreToken::DEL(Delimeter::LBracket),
+"]" => PreToken::DEL(Delimeter::RBracket),
 "." => PreToken::DEL(Delimeter::Dot),
 ":" => PreToken::DEL(Delimeter::Colon),
 ";" => PreToken::DEL(Delimeter::Semicolon),
@@ -106,76 +309,362 @@ const TOKEN_MAP: Map<&str, PreToken> = phf_map! {
 "=>" => PreToken::KW(Keyword::Kerchow),
 ":=" => PreToken::KW(Keyword::Define),
 "func" => PreToken::KW(Keyword::Func),
+"pub" => PreToken::KW(Keyword::Pub),
+"\\" => PreToken::KW(Keyword::Lambda),
+"->" => PreToken::KW(Keyword::Arrow),
+"let" => PreToken::KW(Keyword::Let),
+"in" => PreToken::KW(Keyword::In),
+"where" => PreToken::KW(Keyword::Where),
+"match" => PreToken::KW(Keyword::Match),
+"if" => PreToken::KW(Keyword::If),
+"else" => PreToken::KW(Keyword::Else),
+"type" => PreToken::KW(Keyword::Type),
+"with" => PreToken::KW(Keyword::With),
 "int" => PreToken::TYPE(Type::Int),
 "float" => PreToken::TYPE(Type::Float),
 "str" => PreToken::TYPE(Type::Str),
 "bool" => PreToken::TYPE(Type::Bool),
-"#" => PreToken::COMMENT,
 };
 
+impl std::fmt::Display for PreToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreToken::EOL => write!(f, "end of line"),
+            _ => match TOKEN_MAP.entries().find(|(_, t)| *t == self) {
+                Some((s, _)) => write!(f, "`{}`", s),
+                None => write!(f, "{:?}", self),
+            },
+        }
+    }
+}
+
+/// Every keyword and operator spelling the scanner recognises.
+pub fn keyword_names() -> impl Iterator<Item = &'static str> {
+    TOKEN_MAP.keys().copied()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PreTokenized {
     T(PreToken),
     S(String),
+    /// A number literal, as written.
+    Num(String),
+    /// A string literal, with its escapes already replaced.
+    Str(String),
+    /// The text of a `##` doc comment.
+    Doc(String),
+    /// A `#` or `#[ ... ]#` comment as written.
+    Comment(String),
 }
 
-fn string_to_tokenize(s: &str) -> PreTokenized {
-    let res = TOKEN_MAP.get(s);
-    match res {
-        Some(t) => PreTokenized::T(*t),
-        None => PreTokenized::S(s.to_owned()),
-    }
+/// The keyword, operator or delimiter spelled `s`, if any.
+pub fn token_for(s: &str) -> Option<PreToken> {
+    TOKEN_MAP.get(s).copied()
 }
 
-pub fn tokenize_line(line: String) -> Vec<PreTokenized> {
-    let re = Regex::new("(\".*\"|\\(|\\)|\\|\\+|\\-|\\*|/|,|:=|=>|;)").unwrap();
-    let mut split: Vec<PreTokenized> = re
-        .split_inclusive(line.as_str())
-        .flat_map(|s| re.split_inclusive_left(s))
-        .flat_map(|s| {
-            if re.is_match(s) {
-                vec![s]
-            } else {
-                s.split_whitespace().collect()
-            }
-        })
-        .map(string_to_tokenize)
-        .filter(|t| t != &PreTokenized::T(PreToken::COMMENT))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::Comma)))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::Semicolon)))
-        .collect();
-    split.push(PreTokenized::T(PreToken::EOL));
-    split
+/// Whether `line` is an `include` or `import` directive.
+pub fn is_directive(line: &str) -> bool {
+    INCLUDE.is_match(line) || IMPORT.is_match(line)
+}
+
+static INCLUDE: LazyLock<Regex> = LazyLock::new(|| Regex::new("^include +(.+?) *$").unwrap());
+static IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"^import +(?:"([^"]+)"|([A-Za-z_][\w/]*))(?: +as +([A-Za-z_]\w*))? *(?:\(([^)]*)\))? *$"#,
+    )
+    .unwrap()
+});
+
+/// A file currently being read, along with where it was included from.
+struct Inclusion {
+    canonical: PathBuf,
+    path: String,
+    included_at: Option<Span>,
 }
 
-pub struct Scanner {
-    lines_stack: Vec<String>,
+pub struct Scanner<'a> {
+    lines_stack: Vec<(String, usize, usize)>,
+    sources: &'a mut SourceMap,
+    modules: &'a mut ModuleTable,
+    search_paths: Vec<PathBuf>,
+    /// Files read so far, along with the module they were read into.
+    loaded: HashSet<(usize, PathBuf)>,
+    module_paths: HashMap<PathBuf, usize>,
+    chain: Vec<Inclusion>,
 }
 
-impl Scanner {
-    pub fn new() -> Scanner {
+impl<'a> Scanner<'a> {
+    /// `search_paths` are tried in order when an included file is not found next to the file
+    /// including it.
+    pub fn new(
+        sources: &'a mut SourceMap,
+        modules: &'a mut ModuleTable,
+        search_paths: Vec<PathBuf>,
+    ) -> Scanner<'a> {
         Scanner {
             lines_stack: Vec::new(),
+            sources,
+            modules,
+            search_paths,
+            loaded: HashSet::new(),
+            module_paths: HashMap::new(),
+            chain: Vec::new(),
         }
     }
 
-    pub fn load_file(&mut self, path: &str) -> std::io::Result<()> {
-        let mut file = std::fs::File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        for line in contents.lines().rev() {
-            if line.starts_with("include") {
-                let re = Regex::new("(include )(.+)").unwrap();
-                let include_path = re.captures(line).unwrap().get(2).unwrap().as_str();
-                self.load_file(include_path)?;
+    pub fn load_file(&mut self, path: &str) -> Result<(), ProgramError> {
+        let mut lines = Vec::new();
+        let main = self.modules.add("");
+        if let Ok(canonical) = Path::new(path).canonicalize() {
+            self.module_paths.insert(canonical, main);
+        }
+        self.read_file(Path::new(path), None, main, &mut lines)?;
+        lines.reverse();
+        self.lines_stack = lines;
+        Ok(())
+    }
+
+    /// Appends the lines of `path` to `out`, expanding its includes in place and reading its
+    /// imports into modules of their own. Each file is only read once into a module; including
+    /// it again does nothing.
+    fn read_file(
+        &mut self,
+        path: &Path,
+        included_at: Option<Span>,
+        module: usize,
+        out: &mut Vec<(String, usize, usize)>,
+    ) -> Result<(), ProgramError> {
+        let unreadable = |e: std::io::Error| ProgramError {
+            code: ErrorCode::UnreadableFile,
+            message: format!("could not read `{}`: {}", path.display(), e),
+            span: included_at,
+            related: Vec::new(),
+        };
+        let canonical = path.canonicalize().map_err(unreadable)?;
+        if let Some(start) = self.chain.iter().position(|f| f.canonical == canonical) {
+            return Err(self.include_cycle(start, included_at));
+        }
+        if !self.loaded.insert((module, canonical.clone())) {
+            return Ok(());
+        }
+        let contents = std::fs::read_to_string(path).map_err(unreadable)?;
+        let display = path.display().to_string();
+        let file_id = self.sources.add(display.clone(), contents.clone());
+        self.modules.set_file(file_id, module);
+        self.chain.push(Inclusion {
+            canonical,
+            path: display,
+            included_at,
+        });
+        let hidden = hidden_lines(&contents);
+        for (line_no, line) in contents.lines().enumerate() {
+            if hidden[line_no] {
+                out.push((line.to_owned(), file_id, line_no + 1));
+                continue;
+            }
+            let span_of = |m: regex::Match| {
+                let col = line[..m.start()].chars().count() + 1;
+                Span::new(file_id, line_no + 1, col, col + m.as_str().chars().count())
+            };
+            if let Some(path_match) = INCLUDE.captures(line).and_then(|c| c.get(1)) {
+                let span = span_of(path_match);
+                let include_path = self.resolve(path, path_match.as_str(), span)?;
+                self.read_file(&include_path, Some(span), module, out)?;
+            } else if let Some(captures) = IMPORT.captures(line) {
+                let (path_match, file_name) = match (captures.get(1), captures.get(2)) {
+                    (Some(quoted), _) => (quoted, quoted.as_str().to_string()),
+                    (_, Some(bare)) => (bare, format!("{}.bo", bare.as_str())),
+                    _ => unreachable!(),
+                };
+                let span = span_of(path_match);
+                let import_path = self.resolve(path, &file_name, span)?;
+                let imported = self.load_module(&import_path, span, out)?;
+                let (alias, alias_span) = match captures.get(3) {
+                    Some(alias) => (alias.as_str().to_string(), span_of(alias)),
+                    None => (self.modules.get(imported).name.clone(), span),
+                };
+                let imports = &mut self.modules.get_mut(module).imports;
+                match imports.get(&alias) {
+                    Some((other, first)) if *other != imported => {
+                        return Err(ProgramError {
+                            code: ErrorCode::DuplicateDefinition,
+                            message: format!("the module name `{}` is used twice", alias),
+                            span: Some(alias_span),
+                            related: vec![(*first, "first imported here".to_string())],
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        imports.insert(alias, (imported, alias_span));
+                    }
+                }
+                if let Some(list) = captures.get(4) {
+                    let selected = &mut self.modules.get_mut(module).selected;
+                    let mut offset = list.start();
+                    for name in list.as_str().split(',') {
+                        let trimmed = name.trim();
+                        let start = offset + name.find(trimmed).unwrap_or(0);
+                        offset += name.len() + 1;
+                        if trimmed.is_empty() {
+                            continue;
+                        }
+                        let col = line[..start].chars().count() + 1;
+                        let name_span =
+                            Span::new(file_id, line_no + 1, col, col + trimmed.chars().count());
+                        selected.insert(trimmed.to_string(), (imported, name_span));
+                    }
+                }
+            } else if line.starts_with("import") {
+                let end = line.chars().count() + 1;
+                return Err(ProgramError {
+                    code: ErrorCode::UnexpectedToken,
+                    message: "malformed import, expected `import name`, \
+                        `import \"path\" as name` or `import name (a, b)`"
+                        .to_string(),
+                    span: Some(Span::new(file_id, line_no + 1, 1, end)),
+                    related: Vec::new(),
+                });
             } else {
-                self.lines_stack.push(line.to_owned());
+                out.push((line.to_owned(), file_id, line_no + 1));
             }
         }
+        self.chain.pop();
         Ok(())
     }
 
-    pub fn get_next_line(&mut self) -> Option<String> {
+    /// Reads `path` into a module of its own, unless it was imported before, and returns it.
+    fn load_module(
+        &mut self,
+        path: &Path,
+        imported_at: Span,
+        out: &mut Vec<(String, usize, usize)>,
+    ) -> Result<usize, ProgramError> {
+        let canonical = path.canonicalize().map_err(|e| ProgramError {
+            code: ErrorCode::UnreadableFile,
+            message: format!("could not read `{}`: {}", path.display(), e),
+            span: Some(imported_at),
+            related: Vec::new(),
+        })?;
+        if let Some(module) = self.module_paths.get(&canonical) {
+            return Ok(*module);
+        }
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let module = self.modules.add(&name);
+        self.module_paths.insert(canonical, module);
+        self.read_file(path, Some(imported_at), module, out)?;
+        Ok(module)
+    }
+
+    /// Finds an included file, first next to the file including it and then in each of the
+    /// search paths.
+    fn resolve(&self, from: &Path, include: &str, span: Span) -> Result<PathBuf, ProgramError> {
+        let dir = from.parent().unwrap_or(Path::new(""));
+        let candidates: Vec<PathBuf> = std::iter::once(dir)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(include))
+            .collect();
+        match candidates.iter().find(|c| c.is_file()) {
+            Some(found) => Ok(found.clone()),
+            None => {
+                let searched: Vec<String> = candidates
+                    .iter()
+                    .map(|c| format!("`{}`", c.display()))
+                    .collect();
+                Err(ProgramError {
+                    code: ErrorCode::UnreadableFile,
+                    message: format!(
+                        "could not find `{}` (tried {})",
+                        include,
+                        searched.join(", ")
+                    ),
+                    span: Some(span),
+                    related: Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// The error for an include at `span` of a file that is already being read, starting at
+    /// `chain[start]`.
+    fn include_cycle(&self, start: usize, span: Option<Span>) -> ProgramError {
+        let cycle = &self.chain[start..];
+        let mut names: Vec<&str> = cycle.iter().map(|f| f.path.as_str()).collect();
+        names.push(&cycle[0].path);
+        ProgramError {
+            code: ErrorCode::IncludeCycle,
+            message: format!("include cycle: {}", names.join(" -> ")),
+            span,
+            related: cycle[1..]
+                .iter()
+                .filter_map(|f| {
+                    f.included_at
+                        .map(|s| (s, format!("`{}` is included here", f.path)))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the next line along with the file it came from and its 1-based line number.
+    pub fn get_next_line(&mut self) -> Option<(String, usize, usize)> {
         self.lines_stack.pop()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::Diagnostic;
+    use crate::errors::ErrorCode;
+    use crate::testing::compile_files;
+
+    #[test]
+    fn includes_are_found_next_to_the_including_file() {
+        let files = [
+            ("main.bo", "include lib/shapes.bo\nint main := + sides 1\n"),
+            ("lib/shapes.bo", "include sizes.bo\nint sides := size\n"),
+            ("lib/sizes.bo", "int size := 4\n"),
+        ];
+        assert!(compile_files(&files).0.is_ok());
+    }
+
+    #[test]
+    fn files_are_only_included_once() {
+        let files = [
+            ("main.bo", "include a.bo\ninclude b.bo\nint main := + a b\n"),
+            ("a.bo", "include common.bo\nint a := common\n"),
+            ("b.bo", "include common.bo\nint b := common\n"),
+            ("common.bo", "int common := 1\n"),
+        ];
+        assert!(compile_files(&files).0.is_ok());
+    }
+
+    #[test]
+    fn include_cycles_show_the_chain() {
+        let files = [
+            ("main.bo", "include a.bo\nint main := 1\n"),
+            ("a.bo", "include b.bo\n"),
+            ("b.bo", "include a.bo\n"),
+        ];
+        let errors = compile_files(&files).0.err().unwrap();
+        let diagnostic = Diagnostic::from(&errors[0]);
+        assert_eq!(diagnostic.code, ErrorCode::IncludeCycle);
+        let chain: Vec<&str> = diagnostic
+            .message
+            .trim_start_matches("include cycle: ")
+            .split(" -> ")
+            .map(|path| path.rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(chain, ["a.bo", "b.bo", "a.bo"]);
+    }
+
+    #[test]
+    fn missing_includes_are_reported() {
+        let errors = compile_files(&[("main.bo", "include gone.bo\nint main := 1\n")])
+            .0
+            .err()
+            .unwrap();
+        assert_eq!(Diagnostic::from(&errors[0]).code, ErrorCode::UnreadableFile);
+    }
+}
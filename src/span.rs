@@ -0,0 +1,82 @@
+use std::fmt;
+
+/// A region of source text. Lines and columns are 1-based, `end_col` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+impl Span {
+    pub fn new(file: usize, line: usize, col: usize, end_col: usize) -> Span {
+        Span {
+            file,
+            line,
+            col,
+            end_line: line,
+            end_col,
+        }
+    }
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        if self.file != other.file {
+            return self;
+        }
+        let (line, col) = (self.line, self.col).min((other.line, other.col));
+        let (end_line, end_col) =
+            (self.end_line, self.end_col).max((other.end_line, other.end_col));
+        Span {
+            file: self.file,
+            line,
+            col,
+            end_line,
+            end_col,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+pub struct SourceFile {
+    pub path: String,
+    pub contents: String,
+}
+
+/// Every file loaded while scanning, indexed by the `file` field of a `Span`.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: Vec::new() }
+    }
+    pub fn add(&mut self, path: String, contents: String) -> usize {
+        self.files.push(SourceFile { path, contents });
+        self.files.len() - 1
+    }
+    pub fn get(&self, file: usize) -> Option<&SourceFile> {
+        self.files.get(file)
+    }
+    pub fn path(&self, file: usize) -> &str {
+        self.files
+            .get(file)
+            .map(|f| f.path.as_str())
+            .unwrap_or("<unknown>")
+    }
+    pub fn line(&self, file: usize, line: usize) -> Option<&str> {
+        self.files
+            .get(file)
+            .and_then(|f| f.contents.lines().nth(line.checked_sub(1)?))
+    }
+    /// Renders a span as `path:line:col`.
+    pub fn location(&self, span: Span) -> String {
+        format!("{}:{}", self.path(span.file), span)
+    }
+}
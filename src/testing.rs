@@ -0,0 +1,85 @@
+//! Compiling and running whole programs from the tests.
+
+use crate::ast_generator::generate_ast;
+use crate::diagnostics::Diagnostic;
+use crate::errors::{CompileError, ProgramError};
+use crate::globalstate::GlobalState;
+use crate::modules::ModuleTable;
+use crate::parser::{parse, Literal};
+use crate::scanner::TypeTable;
+use crate::span::SourceMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Deeply nested programs need more than the default stack of a test thread, like they do
+/// in `main`.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+static PROGRAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes `files`, given as `(path, contents)`, into a directory of their own and compiles
+/// the first one, which may include or import the others.
+pub fn compile_files(
+    files: &[(&str, &str)],
+) -> (Result<GlobalState, Vec<CompileError>>, SourceMap) {
+    let dir = std::env::temp_dir().join(format!(
+        "breakout_test_{}_{}",
+        std::process::id(),
+        PROGRAMS.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, contents) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    let path: PathBuf = dir.join(files[0].0);
+    let mut sources = SourceMap::new();
+    let mut modules = ModuleTable::new();
+    let mut types = TypeTable::new();
+    let tokens = parse(
+        path.to_str().unwrap(),
+        Vec::new(),
+        &mut sources,
+        &mut modules,
+        &mut types,
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    let mut tokens = match tokens {
+        Ok(tokens) => tokens,
+        Err(e) => return (Err(vec![e]), sources),
+    };
+    let mut global_state = GlobalState::new(modules, types);
+    let result = match generate_ast(&mut tokens, &mut global_state, 0) {
+        Ok(()) => Ok(global_state),
+        Err((errors, _)) => Err(errors),
+    };
+    (result, sources)
+}
+
+/// Compiles `contents` as a program of its own.
+pub fn compile(contents: &str) -> Result<GlobalState, Vec<CompileError>> {
+    compile_files(&[("main.bo", contents)]).0
+}
+
+/// Compiles `contents`, which must not compile, and returns its diagnostics.
+pub fn errors(contents: &str) -> Vec<Diagnostic> {
+    match compile(contents) {
+        Ok(_) => panic!("compiles: {}", contents),
+        Err(errors) => errors.iter().map(Diagnostic::from).collect(),
+    }
+}
+
+/// Compiles `contents` and evaluates its `main` on a thread with a large stack.
+pub fn run(contents: &str) -> Result<Literal, ProgramError> {
+    let contents = contents.to_string();
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || match compile(&contents) {
+            Ok(global_state) => global_state.eval_main(),
+            Err(errors) => panic!("does not compile: {:?}\n{}", errors, contents),
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}